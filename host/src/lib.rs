@@ -0,0 +1,89 @@
+// Host side codec for the device's raw HID channel, src/usb/raw_hid.rs. The framing is
+// the firmware's own src/usb/framing.rs, built here for std.
+//
+// Reports go to and come from the device as they are, except that hidapi and the OS
+// HID APIs want the report id in front of an output report. The descriptor doesn't use
+// ids, so that is a 0 byte:
+//
+//   for report in encoder.encode(b"hello")? {
+//       let mut buf = [0u8; REPORT_SIZE + 1];
+//       buf[1..].copy_from_slice(&report);
+//       device.write(&buf)?;
+//   }
+
+#[path = "../../src/usb/framing.rs"]
+mod framing;
+
+pub use framing::{
+    Deframer, FrameError, Framer, END, HEADER_SIZE, MAX_MESSAGE, PAYLOAD_SIZE, REPORT_SIZE, START,
+};
+
+// Host to device.
+#[derive(Debug, Default)]
+pub struct Encoder {
+    framer: Framer,
+}
+
+impl Encoder {
+    pub fn new() -> Self {
+        Encoder {
+            framer: Framer::new(),
+        }
+    }
+
+    // The device starts over on a bus reset, or when it is plugged in again.
+    pub fn reset(&mut self) {
+        self.framer.reset();
+    }
+
+    // The reports that carry message, in order. Overflow if the device has no room for
+    // it.
+    pub fn encode(&mut self, message: &[u8]) -> Result<Vec<[u8; REPORT_SIZE]>, FrameError> {
+        if message.len() > MAX_MESSAGE {
+            return Err(FrameError::Overflow);
+        }
+
+        let mut reports = Vec::new();
+        let mut offset = 0;
+        loop {
+            let mut report = [0u8; REPORT_SIZE];
+            offset = self.framer.frame(message, offset, &mut report);
+            reports.push(report);
+            if offset == message.len() {
+                return Ok(reports);
+            }
+        }
+    }
+}
+
+// Device to host.
+pub struct Decoder {
+    deframer: Box<Deframer<MAX_MESSAGE>>,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Decoder {
+            deframer: Box::new(Deframer::new()),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.deframer.reset();
+    }
+
+    // Take one input report, with the report id already stripped. The message once its
+    // last report is in.
+    pub fn decode(&mut self, report: &[u8]) -> Result<Option<Vec<u8>>, FrameError> {
+        Ok(self
+            .deframer
+            .push(report)?
+            .map(|_| self.deframer.message().to_vec()))
+    }
+}
@@ -0,0 +1,100 @@
+use stm32f072_usb_host::{
+    Decoder, Encoder, FrameError, END, MAX_MESSAGE, PAYLOAD_SIZE, REPORT_SIZE, START,
+};
+
+fn message(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7) as u8).collect()
+}
+
+#[test]
+fn round_trip() {
+    let mut encoder = Encoder::new();
+    let mut decoder = Decoder::new();
+
+    for &len in &[
+        0,
+        1,
+        PAYLOAD_SIZE,
+        PAYLOAD_SIZE + 1,
+        3 * PAYLOAD_SIZE,
+        MAX_MESSAGE,
+    ] {
+        let msg = message(len);
+        let reports = encoder.encode(&msg).unwrap();
+        assert_eq!(reports.len(), std::cmp::max(1, len.div_ceil(PAYLOAD_SIZE)));
+
+        let (last, rest) = reports.split_last().unwrap();
+        for report in rest {
+            assert_eq!(decoder.decode(report), Ok(None));
+        }
+        assert_eq!(decoder.decode(last), Ok(Some(msg)));
+    }
+}
+
+#[test]
+fn flags_and_padding() {
+    let reports = Encoder::new().encode(&message(PAYLOAD_SIZE + 2)).unwrap();
+    assert_eq!(&reports[0][..3], &[0, START, PAYLOAD_SIZE as u8]);
+    assert_eq!(&reports[1][..3], &[1, END, 2]);
+    assert!(reports[1][5..].iter().all(|&b| b == 0));
+
+    let empty = Encoder::new().encode(&[]).unwrap();
+    assert_eq!(empty.len(), 1);
+    assert_eq!(&empty[0][..3], &[0, START | END, 0]);
+}
+
+#[test]
+fn sequence_wraps() {
+    let mut encoder = Encoder::new();
+    let mut decoder = Decoder::new();
+    for i in 0..300 {
+        let msg = vec![i as u8; 3];
+        let reports = encoder.encode(&msg).unwrap();
+        assert_eq!(reports[0][0], i as u8);
+        assert_eq!(decoder.decode(&reports[0]), Ok(Some(msg)));
+    }
+}
+
+#[test]
+fn lost_report_drops_message() {
+    let mut encoder = Encoder::new();
+    let mut decoder = Decoder::new();
+
+    let reports = encoder.encode(&message(3 * PAYLOAD_SIZE)).unwrap();
+    assert_eq!(decoder.decode(&reports[0]), Ok(None));
+    assert_eq!(
+        decoder.decode(&reports[2]),
+        Err(FrameError::Sequence {
+            expected: 1,
+            got: 2
+        })
+    );
+
+    // The next message gets through.
+    let msg = message(10);
+    let reports = encoder.encode(&msg).unwrap();
+    assert_eq!(decoder.decode(&reports[0]), Ok(Some(msg)));
+}
+
+#[test]
+fn malformed() {
+    let mut encoder = Encoder::new();
+    let mut decoder = Decoder::new();
+
+    assert_eq!(
+        encoder.encode(&message(MAX_MESSAGE + 1)),
+        Err(FrameError::Overflow)
+    );
+
+    let mut report = [0u8; REPORT_SIZE];
+    report[2] = PAYLOAD_SIZE as u8 + 1;
+    assert_eq!(
+        decoder.decode(&report),
+        Err(FrameError::Length(PAYLOAD_SIZE as u8 + 1))
+    );
+
+    // A continuation with nothing before it.
+    let reports = encoder.encode(&message(2 * PAYLOAD_SIZE)).unwrap();
+    decoder.reset();
+    assert_eq!(decoder.decode(&reports[1]), Err(FrameError::NoStart));
+}
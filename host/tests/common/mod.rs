@@ -0,0 +1,70 @@
+// A simulated flash for the update tests, expects the firmware's flash module as
+// crate::flash.
+
+use crate::flash::{Flash, FlashError, IMAGE_START, PAGES, PAGE_SIZE, STAGING_START};
+
+const FLASH_SIZE: usize = 128 * 1024;
+
+// NOR flash: erasing sets a page to 0xFF, programming only takes erased half words.
+pub struct SimFlash {
+    pub memory: Vec<u8>,
+    pub installed: Option<u32>,
+}
+
+impl SimFlash {
+    pub fn new() -> Self {
+        SimFlash {
+            memory: vec![0xA5; FLASH_SIZE],
+            installed: None,
+        }
+    }
+
+    pub fn image(&self, len: usize) -> &[u8] {
+        &self.memory[..len]
+    }
+
+    fn offset(address: u32) -> usize {
+        (address - IMAGE_START) as usize
+    }
+}
+
+impl Flash for SimFlash {
+    fn erase_page(&mut self, address: u32) -> Result<(), FlashError> {
+        assert_eq!(address % PAGE_SIZE, 0);
+        assert!(address >= STAGING_START, "erasing the running image");
+        let start = Self::offset(address);
+        self.memory[start..start + PAGE_SIZE as usize]
+            .iter_mut()
+            .for_each(|b| *b = 0xFF);
+        Ok(())
+    }
+
+    fn program(&mut self, address: u32, data: &[u8]) -> Result<(), FlashError> {
+        assert!(address >= STAGING_START, "programming the running image");
+        let start = Self::offset(address);
+        let target = &mut self.memory[start..start + data.len()];
+        if target.iter().any(|&b| b != 0xFF) {
+            return Err(FlashError::Program);
+        }
+        target.copy_from_slice(data);
+        Ok(())
+    }
+
+    fn read(&mut self, address: u32, data: &mut [u8]) -> Result<(), FlashError> {
+        let start = Self::offset(address);
+        data.copy_from_slice(&self.memory[start..start + data.len()]);
+        Ok(())
+    }
+
+    fn install(&mut self, pages: u32) {
+        for page in 0..PAGES {
+            if pages & (1 << page) != 0 {
+                let image = (page * PAGE_SIZE) as usize;
+                let staging = Self::offset(STAGING_START) + image;
+                self.memory
+                    .copy_within(staging..staging + PAGE_SIZE as usize, image);
+            }
+        }
+        self.installed = Some(pages);
+    }
+}
@@ -0,0 +1,168 @@
+// The firmware's EP0 state machine, the data stage as USB 2.0 5.5.3 and 8.5.3 want it.
+
+#[path = "../../src/usb"]
+#[allow(clippy::unusual_byte_groupings, clippy::upper_case_acronyms)]
+mod usb {
+    pub mod constants;
+    pub mod control;
+}
+
+use usb::constants::{Destination, Direction, Type, UsbRequest};
+use usb::control::{ControlPipe, ControlState, SetupPacket};
+
+// bMaxPacketSize0 as the firmware has it.
+const MAX_PACKET: usize = 64;
+
+fn pipe(size: usize) -> ControlPipe {
+    ControlPipe::new(Box::leak(vec![0u8; size].into_boxed_slice()), MAX_PACKET)
+}
+
+fn setup(request_type: u8, length: u16) -> SetupPacket {
+    SetupPacket {
+        bmRequestType: request_type,
+        bRequest: 0x06,
+        wValue: 0x0200,
+        wIndex: 0,
+        wLength: length,
+    }
+}
+
+// GET_DESCRIPTOR of len bytes with wLength length, the packets it goes out as.
+fn data_in(pipe: &mut ControlPipe, len: usize, length: u16) -> Vec<usize> {
+    pipe.setup(setup(0x80, length));
+    for (i, b) in pipe.buffer().iter_mut().enumerate() {
+        *b = i as u8;
+    }
+    pipe.data_in(len);
+    assert_eq!(pipe.state(), ControlState::DataIn);
+
+    let mut packets = Vec::new();
+    let mut offset = 0;
+    while let Some(packet) = pipe.next_in() {
+        assert!(packet
+            .iter()
+            .enumerate()
+            .all(|(i, &b)| b == (offset + i) as u8));
+        offset += packet.len();
+        packets.push(packet.len());
+        assert!(packets.len() < 100, "data stage never ends");
+    }
+    packets
+}
+
+#[test]
+fn setup_packet() {
+    // SET_ADDRESS 5 as it sits in the PMA.
+    let setup = SetupPacket::from([0x0500, 0x0005, 0x0000, 0x0000]);
+    assert_eq!((setup.bmRequestType, setup.bRequest), (0x00, 0x05));
+    assert_eq!((setup.wValue, setup.wIndex, setup.wLength), (5, 0, 0));
+    assert!(matches!(setup.request(), Some(UsbRequest::SetAddress)));
+    assert!(matches!(setup.direction(), Some(Direction::OUT)));
+    assert!(matches!(setup.request_type(), Some(Type::Standard)));
+    assert!(matches!(setup.destination(), Some(Destination::Device)));
+}
+
+#[test]
+fn clipped_to_wlength() {
+    // The first GET_DESCRIPTOR(DEVICE) of a host, wLength 64 for an 18 byte descriptor,
+    // and then one with wLength 8 for it.
+    let mut pipe = pipe(256);
+    assert_eq!(data_in(&mut pipe, 18, 64), [18]);
+    assert_eq!(data_in(&mut pipe, 18, 8), [8]);
+    assert_eq!(pipe.length(), 8);
+
+    // Configuration header only.
+    assert_eq!(data_in(&mut pipe, 100, 9), [9]);
+}
+
+#[test]
+fn short_packet_ends_the_stage() {
+    let mut pipe = pipe(256);
+    assert_eq!(data_in(&mut pipe, 100, 255), [64, 36]);
+    assert_eq!(data_in(&mut pipe, 63, 255), [63]);
+}
+
+#[test]
+fn zlp_after_a_multiple_shorter_than_wlength() {
+    let mut pipe = pipe(256);
+    assert_eq!(data_in(&mut pipe, 64, 255), [64, 0]);
+    assert_eq!(data_in(&mut pipe, 128, 255), [64, 64, 0]);
+    assert_eq!(data_in(&mut pipe, 64, 65), [64, 0]);
+}
+
+#[test]
+fn no_zlp_when_wlength_is_met() {
+    let mut pipe = pipe(256);
+    assert_eq!(data_in(&mut pipe, 64, 64), [64]);
+    assert_eq!(data_in(&mut pipe, 128, 128), [64, 64]);
+    assert_eq!(data_in(&mut pipe, 200, 128), [64, 64]);
+}
+
+#[test]
+fn nothing_to_send() {
+    let mut pipe = pipe(256);
+    assert_eq!(data_in(&mut pipe, 0, 0), []);
+    // Asked for some, has none, the ZLP says so.
+    assert_eq!(data_in(&mut pipe, 0, 18), [0]);
+}
+
+#[test]
+fn clipped_to_the_buffer() {
+    let mut pipe = pipe(64);
+    assert_eq!(data_in(&mut pipe, 100, 255), [64, 0]);
+    assert_eq!(pipe.length(), 64);
+}
+
+#[test]
+fn data_out_in_packets() {
+    let mut pipe = pipe(128);
+    pipe.setup(setup(0x21, 100));
+    assert!(pipe.data_out());
+    assert_eq!(pipe.state(), ControlState::DataOut);
+
+    let first = pipe.next_out(64);
+    assert_eq!(first.len(), 64);
+    first.copy_from_slice(&[0xA5; 64]);
+    assert!(!pipe.out_complete());
+    assert_eq!(pipe.remaining(), 36);
+
+    // The hardware said 64 but only 36 are wanted, the rest is dropped.
+    let second = pipe.next_out(64);
+    assert_eq!(second.len(), 36);
+    second.copy_from_slice(&[0x5A; 36]);
+    assert!(pipe.out_complete());
+
+    let data = pipe.data();
+    assert_eq!(data.len(), 100);
+    assert!(data[..64].iter().all(|&b| b == 0xA5));
+    assert!(data[64..].iter().all(|&b| b == 0x5A));
+}
+
+#[test]
+fn data_out_too_long() {
+    let mut pipe = pipe(128);
+    pipe.setup(setup(0x21, 128));
+    assert!(pipe.data_out());
+
+    pipe.setup(setup(0x21, 129));
+    assert!(!pipe.data_out());
+    assert_eq!(pipe.state(), ControlState::Setup);
+}
+
+#[test]
+fn setup_aborts_the_transfer() {
+    let mut pipe = pipe(256);
+    pipe.setup(setup(0x80, 255));
+    pipe.data_in(200);
+    assert_eq!(pipe.next_in().map(<[u8]>::len), Some(64));
+
+    pipe.setup(setup(0x00, 0));
+    assert_eq!(pipe.state(), ControlState::Setup);
+    assert_eq!((pipe.length(), pipe.offset()), (0, 0));
+    assert_eq!(pipe.request().wLength, 0);
+
+    pipe.stall();
+    assert_eq!(pipe.state(), ControlState::Stall);
+    pipe.reset();
+    assert_eq!(pipe.state(), ControlState::Idle);
+}
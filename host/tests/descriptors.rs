@@ -0,0 +1,200 @@
+// The firmware's const descriptor builders against descriptors written out by hand from
+// USB 2.0 9.6, little endian and all.
+
+#[path = "../../src/usb"]
+#[allow(
+    clippy::multiple_bound_locations,
+    clippy::unusual_byte_groupings,
+    clippy::upper_case_acronyms,
+    clippy::wrong_self_convention
+)]
+mod usb {
+    pub mod constants;
+    pub mod descriptors;
+    pub mod types;
+}
+
+use usb::descriptors::{
+    Configuration, DescriptorWriter, Device, DeviceQualifier, Endpoint, Interface,
+};
+use usb::types;
+
+#[test]
+fn device() {
+    const DEVICE: Device = Device::new()
+        .idVendor(0x0483)
+        .idProduct(0x5740)
+        .iManufacturer(1)
+        .iProduct(2)
+        .iSerialNumber(3);
+    const BYTES: [u8; 18] = DEVICE.to_bytes();
+
+    #[rustfmt::skip]
+    let expected = [
+        0x12, 0x01,       // bLength, DEVICE
+        0x00, 0x02,       // bcdUSB 2.00
+        0x00, 0x00, 0x00, // Class given by the interfaces
+        0x40,             // bMaxPacketSize0
+        0x83, 0x04,       // idVendor
+        0x40, 0x57,       // idProduct
+        0x00, 0x02,       // bcdDevice
+        0x01, 0x02, 0x03, // Strings
+        0x01,             // bNumConfigurations
+    ];
+    assert_eq!(BYTES, expected);
+}
+
+#[test]
+fn device_qualifier() {
+    const QUALIFIER: DeviceQualifier = DeviceQualifier::new().bcdUSB(0x0210);
+    assert_eq!(
+        QUALIFIER.to_bytes(),
+        [0x0A, 0x06, 0x10, 0x02, 0x00, 0x00, 0x00, 0x40, 0x01, 0x00]
+    );
+}
+
+#[test]
+fn configuration() {
+    const CONFIGURATION: Configuration = Configuration::new()
+        .wTotalLength(0x0123)
+        .bNumInterfaces(2)
+        .bConfigurationValue(1)
+        .iConfiguration(4)
+        .bmAttributes(0xC0) // Self powered.
+        .bMaxPower(0xFA);
+    assert_eq!(
+        CONFIGURATION.to_bytes(),
+        [0x09, 0x02, 0x23, 0x01, 0x02, 0x01, 0x04, 0xC0, 0xFA]
+    );
+
+    // Same layout, but OTHER_SPEED_CONFIGURATION.
+    assert_eq!(CONFIGURATION.other_speed().to_bytes()[1], 0x07);
+}
+
+#[test]
+fn interface() {
+    const INTERFACE: Interface = Interface::new()
+        .bInterfaceNumber(3)
+        .bAlternateSetting(1)
+        .bNumEndpoints(2)
+        .bInterfaceClass(0x03)
+        .bInterfaceSubClass(0x01)
+        .bInterfaceProtocol(0x02)
+        .iInterface(7);
+    assert_eq!(
+        INTERFACE.to_bytes(),
+        [0x09, 0x04, 0x03, 0x01, 0x02, 0x03, 0x01, 0x02, 0x07]
+    );
+}
+
+#[test]
+fn endpoint() {
+    // wMaxPacketSize has both bytes set, so a swap would show.
+    const ISOCHRONOUS: Endpoint = Endpoint::new()
+        .bEndpointAddress(0x81)
+        .bmAttributes(0b00_00_01)
+        .wMaxPacketSize(0x03FF)
+        .bInterval(1);
+    assert_eq!(
+        ISOCHRONOUS.to_bytes(),
+        [0x07, 0x05, 0x81, 0x01, 0xFF, 0x03, 0x01]
+    );
+
+    // Bulk OUT with the defaults.
+    assert_eq!(
+        Endpoint::new().to_bytes(),
+        [0x07, 0x05, 0x01, 0x02, 0x40, 0x00, 0x0A]
+    );
+}
+
+const HEADER: Configuration = Configuration::new()
+    .bConfigurationValue(1)
+    .bmAttributes(0x80)
+    .bMaxPower(0x32);
+
+// A vendor interface with two bulk endpoints, then a HID interface with a class
+// descriptor and an alternate setting without its endpoint.
+const VENDOR: Interface = Interface::new().bNumEndpoints(2);
+const EP01: Endpoint = Endpoint::new().bEndpointAddress(0x01).bInterval(0);
+const EP81: Endpoint = Endpoint::new().bEndpointAddress(0x81).bInterval(0);
+const HID: Interface = Interface::new()
+    .bInterfaceNumber(1)
+    .bInterfaceClass(0x03)
+    .bInterfaceSubClass(0x00)
+    .bInterfaceProtocol(0x00);
+const HID_IDLE: Interface = HID.bAlternateSetting(1).bNumEndpoints(0);
+const HID_DESCRIPTOR: [u8; 9] = [0x09, 0x21, 0x11, 0x01, 0x00, 0x01, 0x22, 0x3F, 0x00];
+const EP82: Endpoint = Endpoint::new()
+    .bEndpointAddress(0x82)
+    .bmAttributes(0b00_00_11)
+    .wMaxPacketSize(8);
+
+const VENDOR_EPS: [types::Endpoint; 2] = [types::Endpoint::new(&EP01), types::Endpoint::new(&EP81)];
+const HID_CLASS: [&[u8]; 1] = [&HID_DESCRIPTOR];
+const HID_EPS: [types::Endpoint; 1] = [types::Endpoint::new(&EP82)];
+const INTS: [types::Interface; 3] = [
+    types::Interface::new(&VENDOR, &[], &VENDOR_EPS),
+    types::Interface::new(&HID, &HID_CLASS, &HID_EPS),
+    types::Interface::new(&HID_IDLE, &[], &[]),
+];
+const CONF: types::Configuration = types::Configuration::new(&HEADER, &INTS);
+
+#[rustfmt::skip]
+const EXPECTED: [u8; 66] = [
+    0x09, 0x02, 0x42, 0x00, 0x02, 0x01, 0x00, 0x80, 0x32, // 2 interfaces, 66 bytes
+    0x09, 0x04, 0x00, 0x00, 0x02, 0xFF, 0xFF, 0xFF, 0x00,
+    0x07, 0x05, 0x01, 0x02, 0x40, 0x00, 0x00,
+    0x07, 0x05, 0x81, 0x02, 0x40, 0x00, 0x00,
+    0x09, 0x04, 0x01, 0x00, 0x01, 0x03, 0x00, 0x00, 0x00,
+    0x09, 0x21, 0x11, 0x01, 0x00, 0x01, 0x22, 0x3F, 0x00, // Before the endpoint
+    0x07, 0x05, 0x82, 0x03, 0x08, 0x00, 0x0A,
+    0x09, 0x04, 0x01, 0x01, 0x00, 0x03, 0x00, 0x00, 0x00,
+];
+
+// The blob that goes in flash.
+const CONF_BYTES: [u8; CONF.total_length() as usize] = CONF.to_bytes();
+
+#[test]
+fn configuration_blob() {
+    assert_eq!(CONF.total_length(), 66);
+    assert_eq!(CONF.num_interfaces(), 2);
+    assert_eq!(CONF_BYTES, EXPECTED);
+}
+
+// GET_DESCRIPTOR writes the same bytes, and cuts them short at wLength.
+#[test]
+fn configuration_written() {
+    let mut buf = [0u8; 256];
+    let mut writer = DescriptorWriter::new(&mut buf);
+    CONF.write(&mut writer);
+    let len = writer.len();
+    assert_eq!(&buf[..len], &EXPECTED[..]);
+
+    let mut short = [0u8; 9];
+    let mut writer = DescriptorWriter::new(&mut short);
+    CONF.write(&mut writer);
+    assert_eq!(writer.len(), 9);
+    assert_eq!(writer.total(), 66);
+    assert_eq!(short, EXPECTED[..9]);
+}
+
+#[test]
+#[should_panic(expected = "wTotalLength")]
+fn configuration_blob_wrong_size() {
+    let _: [u8; 64] = CONF.to_bytes();
+}
+
+#[test]
+fn device_tree() {
+    const DEVICE: Device = Device::new();
+    const CONFS: [types::Configuration; 1] = [CONF];
+    let device = types::Device::new(&DEVICE, &CONFS);
+
+    let mut buf = [0u8; 64];
+    let mut writer = DescriptorWriter::new(&mut buf);
+    device.write(&mut writer);
+    let len = writer.len();
+    assert_eq!(&buf[..len], &DEVICE.to_bytes()[..]);
+    assert!(device.configuration(1).is_some());
+    assert!(device.configuration(2).is_none());
+}
@@ -0,0 +1,302 @@
+// The firmware's DFU class on a simulated flash, driven by the control transfers
+// dfu-util makes.
+
+#[path = "../../src/usb/flash.rs"]
+mod flash;
+
+// The class and what it uses, where it expects them. The firmware's own style in those
+// isn't what clippy would have.
+#[path = "../../src/usb"]
+#[allow(
+    clippy::multiple_bound_locations,
+    clippy::unusual_byte_groupings,
+    clippy::upper_case_acronyms,
+    clippy::wrong_self_convention
+)]
+mod usb {
+    pub mod class;
+    pub mod constants;
+    pub mod control;
+    pub mod descriptors;
+    pub mod dfu;
+
+    use super::flash;
+}
+
+mod common;
+
+use common::SimFlash;
+use flash::IMAGE_SIZE;
+use usb::class::UsbClass;
+use usb::control::{ControlResponse, SetupPacket};
+use usb::dfu::{Dfu, DfuRuntime, State, Status, Target, TRANSFER_SIZE};
+
+const INTERFACE: u16 = 1;
+
+fn dfu() -> Dfu<SimFlash> {
+    Dfu::new(INTERFACE as u8, SimFlash::new())
+}
+
+fn firmware(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 13 + i / 253) as u8).collect()
+}
+
+// One control transfer per line, as usbmon shows them:
+//
+//   bmRequestType bRequest wValue wIndex wLength  [< IN data | > image | stall]  # note
+//
+// "> image" is the next wLength bytes of the image being downloaded, other OUT data
+// stages are zeros. An IN line without data expects none.
+fn replay(class: &mut dyn UsbClass, transcript: &str, image: &[u8]) {
+    let mut sent = 0;
+    for (n, line) in transcript.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let mut tokens = line.split_whitespace();
+        let mut field = || u16::from_str_radix(tokens.next().unwrap(), 16).unwrap();
+        let setup = SetupPacket {
+            bmRequestType: field() as u8,
+            bRequest: field() as u8,
+            wValue: field(),
+            wIndex: field(),
+            wLength: field(),
+        };
+        let rest: Vec<&str> = tokens.collect();
+        let length = setup.wLength as usize;
+        let stall = rest.first() == Some(&"stall");
+
+        let response = if setup.bmRequestType & 0x80 == 0 {
+            let mut data = vec![0u8; length];
+            if rest.first() == Some(&">") {
+                data.copy_from_slice(&image[sent..sent + length]);
+                sent += length;
+            }
+            class.control(&setup, &mut data)
+        } else {
+            let mut data = [0u8; 256];
+            let response = class.control(&setup, &mut data);
+            if let Some(ControlResponse::Data(len)) = response {
+                let expected: Vec<u8> = rest
+                    .iter()
+                    .skip(1)
+                    .flat_map(|hex| {
+                        (0..hex.len())
+                            .step_by(2)
+                            .map(move |i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+                    })
+                    .collect();
+                let len = len.min(length);
+                assert_eq!(&data[..len], &expected[..], "line {}: {}", n + 1, line);
+            }
+            response
+        };
+
+        let expected = match (stall, setup.bmRequestType & 0x80 != 0) {
+            (true, _) => ControlResponse::Stall,
+            (false, false) => ControlResponse::Accept,
+            (false, true) => match response {
+                Some(ControlResponse::Data(len)) => ControlResponse::Data(len),
+                _ => panic!("line {}: {} got {:?}", n + 1, line, response),
+            },
+        };
+        assert_eq!(response, Some(expected), "line {}: {}", n + 1, line);
+    }
+}
+
+// dfu-util -a 0 -D image.bin -R, for a 600 byte image.
+const DOWNLOAD: &str = "
+    a1 03 0000 0001 0006  < 00 000000 02 00  # GETSTATUS, dfuIDLE
+    21 01 0000 0001 0100  > image            # DNLOAD, block 0
+    a1 03 0000 0001 0006  < 00 000000 05 00  # dfuDNLOAD-IDLE
+    21 01 0001 0001 0100  > image
+    a1 03 0000 0001 0006  < 00 000000 05 00
+    21 01 0002 0001 0058  > image
+    a1 03 0000 0001 0006  < 00 000000 05 00
+    21 01 0003 0001 0000                     # The end
+    a1 03 0000 0001 0006  < 00 000000 07 00  # dfuMANIFEST
+    a1 03 0000 0001 0006  < 00 000000 08 00  # A second later, dfuMANIFEST-WAIT-RESET
+    21 00 03e8 0001 0000                     # DETACH from -R, then a bus reset
+";
+
+#[test]
+fn download() {
+    let mut dfu = dfu();
+    let image = firmware(600);
+    replay(&mut dfu, DOWNLOAD, &image);
+    dfu.reset();
+    assert_eq!(dfu.state(), State::ManifestWaitReset);
+
+    // The image is only touched by install, which takes the one page written.
+    assert!(dfu.update().flash().image(image.len()) != &image[..]);
+    dfu.update().install();
+    let flash = dfu.update().flash();
+    assert_eq!(flash.installed, Some(0b1));
+    assert_eq!(flash.image(image.len()), &image[..]);
+}
+
+// dfu-util -a 0 -U image.bin. Blocks of wTransferSize until a short one.
+#[test]
+fn upload() {
+    let mut dfu = dfu();
+    let expected = dfu.update().flash().image(IMAGE_SIZE as usize).to_vec();
+
+    let mut image = Vec::new();
+    for block in 0.. {
+        let setup = SetupPacket {
+            bmRequestType: 0xA1,
+            bRequest: 0x02,
+            wValue: block,
+            wIndex: INTERFACE,
+            wLength: TRANSFER_SIZE as u16,
+        };
+        let mut data = [0u8; 256];
+        let len = match dfu.control(&setup, &mut data) {
+            Some(ControlResponse::Data(len)) => len,
+            response => panic!("block {}: {:?}", block, response),
+        };
+        image.extend_from_slice(&data[..len]);
+        if len < TRANSFER_SIZE {
+            break;
+        }
+        assert_eq!(dfu.state(), State::UploadIdle);
+    }
+    assert_eq!(dfu.state(), State::Idle);
+    assert_eq!(image, expected);
+}
+
+// Out of place requests stall and leave dfuERROR, which only CLRSTATUS gets out of.
+const ERRORS: &str = "
+    21 00 03e8 0001 0000  stall              # DETACH is for the runtime interface
+    a1 03 0000 0001 0006  < 0f 000000 0a 00  # errSTALLEDPKT, dfuERROR
+    21 01 0000 0001 0100  stall              # No DNLOAD in dfuERROR
+    a1 05 0000 0001 0001  < 0a               # GETSTATE
+    21 04 0000 0001 0000                     # CLRSTATUS
+    a1 03 0000 0001 0006  < 00 000000 02 00
+    21 01 0000 0001 0000  stall              # The end of a download that didn't start
+    21 04 0000 0001 0000
+    21 04 0000 0001 0000  stall              # CLRSTATUS out of dfuERROR
+";
+
+#[test]
+fn errors() {
+    let mut dfu = dfu();
+    replay(&mut dfu, ERRORS, &[]);
+    assert_eq!(dfu.state(), State::Error);
+
+    // Requests for other interfaces aren't answered.
+    let setup = SetupPacket {
+        bmRequestType: 0xA1,
+        bRequest: 0x03,
+        wValue: 0,
+        wIndex: 0,
+        wLength: 6,
+    };
+    assert_eq!(dfu.control(&setup, &mut [0u8; 256]), None);
+}
+
+// Two blocks, then dfu-util is killed and run again. It aborts the transfer it finds
+// going and starts over.
+const ABORTED: &str = "
+    a1 03 0000 0001 0006  < 00 000000 02 00
+    21 01 0000 0001 0100  > image
+    a1 03 0000 0001 0006  < 00 000000 05 00
+    21 01 0001 0001 0100  > image
+    a1 03 0000 0001 0006  < 00 000000 05 00
+    a1 03 0000 0001 0006  < 00 000000 05 00  # The next dfu-util
+    21 06 0000 0001 0000                     # ABORT
+";
+
+#[test]
+fn abort() {
+    let mut dfu = dfu();
+    let first = firmware(3000);
+    replay(&mut dfu, ABORTED, &first);
+    assert_eq!(dfu.state(), State::Idle);
+
+    let image: Vec<u8> = firmware(600).iter().map(|b| !b).collect();
+    replay(&mut dfu, DOWNLOAD, &image);
+    dfu.update().install();
+    let flash = dfu.update().flash();
+    assert_eq!(flash.installed, Some(0b1));
+    assert_eq!(flash.image(image.len()), &image[..]);
+}
+
+// A file bigger than the image half fails on the first block past it, and never gets
+// as far as being installed.
+#[test]
+fn too_big() {
+    let mut dfu = dfu();
+    let get_status = SetupPacket {
+        bmRequestType: 0xA1,
+        bRequest: 0x03,
+        wValue: 0,
+        wIndex: INTERFACE,
+        wLength: 6,
+    };
+
+    let blocks = IMAGE_SIZE as usize / TRANSFER_SIZE;
+    for block in 0..=blocks {
+        let setup = SetupPacket {
+            bmRequestType: 0x21,
+            bRequest: 0x01,
+            wValue: block as u16,
+            wIndex: INTERFACE,
+            wLength: TRANSFER_SIZE as u16,
+        };
+        let mut data = [0x5A; TRANSFER_SIZE];
+        assert_eq!(
+            dfu.control(&setup, &mut data),
+            Some(ControlResponse::Accept)
+        );
+        let mut status = [0u8; 256];
+        dfu.control(&get_status, &mut status);
+        if block < blocks {
+            assert_eq!(dfu.state(), State::DnloadIdle);
+        } else {
+            assert_eq!(&status[..6], &[0x08, 0, 0, 0, 0x0A, 0]);
+        }
+    }
+    assert_eq!(dfu.state(), State::Error);
+    assert_eq!(dfu.status(), Status::ErrAddress);
+    assert_eq!(dfu.update().flash().installed, None);
+}
+
+// dfu-util -e against the application's runtime interface, number 5.
+const DETACH: &str = "
+    a1 03 0000 0005 0006  < 00 000000 00 00  # GETSTATUS, appIDLE
+    21 00 03e8 0005 0000                     # DETACH
+    a1 05 0000 0005 0001  < 01               # appDETACH
+";
+
+#[test]
+fn runtime_detach() {
+    let mut runtime = DfuRuntime::new(5);
+    assert_eq!(runtime.detach(), None);
+    replay(&mut runtime, DETACH, &[]);
+    assert_eq!(runtime.detach(), Some(Target::Update));
+}
+
+const VENDOR_REBOOT: &str = "
+    41 01 0002 0005 0000  stall              # No such target
+    41 01 0001 0005 0000                     # The ROM bootloader
+    a1 00 0000 0005 0000  stall              # DETACH is host to device
+";
+
+#[test]
+fn runtime_vendor_reboot() {
+    let mut runtime = DfuRuntime::new(5);
+    replay(&mut runtime, VENDOR_REBOOT, &[]);
+    assert_eq!(runtime.detach(), Some(Target::SystemMemory));
+
+    // Requests for other interfaces aren't its business.
+    let setup = SetupPacket {
+        bmRequestType: 0x41,
+        bRequest: 0x01,
+        wValue: 0,
+        wIndex: 1,
+        wLength: 0,
+    };
+    assert_eq!(runtime.control(&setup, &mut []), None);
+}
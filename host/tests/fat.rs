@@ -0,0 +1,192 @@
+// The firmware's virtual FAT12 volume, read back block by block the way the host would.
+
+#[path = "../../src/usb/block.rs"]
+mod block;
+#[path = "../../src/usb/fat.rs"]
+mod fat;
+
+use std::convert::TryInto;
+use std::fmt::Write;
+use std::process::Command;
+
+use block::{BlockDevice, BLOCK_SIZE};
+use fat::{File, VirtualFat, MAX_FILE_SIZE, SECTORS};
+
+struct Context {
+    version: &'static str,
+    lines: usize,
+}
+
+static FILES: [File<Context>; 4] = [
+    File {
+        name: "INFO.TXT",
+        generate: |c, w| writeln!(w, "Firmware {}\r\nUSB Configured(1)\r", c.version),
+    },
+    File {
+        name: "LOG.TXT",
+        generate: |c, w| {
+            for n in 0..c.lines {
+                writeln!(w, "{:5} event {}\r", n * 10, n)?;
+            }
+            Ok(())
+        },
+    },
+    File {
+        name: "EMPTY",
+        generate: |_, _| Ok(()),
+    },
+    File {
+        name: "BIG.BIN",
+        generate: |_, w| loop {
+            w.write_str("0123456789abcdef")?;
+        },
+    },
+];
+
+fn volume(lines: usize) -> VirtualFat<Context> {
+    let context = Context {
+        version: "0.1.0",
+        lines,
+    };
+    VirtualFat::new("STM32F072", 0x1234_5678, &FILES, context)
+}
+
+fn image(fat: &mut VirtualFat<Context>) -> Vec<u8> {
+    let mut image = vec![0u8; SECTORS as usize * BLOCK_SIZE];
+    for (lba, chunk) in image.chunks_mut(BLOCK_SIZE).enumerate() {
+        let block: &mut [u8; BLOCK_SIZE] = chunk.try_into().unwrap();
+        fat.read_block(lba as u32, block).unwrap();
+    }
+    image
+}
+
+fn u16_at(image: &[u8], offset: usize) -> usize {
+    u16::from_le_bytes([image[offset], image[offset + 1]]) as usize
+}
+
+// (name, content) of each file in the root directory, by following the FAT.
+fn files(image: &[u8]) -> Vec<(String, Vec<u8>)> {
+    assert_eq!(&image[510..512], &[0x55, 0xAA]);
+    let sector = u16_at(image, 11);
+    let reserved = u16_at(image, 14);
+    let fats = image[16] as usize;
+    let root_entries = u16_at(image, 17);
+    let fat_sectors = u16_at(image, 22);
+    assert_eq!(u16_at(image, 19), SECTORS as usize);
+
+    let fat = &image[reserved * sector..(reserved + fat_sectors) * sector];
+    for copy in 1..fats {
+        let start = (reserved + copy * fat_sectors) * sector;
+        assert_eq!(fat, &image[start..start + fat_sectors * sector]);
+    }
+    let entry = |cluster: usize| {
+        let pair = u16_at(fat, cluster * 3 / 2);
+        if cluster & 1 == 0 {
+            pair & 0xFFF
+        } else {
+            pair >> 4
+        }
+    };
+    assert_eq!(entry(0), 0xFF8);
+
+    let root = (reserved + fats * fat_sectors) * sector;
+    let data = root + root_entries * 32;
+    let mut files = Vec::new();
+    for dir in image[root..data].chunks(32) {
+        if dir[0] == 0 || dir[11] & 0x08 != 0 {
+            continue;
+        }
+        let name = String::from_utf8(dir[0..11].to_vec()).unwrap();
+        let size = u32::from_le_bytes(dir[28..32].try_into().unwrap()) as usize;
+        let mut content = Vec::new();
+        let mut cluster = u16_at(dir, 26);
+        while cluster != 0 && cluster < 0xFF8 {
+            let start = data + (cluster - 2) * sector;
+            content.extend_from_slice(&image[start..start + sector]);
+            cluster = entry(cluster);
+        }
+        assert_eq!(content.len(), size.div_ceil(sector) * sector, "{}", name);
+        content.truncate(size);
+        files.push((name, content));
+    }
+    files
+}
+
+#[test]
+fn directory_and_content() {
+    let mut fat = volume(200);
+    let files = files(&image(&mut fat));
+    let names: Vec<&str> = files.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(
+        names,
+        ["INFO    TXT", "LOG     TXT", "EMPTY      ", "BIG     BIN"]
+    );
+
+    assert_eq!(files[0].1, b"Firmware 0.1.0\r\nUSB Configured(1)\r\n");
+
+    // Several clusters, each generated on its own.
+    let mut log = String::new();
+    for n in 0..200 {
+        writeln!(log, "{:5} event {}\r", n * 10, n).unwrap();
+    }
+    assert!(log.len() > 4 * BLOCK_SIZE);
+    assert_eq!(files[1].1, log.as_bytes());
+
+    assert!(files[2].1.is_empty());
+
+    // Cut at the room a file has.
+    assert_eq!(files[3].1.len(), MAX_FILE_SIZE);
+    assert!(files[3].1.chunks(16).all(|c| c == b"0123456789abcdef"));
+}
+
+#[test]
+fn content_follows_the_context() {
+    let mut fat = volume(1);
+    assert_eq!(files(&image(&mut fat))[1].1, b"    0 event 0\r\n");
+    fat.context_mut().lines = 2;
+    assert_eq!(
+        files(&image(&mut fat))[1].1,
+        b"    0 event 0\r\n   10 event 1\r\n"
+    );
+}
+
+#[test]
+fn read_only() {
+    let mut fat = volume(1);
+    assert!(fat.is_read_only());
+    assert_eq!(
+        fat.write_block(0, &[0; BLOCK_SIZE]),
+        Err(block::BlockError::ReadOnly)
+    );
+    assert_eq!(
+        fat.read_block(SECTORS, &mut [0; BLOCK_SIZE]),
+        Err(block::BlockError::OutOfRange)
+    );
+}
+
+// dosfstools' fsck.fat on a dump of the volume. Not everywhere has it, run with
+// --ignored where it is installed.
+#[test]
+#[ignore = "needs fsck.fat from dosfstools"]
+fn fsck() {
+    let mut fat = volume(100);
+    let path = std::env::temp_dir().join(format!("virtual-fat-{}.img", std::process::id()));
+    std::fs::write(&path, image(&mut fat)).unwrap();
+
+    let output = ["fsck.fat", "/sbin/fsck.fat", "/usr/sbin/fsck.fat"]
+        .iter()
+        .find_map(|fsck| {
+            Command::new(fsck)
+                .arg("-n")
+                .arg("-v")
+                .arg(&path)
+                .output()
+                .ok()
+        });
+    std::fs::remove_file(&path).unwrap();
+
+    let output = output.expect("fsck.fat not found");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(output.status.success(), "{}", stdout);
+    assert!(!stdout.contains("differ"), "{}", stdout);
+}
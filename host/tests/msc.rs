@@ -0,0 +1,436 @@
+// The firmware's mass storage class, its transport and SCSI layer, on a RAM disk.
+
+#[path = "../../src/usb"]
+#[allow(clippy::unusual_byte_groupings, clippy::upper_case_acronyms)]
+mod usb {
+    pub mod block;
+    pub mod bot;
+    pub mod class;
+    pub mod constants;
+    pub mod control;
+    pub mod endpoint;
+    pub mod msc;
+    pub mod scsi;
+}
+
+use usb::block::{BlockDevice, RamDisk, BLOCK_SIZE};
+use usb::bot::{self, Bot, Stalls};
+use usb::class::UsbClass;
+use usb::control::{ControlResponse, SetupPacket};
+use usb::endpoint::{EndpointError, Endpoints};
+use usb::msc::MassStorage;
+use usb::scsi::Scsi;
+
+const PASSED: u8 = 0;
+const FAILED: u8 = 1;
+const PHASE_ERROR: u8 = 2;
+
+struct Transaction {
+    data: Vec<u8>,
+    stalls: Stalls,
+    residue: u32,
+    status: u8,
+}
+
+fn scsi() -> Scsi<RamDisk<8>> {
+    Scsi::new(RamDisk::new(), "bentwire", "STM32F072", "0001")
+}
+
+fn bot() -> Bot<RamDisk<8>> {
+    Bot::new(scsi())
+}
+
+fn cbw(length: u32, host_in: bool, cdb: &[u8]) -> [u8; 31] {
+    let mut cbw = [0u8; 31];
+    cbw[0..4].copy_from_slice(b"USBC");
+    cbw[4..8].copy_from_slice(&0x1234_5678u32.to_le_bytes());
+    cbw[8..12].copy_from_slice(&length.to_le_bytes());
+    cbw[12] = if host_in { 0x80 } else { 0x00 };
+    cbw[14] = cdb.len() as u8;
+    cbw[15..15 + cdb.len()].copy_from_slice(cdb);
+    cbw
+}
+
+fn rw10(opcode: u8, lba: u32, blocks: u16) -> [u8; 10] {
+    let mut cdb = [0u8; 10];
+    cdb[0] = opcode;
+    cdb[2..6].copy_from_slice(&lba.to_be_bytes());
+    cdb[7..9].copy_from_slice(&blocks.to_be_bytes());
+    cdb
+}
+
+// One command: the CBW, the host's OUT data, then IN packets up to and including the CSW.
+fn transact(bot: &mut Bot<RamDisk<8>>, cbw: &[u8], out: &[u8]) -> Transaction {
+    bot.packet_out(cbw);
+    for chunk in out.chunks(bot::PACKET_SIZE) {
+        if !bot.wants_out() {
+            break;
+        }
+        bot.packet_out(chunk);
+    }
+
+    let mut data = Vec::new();
+    let mut stalls = Stalls::default();
+    loop {
+        let s = bot.take_stalls();
+        stalls.bulk_in |= s.bulk_in;
+        stalls.bulk_out |= s.bulk_out;
+
+        let packet = bot.packet_in().expect("transport stuck").to_vec();
+        bot.packet_sent();
+        // Only the CSW going out gets it back to waiting for a CBW.
+        if bot.wants_out() {
+            assert_eq!(packet.len(), 13);
+            assert_eq!(&packet[0..4], b"USBS");
+            assert_eq!(&packet[4..8], &0x1234_5678u32.to_le_bytes());
+            return Transaction {
+                data,
+                stalls,
+                residue: u32::from_le_bytes([packet[8], packet[9], packet[10], packet[11]]),
+                status: packet[12],
+            };
+        }
+        data.extend_from_slice(&packet);
+    }
+}
+
+#[test]
+fn inquiry() {
+    let mut bot = bot();
+    let r = transact(&mut bot, &cbw(36, true, &[0x12, 0, 0, 0, 36, 0]), &[]);
+    assert_eq!((r.status, r.residue), (PASSED, 0));
+    assert_eq!(r.data.len(), 36);
+    assert_eq!(r.data[1], 0x80);
+    assert_eq!(&r.data[8..16], b"bentwire");
+    assert_eq!(&r.data[16..32], b"STM32F072       ");
+    assert_eq!(r.stalls, Stalls::default());
+}
+
+#[test]
+fn read_capacity() {
+    let mut bot = bot();
+    let r = transact(
+        &mut bot,
+        &cbw(8, true, &[0x25, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
+        &[],
+    );
+    assert_eq!(r.status, PASSED);
+    assert_eq!(r.data, [0, 0, 0, 7, 0, 0, 2, 0]);
+}
+
+#[test]
+fn write_then_read() {
+    let mut bot = bot();
+    let blocks: Vec<u8> = (0..2 * BLOCK_SIZE).map(|i| (i % 251) as u8).collect();
+
+    let r = transact(&mut bot, &cbw(1024, false, &rw10(0x2A, 3, 2)), &blocks);
+    assert_eq!((r.status, r.residue), (PASSED, 0));
+
+    let r = transact(&mut bot, &cbw(1024, true, &rw10(0x28, 3, 2)), &[]);
+    assert_eq!((r.status, r.residue), (PASSED, 0));
+    assert_eq!(r.data, blocks);
+
+    let mut block = [0u8; BLOCK_SIZE];
+    bot.scsi().device().read_block(4, &mut block).unwrap();
+    assert_eq!(&block[..], &blocks[BLOCK_SIZE..]);
+}
+
+#[test]
+fn check_condition_and_sense() {
+    let mut bot = bot();
+
+    // Unknown opcode, with no data phase.
+    let r = transact(&mut bot, &cbw(0, false, &[0xFF, 0, 0, 0, 0, 0]), &[]);
+    assert_eq!((r.status, r.residue), (FAILED, 0));
+
+    let r = transact(&mut bot, &cbw(18, true, &[0x03, 0, 0, 0, 18, 0]), &[]);
+    assert_eq!(r.status, PASSED);
+    assert_eq!((r.data[2], r.data[12]), (0x05, 0x20));
+
+    // Past the end, the host's data phase is halted.
+    let r = transact(&mut bot, &cbw(1024, true, &rw10(0x28, 7, 2)), &[]);
+    assert_eq!((r.status, r.residue), (FAILED, 1024));
+    assert!(r.stalls.bulk_in);
+
+    let r = transact(&mut bot, &cbw(18, true, &[0x03, 0, 0, 0, 18, 0]), &[]);
+    assert_eq!((r.data[2], r.data[12]), (0x05, 0x21));
+
+    // Read after the sense is cleared.
+    let r = transact(&mut bot, &cbw(18, true, &[0x03, 0, 0, 0, 18, 0]), &[]);
+    assert_eq!((r.data[2], r.data[12]), (0x00, 0x00));
+}
+
+#[test]
+fn thirteen_cases() {
+    let mut bot = bot();
+    let tur = [0x00, 0, 0, 0, 0, 0];
+
+    // Case 4, Hi > Dn.
+    let r = transact(&mut bot, &cbw(8, true, &tur), &[]);
+    assert_eq!((r.status, r.residue), (PASSED, 8));
+    assert!(r.stalls.bulk_in && !r.stalls.bulk_out);
+
+    // Case 5, Hi > Di: the short reply, then a halt.
+    let r = transact(&mut bot, &cbw(64, true, &[0x12, 0, 0, 0, 36, 0]), &[]);
+    assert_eq!((r.status, r.residue, r.data.len()), (PASSED, 28, 36));
+    assert!(r.stalls.bulk_in);
+
+    // Case 7, Hi < Di.
+    let r = transact(&mut bot, &cbw(512, true, &rw10(0x28, 0, 2)), &[]);
+    assert_eq!((r.status, r.residue, r.data.len()), (PHASE_ERROR, 0, 512));
+
+    // Case 8, Hi <> Do.
+    let r = transact(&mut bot, &cbw(512, true, &rw10(0x2A, 0, 1)), &[]);
+    assert_eq!(r.status, PHASE_ERROR);
+    assert!(r.stalls.bulk_in);
+
+    // Case 9, Ho > Dn.
+    let r = transact(&mut bot, &cbw(64, false, &tur), &[]);
+    assert_eq!((r.status, r.residue), (PASSED, 64));
+    assert!(r.stalls.bulk_out);
+
+    // Case 11, Ho > Do.
+    let r = transact(&mut bot, &cbw(1024, false, &rw10(0x2A, 0, 1)), &[0u8; 1024]);
+    assert_eq!((r.status, r.residue), (PASSED, 512));
+    assert!(r.stalls.bulk_out);
+
+    // Case 13, Ho < Do.
+    let r = transact(&mut bot, &cbw(512, false, &rw10(0x2A, 0, 2)), &[0u8; 512]);
+    assert_eq!(r.status, PHASE_ERROR);
+
+    // Case 2, Hn < Di.
+    let r = transact(&mut bot, &cbw(0, true, &[0x12, 0, 0, 0, 36, 0]), &[]);
+    assert_eq!(r.status, PHASE_ERROR);
+}
+
+#[test]
+fn invalid_cbw_needs_reset() {
+    let mut bot = bot();
+    let mut bad = cbw(0, false, &[0x00, 0, 0, 0, 0, 0]);
+    bad[0] = b'X';
+    bot.packet_out(&bad);
+
+    assert_eq!(
+        bot.take_stalls(),
+        Stalls {
+            bulk_in: true,
+            bulk_out: true
+        }
+    );
+    assert!(bot.needs_reset());
+    assert!(!bot.wants_out());
+    assert!(bot.packet_in().is_none());
+
+    bot.reset();
+    assert!(!bot.needs_reset());
+    let r = transact(&mut bot, &cbw(0, false, &[0x00, 0, 0, 0, 0, 0]), &[]);
+    assert_eq!(r.status, PASSED);
+}
+
+const EP_IN: u8 = 0x86;
+const EP_OUT: u8 = 0x06;
+
+// What the host sees of the IN endpoint.
+#[derive(Debug, PartialEq)]
+enum In {
+    Nak,
+    Stall,
+    Data(Vec<u8>),
+}
+
+// The bulk endpoints as the hardware has them, one packet buffer each way. A halt set
+// over a queued IN packet replaces it, STAT_TX only has the one state.
+#[derive(Default)]
+struct Sim {
+    queued_in: Option<Vec<u8>>,
+    queued_out: Option<Vec<u8>>,
+    halted_in: bool,
+    halted_out: bool,
+}
+
+impl Sim {
+    fn host_out(&mut self, packet: &[u8]) {
+        assert!(!self.halted_out && self.queued_out.is_none(), "OUT NAK");
+        self.queued_out = Some(packet.to_vec());
+    }
+
+    fn host_in(&mut self) -> In {
+        if self.halted_in {
+            In::Stall
+        } else {
+            self.queued_in.take().map_or(In::Nak, In::Data)
+        }
+    }
+
+    // CLEAR_FEATURE(ENDPOINT_HALT), the class gets it first as Usb::interrupt does.
+    fn clear_halt(&mut self, msc: &mut MassStorage<RamDisk<8>>, address: u8) {
+        let setup = SetupPacket {
+            bmRequestType: 0x02,
+            bRequest: 0x01,
+            wValue: 0,
+            wIndex: address as u16,
+            wLength: 0,
+        };
+        if msc.control(&setup, &mut []).is_none() {
+            self.set_stalled(address, false).unwrap();
+        }
+    }
+}
+
+impl Endpoints for Sim {
+    fn write(&mut self, address: u8, data: &[u8]) -> Result<usize, EndpointError> {
+        assert_eq!(address, EP_IN);
+        assert!(data.len() <= bot::PACKET_SIZE);
+        if self.halted_in || self.queued_in.is_some() {
+            return Err(EndpointError::WouldBlock);
+        }
+        self.queued_in = Some(data.to_vec());
+        Ok(data.len())
+    }
+
+    fn read(&mut self, address: u8, buf: &mut [u8]) -> Result<usize, EndpointError> {
+        assert_eq!(address, EP_OUT);
+        if self.halted_out {
+            return Err(EndpointError::WouldBlock);
+        }
+        let packet = self.queued_out.take().ok_or(EndpointError::WouldBlock)?;
+        buf[..packet.len()].copy_from_slice(&packet);
+        Ok(packet.len())
+    }
+
+    fn set_stalled(&mut self, address: u8, stalled: bool) -> Result<(), EndpointError> {
+        match address {
+            EP_IN => {
+                self.halted_in = stalled;
+                self.queued_in = None;
+            }
+            EP_OUT => self.halted_out = stalled,
+            _ => return Err(EndpointError::InvalidEndpoint),
+        }
+        Ok(())
+    }
+
+    fn is_stalled(&self, address: u8) -> Result<bool, EndpointError> {
+        match address {
+            EP_IN => Ok(self.halted_in),
+            EP_OUT => Ok(self.halted_out),
+            _ => Err(EndpointError::InvalidEndpoint),
+        }
+    }
+
+    fn in_flight(&self, address: u8) -> Result<bool, EndpointError> {
+        assert_eq!(address, EP_IN);
+        Ok(self.queued_in.is_some())
+    }
+}
+
+fn csw(packet: In) -> (u32, u8) {
+    match packet {
+        In::Data(csw) => {
+            assert_eq!(csw.len(), 13);
+            assert_eq!(&csw[0..4], b"USBS");
+            (
+                u32::from_le_bytes([csw[8], csw[9], csw[10], csw[11]]),
+                csw[12],
+            )
+        }
+        other => panic!("expected a CSW, got {:?}", other),
+    }
+}
+
+// Case 5 through the class: the short reply has to reach the host before the halt does.
+#[test]
+fn short_reply_then_halt() {
+    let mut msc = MassStorage::new(0, EP_IN, EP_OUT, scsi());
+    let mut ep = Sim::default();
+
+    ep.host_out(&cbw(96, true, &[0x12, 0, 0, 0, 36, 0]));
+    msc.poll(&mut ep);
+    match ep.host_in() {
+        In::Data(data) => assert_eq!(data.len(), 36),
+        other => panic!("expected the INQUIRY data, got {:?}", other),
+    }
+
+    // Taken, now the halt goes on and the CSW waits behind it.
+    msc.poll(&mut ep);
+    assert_eq!(ep.host_in(), In::Stall);
+    msc.poll(&mut ep);
+    assert_eq!(ep.host_in(), In::Stall);
+
+    ep.clear_halt(&mut msc, EP_IN);
+    msc.poll(&mut ep);
+    assert_eq!(csw(ep.host_in()), (60, PASSED));
+    assert_eq!(ep.host_in(), In::Nak);
+}
+
+// A full length reply isn't held back, and the next command goes through.
+#[test]
+fn reply_and_status() {
+    let mut msc = MassStorage::new(0, EP_IN, EP_OUT, scsi());
+    let mut ep = Sim::default();
+
+    ep.host_out(&cbw(8, true, &[0x25, 0, 0, 0, 0, 0, 0, 0, 0, 0]));
+    msc.poll(&mut ep);
+    assert_eq!(ep.host_in(), In::Data(vec![0, 0, 0, 7, 0, 0, 2, 0]));
+    msc.poll(&mut ep);
+    assert_eq!(csw(ep.host_in()), (0, PASSED));
+
+    // Case 4, nothing queued so the halt is immediate.
+    ep.host_out(&cbw(8, true, &[0x00, 0, 0, 0, 0, 0]));
+    msc.poll(&mut ep);
+    assert_eq!(ep.host_in(), In::Stall);
+    ep.clear_halt(&mut msc, EP_IN);
+    msc.poll(&mut ep);
+    assert_eq!(csw(ep.host_in()), (8, PASSED));
+}
+
+// An invalid CBW halts both ways until the Bulk-Only Mass Storage Reset, CLEAR_FEATURE
+// alone leaves them on.
+#[test]
+fn invalid_cbw_until_reset() {
+    let mut msc = MassStorage::new(0, EP_IN, EP_OUT, scsi());
+    let mut ep = Sim::default();
+
+    let mut bad = cbw(0, false, &[0x00, 0, 0, 0, 0, 0]);
+    bad[0] = b'X';
+    ep.host_out(&bad);
+    msc.poll(&mut ep);
+    assert_eq!(ep.is_stalled(EP_IN), Ok(true));
+    assert_eq!(ep.is_stalled(EP_OUT), Ok(true));
+
+    let reset = SetupPacket {
+        bmRequestType: 0x21,
+        bRequest: 0xFF,
+        wValue: 0,
+        wIndex: 0,
+        wLength: 0,
+    };
+    assert!(matches!(
+        msc.control(&reset, &mut []),
+        Some(ControlResponse::Accept)
+    ));
+    ep.clear_halt(&mut msc, EP_IN);
+    ep.clear_halt(&mut msc, EP_OUT);
+
+    ep.host_out(&cbw(0, false, &[0x00, 0, 0, 0, 0, 0]));
+    msc.poll(&mut ep);
+    assert_eq!(csw(ep.host_in()), (0, PASSED));
+}
+
+// A bus reset while the halt still waits on the short reply forgets both.
+#[test]
+fn bus_reset_drops_pending_halt() {
+    let mut msc = MassStorage::new(0, EP_IN, EP_OUT, scsi());
+    let mut ep = Sim::default();
+
+    ep.host_out(&cbw(96, true, &[0x12, 0, 0, 0, 36, 0]));
+    msc.poll(&mut ep);
+    msc.reset();
+    ep = Sim::default();
+    msc.poll(&mut ep);
+    assert_eq!(ep.host_in(), In::Nak);
+
+    ep.host_out(&cbw(0, false, &[0x00, 0, 0, 0, 0, 0]));
+    msc.poll(&mut ep);
+    assert_eq!(csw(ep.host_in()), (0, PASSED));
+}
@@ -0,0 +1,246 @@
+// The firmware's PMA code on a PMA made of RAM: 512 half words, as the peripheral has.
+
+use std::cell::Cell;
+
+#[path = "../../src/usb/pma.rs"]
+#[allow(
+    clippy::manual_div_ceil,
+    clippy::manual_is_multiple_of,
+    clippy::upper_case_acronyms
+)]
+mod pma;
+
+use pma::{rx_size_bits, PMA_Area, PmaAllocator, PmaError, MAX_RX_SIZE, PMA_SIZE};
+
+struct Memory {
+    words: Vec<Cell<u16>>,
+}
+
+impl Memory {
+    // Neighbouring bytes all differ, so a write in the wrong place shows.
+    fn new() -> Self {
+        let words = (0..PMA_SIZE / 2)
+            .map(|i| Cell::new(u16::from_le_bytes([pattern(2 * i), pattern(2 * i + 1)])))
+            .collect();
+        Memory { words }
+    }
+
+    // VolatileCell and Cell are both a transparent UnsafeCell, PMA_Area is the array.
+    fn area(&self) -> &PMA_Area {
+        unsafe { &*(self.words.as_ptr() as *const PMA_Area) }
+    }
+
+    fn byte(&self, offset: usize) -> u8 {
+        self.words[offset / 2].get().to_le_bytes()[offset % 2]
+    }
+}
+
+fn pattern(offset: usize) -> u8 {
+    (offset * 7 + 3) as u8
+}
+
+#[test]
+fn rx_size() {
+    // 2 byte blocks up to 62 bytes, NUM_BLOCK in bits 14:10.
+    assert_eq!(rx_size_bits(0), Ok((0x0000, 0)));
+    assert_eq!(rx_size_bits(1), Ok((0x0400, 2)));
+    assert_eq!(rx_size_bits(2), Ok((0x0400, 2)));
+    assert_eq!(rx_size_bits(3), Ok((0x0800, 4)));
+    assert_eq!(rx_size_bits(61), Ok((0x7C00, 62)));
+    assert_eq!(rx_size_bits(62), Ok((0x7C00, 62)));
+
+    // Then BL_SIZE and 32 byte blocks, NUM_BLOCK is one less than the count.
+    assert_eq!(rx_size_bits(63), Ok((0x8400, 64)));
+    assert_eq!(rx_size_bits(64), Ok((0x8400, 64)));
+    assert_eq!(rx_size_bits(65), Ok((0x8800, 96)));
+    assert_eq!(rx_size_bits(992), Ok((0xF800, 992)));
+    assert_eq!(rx_size_bits(993), Ok((0xFC00, 1024)));
+    assert_eq!(rx_size_bits(MAX_RX_SIZE), Ok((0xFC00, 1024)));
+    assert_eq!(rx_size_bits(1024), Err(PmaError::TooLarge(1024)));
+}
+
+#[test]
+fn allocator() {
+    let mut alloc = PmaAllocator::new();
+    assert_eq!(alloc.available(), PMA_SIZE - 64);
+
+    // After the BTABLE, half word aligned.
+    assert_eq!(alloc.alloc_tx(64), Ok(64));
+    assert_eq!(alloc.alloc_tx(7), Ok(128));
+    assert_eq!(alloc.alloc_tx(8), Ok(136));
+
+    // RX rounds up to what COUNTn_RX can describe.
+    assert_eq!(alloc.alloc_rx(63), Ok((144, 0x8400)));
+    assert_eq!(alloc.alloc_rx(5), Ok((208, 0x0C00)));
+    assert_eq!(alloc.available(), PMA_SIZE - 214);
+
+    // Running out leaves what is left for a smaller request.
+    assert_eq!(
+        alloc.alloc_tx(811),
+        Err(PmaError::OutOfMemory {
+            requested: 812,
+            available: 810
+        })
+    );
+    assert_eq!(
+        alloc.alloc_rx(801),
+        Err(PmaError::OutOfMemory {
+            requested: 832,
+            available: 810
+        })
+    );
+    assert_eq!(alloc.alloc_tx(810), Ok(214));
+    assert_eq!(alloc.available(), 0);
+    assert_eq!(
+        alloc.alloc_rx(MAX_RX_SIZE + 1),
+        Err(PmaError::TooLarge(1024))
+    );
+
+    alloc.reset();
+    assert_eq!(alloc.alloc_tx(64), Ok(64));
+}
+
+#[test]
+fn buffer_descriptor() {
+    let memory = Memory::new();
+    let pma = memory.area();
+
+    // EP3's entry is the fourth 8 byte one.
+    let bd = pma.descriptor(3);
+    bd.set_addr_tx(0x0140);
+    bd.set_count_tx(18);
+    bd.set_addr_rx(0x0180);
+    bd.set_rx_size(0x8400);
+    assert_eq!(memory.words[12].get(), 0x0140);
+    assert_eq!(memory.words[13].get(), 18);
+    assert_eq!(memory.words[14].get(), 0x0180);
+    assert_eq!(memory.words[15].get(), 0x8400);
+    assert_eq!(
+        memory.words[11].get(),
+        u16::from_le_bytes([pattern(22), pattern(23)])
+    );
+    assert_eq!(
+        memory.words[16].get(),
+        u16::from_le_bytes([pattern(32), pattern(33)])
+    );
+
+    // The hardware puts the count in the low 10 bits, under BL_SIZE/NUM_BLOCK.
+    memory.words[15].set(0x8400 | 37);
+    assert_eq!(bd.count_rx(), 37);
+    memory.words[15].set(0xFC00 | 1023);
+    assert_eq!(bd.count_rx(), 1023);
+    assert_eq!(bd.addr_rx(), 0x0180);
+    assert_eq!(bd.addr_tx(), 0x0140);
+    assert_eq!(bd.count_tx(), 18);
+
+    // Double buffered, buffer 0 in the TX half and buffer 1 in the RX half.
+    bd.set_addr_buf(0, 0x0200);
+    bd.set_addr_buf(1, 0x0240);
+    bd.set_count_buf(1, 0x8400);
+    assert_eq!(bd.addr_tx(), 0x0200);
+    assert_eq!(bd.addr_rx(), 0x0240);
+    memory.words[15].set(0x8400 | 64);
+    assert_eq!(bd.count_buf(1), 64);
+    assert_eq!(bd.count_buf(0), 18);
+}
+
+#[test]
+fn bytes() {
+    let memory = Memory::new();
+    let pma = memory.area();
+
+    assert_eq!(pma.get_u8(100), pattern(100));
+    assert_eq!(pma.get_u8(101), pattern(101));
+
+    // Each half of a half word on its own.
+    pma.set_u8(101, 0xAB);
+    assert_eq!(
+        memory.words[50].get(),
+        u16::from_le_bytes([pattern(100), 0xAB])
+    );
+    pma.set_u8(100, 0xCD);
+    assert_eq!(memory.words[50].get(), 0xABCD);
+    assert_eq!(pma.get_u16(100), 0xABCD);
+}
+
+// Every start offset against every length up to a few half words, including none.
+#[test]
+fn write_buffer() {
+    for offset in 200..204 {
+        for len in 0..10 {
+            let memory = Memory::new();
+            let data: Vec<u8> = (0..len).map(|i| 0xF0 | i as u8).collect();
+            memory.area().write_buffer_u8(offset, &data);
+
+            for at in 190..220 {
+                let expected = if (offset..offset + len).contains(&at) {
+                    data[at - offset]
+                } else {
+                    pattern(at)
+                };
+                assert_eq!(
+                    memory.byte(at),
+                    expected,
+                    "offset {} len {}, byte {}",
+                    offset,
+                    len,
+                    at
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn read_buffer() {
+    let memory = Memory::new();
+    for offset in 200..204 {
+        for len in 0..10 {
+            // One more than asked for, which must be left alone.
+            let mut buf = vec![0x55; len + 1];
+            memory.area().read_buffer_u8(offset, &mut buf[..len]);
+            let expected: Vec<u8> = (offset..offset + len).map(pattern).collect();
+            assert_eq!(&buf[..len], &expected[..], "offset {} len {}", offset, len);
+            assert_eq!(buf[len], 0x55);
+        }
+    }
+}
+
+// A packet in and out of the last bytes of the PMA, odd at both ends.
+#[test]
+fn end_of_pma() {
+    let memory = Memory::new();
+    let pma = memory.area();
+    let data = [1, 2, 3, 4, 5];
+    pma.write_buffer_u8(PMA_SIZE - 5, &data);
+    let mut buf = [0u8; 5];
+    pma.read_buffer_u8(PMA_SIZE - 5, &mut buf);
+    assert_eq!(buf, data);
+    assert_eq!(memory.byte(PMA_SIZE - 6), pattern(PMA_SIZE - 6));
+}
+
+// What usb.rs does with a packet: allocate, describe, copy in, copy out.
+#[test]
+fn round_trip() {
+    let memory = Memory::new();
+    let pma = memory.area();
+    let mut alloc = PmaAllocator::new();
+
+    let tx = alloc.alloc_tx(64).unwrap();
+    let (rx, bits) = alloc.alloc_rx(64).unwrap();
+    let bd = pma.descriptor(1);
+    bd.set_addr_tx(tx);
+    bd.set_addr_rx(rx);
+    bd.set_rx_size(bits);
+
+    let packet: Vec<u8> = (0..63).map(|i| i as u8 ^ 0x5A).collect();
+    pma.write_buffer_u8(bd.addr_tx(), &packet);
+    bd.set_count_tx(packet.len());
+
+    let mut out = [0u8; 64];
+    pma.read_buffer_u8(bd.addr_tx(), &mut out[..bd.count_tx()]);
+    assert_eq!(&out[..63], &packet[..]);
+
+    // Nothing spilled in to the RX buffer right after it.
+    assert_eq!(memory.byte(rx), pattern(rx));
+}
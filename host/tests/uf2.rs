@@ -0,0 +1,217 @@
+// UF2 files copied to the firmware's update drive, through the mass storage transport
+// and SCSI WRITE(10) in to a simulated flash.
+
+#[path = "../../src/usb/block.rs"]
+mod block;
+#[path = "../../src/usb/bot.rs"]
+mod bot;
+#[path = "../../src/usb/fat.rs"]
+mod fat;
+#[path = "../../src/usb/flash.rs"]
+mod flash;
+#[path = "../../src/usb/scsi.rs"]
+mod scsi;
+#[path = "../../src/usb/uf2.rs"]
+mod uf2;
+
+mod common;
+
+use block::{BlockDevice, BLOCK_SIZE};
+use bot::Bot;
+use common::SimFlash;
+use fat::{File, VirtualFat};
+use flash::{IMAGE_SIZE, IMAGE_START};
+use scsi::Scsi;
+use uf2::{Uf2, FAMILY_STM32F0};
+
+static FILES: [File<Uf2<SimFlash>>; 2] = uf2::files();
+
+type Drive = VirtualFat<Uf2<SimFlash>>;
+
+fn bot() -> Bot<Drive> {
+    let uf2 = Uf2::new(
+        SimFlash::new(),
+        "v0.1.0",
+        "STM32F072 USB",
+        "STM32F072-bentwire",
+        "https://github.com/bentwire/stm32f072-usb",
+    );
+    let drive = VirtualFat::new("F072BOOT", 1, &FILES, uf2).on_write(uf2::write);
+    Bot::new(Scsi::new(drive, "bentwire", "STM32F072 UF2", "0001"))
+}
+
+fn uf2(bot: &mut Bot<Drive>) -> &mut Uf2<SimFlash> {
+    bot.scsi().device().context_mut()
+}
+
+// What uf2conv.py makes of a binary: 256 byte payloads with the family id.
+fn uf2_file(image: &[u8], address: u32, family: u32) -> Vec<u8> {
+    let blocks = image.len().div_ceil(256);
+    let mut file = Vec::new();
+    for (n, payload) in image.chunks(256).enumerate() {
+        let mut block = [0u8; BLOCK_SIZE];
+        let words = [
+            0x0A32_4655,
+            0x9E5D_5157,
+            0x0000_2000,
+            address + n as u32 * 256,
+            payload.len() as u32,
+            n as u32,
+            blocks as u32,
+            family,
+        ];
+        for (i, word) in words.iter().enumerate() {
+            block[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+        }
+        block[32..32 + payload.len()].copy_from_slice(payload);
+        block[508..512].copy_from_slice(&0x0AB1_6F30u32.to_le_bytes());
+        file.extend_from_slice(&block);
+    }
+    file
+}
+
+fn firmware(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 + i / 251) as u8).collect()
+}
+
+// WRITE(10) of data at lba, as 64 byte bulk packets. Returns the CSW status.
+fn write10(bot: &mut Bot<Drive>, lba: u32, data: &[u8]) -> u8 {
+    let mut cbw = [0u8; 31];
+    cbw[0..4].copy_from_slice(b"USBC");
+    cbw[8..12].copy_from_slice(&(data.len() as u32).to_le_bytes());
+    cbw[14] = 10;
+    cbw[15] = 0x2A;
+    cbw[17..21].copy_from_slice(&lba.to_be_bytes());
+    cbw[22..24].copy_from_slice(&((data.len() / BLOCK_SIZE) as u16).to_be_bytes());
+    bot.packet_out(&cbw);
+    for packet in data.chunks(bot::PACKET_SIZE) {
+        assert!(bot.wants_out());
+        bot.packet_out(packet);
+    }
+    let csw = bot.packet_in().expect("no CSW").to_vec();
+    bot.packet_sent();
+    assert_eq!(&csw[0..4], b"USBS");
+    csw[12]
+}
+
+// The data area, past the FAT and directory.
+const DATA_LBA: u32 = 100;
+
+#[test]
+fn copy_a_file() {
+    let mut bot = bot();
+    let image = firmware(10_000);
+    let file = uf2_file(&image, IMAGE_START, FAMILY_STM32F0);
+
+    // The host also updates the directory and FAT, those are dropped.
+    assert_eq!(write10(&mut bot, 13, &[0x41; BLOCK_SIZE]), 0);
+    for (n, chunk) in file.chunks(8 * BLOCK_SIZE).enumerate() {
+        assert!(!uf2(&mut bot).is_done());
+        let lba = DATA_LBA + n as u32 * 8;
+        assert_eq!(write10(&mut bot, lba, chunk), 0);
+    }
+    assert_eq!(write10(&mut bot, 1, &[0x42; BLOCK_SIZE]), 0);
+
+    let uf2 = uf2(&mut bot);
+    assert!(uf2.is_done());
+    assert_eq!(uf2.progress(), (40, 40));
+    assert_eq!(uf2.rejected(), 0);
+
+    // Nothing touched the image until install, which takes the five pages written.
+    assert!(uf2.update().flash().image(image.len()) != &image[..]);
+    uf2.update().install();
+    let flash = uf2.update().flash();
+    assert_eq!(flash.installed, Some(0b1_1111));
+    assert_eq!(flash.image(image.len()), &image[..]);
+}
+
+#[test]
+fn any_order_and_repeats() {
+    let mut bot = bot();
+    let image = firmware(4096);
+    let file = uf2_file(&image, IMAGE_START, FAMILY_STM32F0);
+    let blocks: Vec<&[u8]> = file.chunks(BLOCK_SIZE).collect();
+
+    for &n in &[3, 0, 15, 3, 7, 1, 2, 4, 5, 6, 8, 9, 10, 11, 12, 13, 0] {
+        assert!(!uf2(&mut bot).is_done());
+        assert_eq!(write10(&mut bot, DATA_LBA + n as u32, blocks[n]), 0);
+    }
+    assert!(!uf2(&mut bot).is_done());
+    assert_eq!(write10(&mut bot, DATA_LBA + 14, blocks[14]), 0);
+    assert!(uf2(&mut bot).is_done());
+
+    let uf2 = uf2(&mut bot);
+    uf2.update().install();
+    assert_eq!(uf2.update().flash().image(image.len()), &image[..]);
+}
+
+#[test]
+fn other_family_and_out_of_range_are_dropped() {
+    let mut bot = bot();
+    let image = firmware(1024);
+
+    let file = uf2_file(&image, IMAGE_START, 0x5EE2_1072); // STM32F1
+    for (n, block) in file.chunks(BLOCK_SIZE).enumerate() {
+        assert_eq!(write10(&mut bot, DATA_LBA + n as u32, block), 0);
+    }
+    assert_eq!(uf2(&mut bot).rejected(), 4);
+    assert!(!uf2(&mut bot).is_done());
+
+    // Past the image, in to the staging half.
+    let file = uf2_file(&image, IMAGE_START + IMAGE_SIZE - 512, FAMILY_STM32F0);
+    for (n, block) in file.chunks(BLOCK_SIZE).enumerate() {
+        assert_eq!(write10(&mut bot, DATA_LBA + n as u32, block), 0);
+    }
+    assert_eq!(uf2(&mut bot).rejected(), 6);
+    assert_eq!(uf2(&mut bot).progress(), (2, 4));
+    assert!(!uf2(&mut bot).is_done());
+}
+
+// A file with blocks that aren't for main flash is complete once those are in as well,
+// and they are never written.
+#[test]
+fn not_main_flash_counts() {
+    let mut bot = bot();
+    let image = firmware(1024);
+    let mut file = uf2_file(&image, IMAGE_START, FAMILY_STM32F0);
+
+    // Block 2 is for somewhere else, at an address the image doesn't even have.
+    let block = &mut file[2 * BLOCK_SIZE..3 * BLOCK_SIZE];
+    block[8..12].copy_from_slice(&0x0000_2001u32.to_le_bytes());
+    block[12..16].copy_from_slice(&0x0900_0000u32.to_le_bytes());
+
+    for (n, block) in file.chunks(BLOCK_SIZE).enumerate() {
+        assert!(!uf2(&mut bot).is_done());
+        assert_eq!(write10(&mut bot, DATA_LBA + n as u32, block), 0);
+    }
+    let uf2 = uf2(&mut bot);
+    assert!(uf2.is_done());
+    assert_eq!(uf2.progress(), (4, 4));
+    assert_eq!(uf2.rejected(), 0);
+
+    uf2.update().install();
+    let flash = uf2.update().flash();
+    assert_eq!(flash.image(512), &image[..512]);
+    assert_eq!(&flash.image(1024)[768..], &image[768..]);
+    assert!(flash.image(768)[512..].iter().all(|&b| b == 0xFF));
+}
+
+#[test]
+fn info_files() {
+    let mut bot = bot();
+    let drive = bot.scsi().device();
+    assert!(!drive.is_read_only());
+
+    let mut root = [0u8; BLOCK_SIZE];
+    drive.read_block(13, &mut root).unwrap();
+    assert_eq!(&root[32..43], b"INFO_UF2TXT");
+    assert_eq!(&root[64..75], b"INDEX   HTM");
+
+    let mut info = [0u8; BLOCK_SIZE];
+    drive.read_block(14, &mut info).unwrap();
+    let size = u32::from_le_bytes([root[60], root[61], root[62], root[63]]) as usize;
+    assert_eq!(
+        &info[..size],
+        &b"UF2 Bootloader v0.1.0\r\nModel: STM32F072 USB\r\nBoard-ID: STM32F072-bentwire\r\n"[..]
+    );
+}
@@ -0,0 +1,403 @@
+// The firmware's descriptor validator, one inconsistency at a time.
+
+#[path = "../../src/usb"]
+#[allow(
+    clippy::manual_div_ceil,
+    clippy::manual_is_multiple_of,
+    clippy::multiple_bound_locations,
+    clippy::unusual_byte_groupings,
+    clippy::upper_case_acronyms,
+    clippy::wrong_self_convention
+)]
+mod usb {
+    pub mod constants;
+    pub mod descriptors;
+    pub mod pma;
+    pub mod types;
+    pub mod validate;
+
+    // EP0's buffers in usb.rs.
+    pub const MAX_PACKET_SIZE: u32 = 64;
+}
+
+use usb::descriptors::{Configuration, Device, Endpoint, Interface, StringTable};
+use usb::types::{self, Descriptors};
+use usb::validate::{validate, DescriptorError};
+
+const DEVICE: Device = Device::new().iManufacturer(1).iProduct(2);
+const HEADER: Configuration = Configuration::new().bConfigurationValue(1);
+const STRINGS: [&str; 3] = ["bentwire", "STM32F072 USB", "Serial port"];
+const TABLES: [StringTable; 1] = [StringTable::new(0x0409, &STRINGS)];
+
+const VENDOR: Interface = Interface::new().bNumEndpoints(2);
+const SECOND: Interface = VENDOR.bInterfaceNumber(1);
+const EP01: Endpoint = Endpoint::new().bEndpointAddress(0x01);
+const EP81: Endpoint = Endpoint::new().bEndpointAddress(0x81);
+const EP02: Endpoint = Endpoint::new().bEndpointAddress(0x02);
+const EP82: Endpoint = Endpoint::new().bEndpointAddress(0x82);
+
+fn check_with(
+    device: &Device,
+    header: &Configuration,
+    interfaces: &[types::Interface],
+    strings: &[StringTable],
+) -> Result<(), DescriptorError> {
+    let configurations = [types::Configuration::new(header, interfaces)];
+    validate(&Descriptors {
+        Device: types::Device::new(device, &configurations),
+        DeviceQualifier: None,
+        Strings: strings,
+        Bos: None,
+    })
+}
+
+fn check(interfaces: &[types::Interface]) -> Result<(), DescriptorError> {
+    check_with(&DEVICE, &HEADER, interfaces, &TABLES)
+}
+
+#[test]
+fn consistent() {
+    let eps = [types::Endpoint::new(&EP01), types::Endpoint::new(&EP81)];
+    let second = [types::Endpoint::new(&EP02), types::Endpoint::new(&EP82)];
+    let named = VENDOR.iInterface(3);
+    let interfaces = [
+        types::Interface::new(&named, &[], &eps),
+        types::Interface::new(&SECOND, &[], &second),
+    ];
+    assert_eq!(check(&interfaces), Ok(()));
+
+    // Declared counts that match are fine too.
+    let header = HEADER.wTotalLength(9 + 2 * (9 + 2 * 7)).bNumInterfaces(2);
+    assert_eq!(check_with(&DEVICE, &header, &interfaces, &TABLES), Ok(()));
+}
+
+#[test]
+fn max_packet_size0() {
+    for size in [0, 9, 128] {
+        assert_eq!(
+            check_with(&DEVICE.bMaxPacketSize0(size), &HEADER, &[], &TABLES),
+            Err(DescriptorError::MaxPacketSize0(size))
+        );
+    }
+    assert_eq!(
+        check_with(&DEVICE.bMaxPacketSize0(8), &HEADER, &[], &TABLES),
+        Ok(())
+    );
+}
+
+#[test]
+fn num_configurations() {
+    assert_eq!(
+        check_with(&DEVICE.bNumConfigurations(2), &HEADER, &[], &TABLES),
+        Err(DescriptorError::NumConfigurations {
+            declared: 2,
+            actual: 1
+        })
+    );
+}
+
+#[test]
+fn total_length() {
+    let eps = [types::Endpoint::new(&EP01), types::Endpoint::new(&EP81)];
+    let interfaces = [types::Interface::new(&VENDOR, &[], &eps)];
+    // 9 + 9 + 2 * 7.
+    assert_eq!(
+        check_with(&DEVICE, &HEADER.wTotalLength(32), &interfaces, &TABLES),
+        Ok(())
+    );
+    assert_eq!(
+        check_with(&DEVICE, &HEADER.wTotalLength(25), &interfaces, &TABLES),
+        Err(DescriptorError::TotalLength {
+            configuration: 1,
+            declared: 25,
+            actual: 32
+        })
+    );
+}
+
+#[test]
+fn num_interfaces() {
+    // Alternate settings don't count as interfaces of their own.
+    let alternate = VENDOR.bAlternateSetting(1).bNumEndpoints(0);
+    let eps = [types::Endpoint::new(&EP01), types::Endpoint::new(&EP81)];
+    let interfaces = [
+        types::Interface::new(&VENDOR, &[], &eps),
+        types::Interface::new(&alternate, &[], &[]),
+    ];
+    assert_eq!(
+        check_with(&DEVICE, &HEADER.bNumInterfaces(1), &interfaces, &TABLES),
+        Ok(())
+    );
+    assert_eq!(
+        check_with(&DEVICE, &HEADER.bNumInterfaces(2), &interfaces, &TABLES),
+        Err(DescriptorError::NumInterfaces {
+            configuration: 1,
+            declared: 2,
+            actual: 1
+        })
+    );
+}
+
+#[test]
+fn num_endpoints() {
+    let eps = [types::Endpoint::new(&EP01)];
+    assert_eq!(
+        check(&[types::Interface::new(&SECOND, &[], &eps)]),
+        Err(DescriptorError::NumEndpoints {
+            interface: 1,
+            declared: 2,
+            actual: 1
+        })
+    );
+}
+
+#[test]
+fn reserved_endpoint() {
+    // EP0, a number past EP7, and a reserved address bit.
+    for address in [0x00, 0x80, 0x08, 0x8F, 0x11] {
+        let ep = Endpoint::new().bEndpointAddress(address);
+        let eps = [types::Endpoint::new(&ep)];
+        let interface = VENDOR.bNumEndpoints(1);
+        assert_eq!(
+            check(&[types::Interface::new(&interface, &[], &eps)]),
+            Err(DescriptorError::ReservedEndpoint(address)),
+            "{:02x}",
+            address
+        );
+    }
+}
+
+#[test]
+fn duplicate_endpoint() {
+    // In one interface.
+    let eps = [types::Endpoint::new(&EP81), types::Endpoint::new(&EP81)];
+    assert_eq!(
+        check(&[types::Interface::new(&VENDOR, &[], &eps)]),
+        Err(DescriptorError::DuplicateEndpoint(0x81))
+    );
+
+    // Across two.
+    let eps = [types::Endpoint::new(&EP01), types::Endpoint::new(&EP81)];
+    let second = [types::Endpoint::new(&EP02), types::Endpoint::new(&EP01)];
+    assert_eq!(
+        check(&[
+            types::Interface::new(&VENDOR, &[], &eps),
+            types::Interface::new(&SECOND, &[], &second),
+        ]),
+        Err(DescriptorError::DuplicateEndpoint(0x01))
+    );
+
+    // Alternate settings of one interface may reuse them.
+    let alternate = VENDOR.bAlternateSetting(1);
+    assert_eq!(
+        check(&[
+            types::Interface::new(&VENDOR, &[], &eps),
+            types::Interface::new(&alternate, &[], &eps),
+        ]),
+        Ok(())
+    );
+}
+
+#[test]
+fn max_packet_size() {
+    let cases = [
+        (EP01.wMaxPacketSize(48), 48),   // Bulk is 8, 16, 32 or 64.
+        (EP01.wMaxPacketSize(128), 128), // Not at full speed.
+        (EP01.bmAttributes(0b11).wMaxPacketSize(0), 0),
+        (EP01.bmAttributes(0b11).wMaxPacketSize(65), 65),
+        (EP01.bmAttributes(0b01).wMaxPacketSize(1024), 1024),
+    ];
+    for (ep, size) in cases.iter() {
+        let eps = [types::Endpoint::new(ep)];
+        let interface = VENDOR.bNumEndpoints(1);
+        assert_eq!(
+            check(&[types::Interface::new(&interface, &[], &eps)]),
+            Err(DescriptorError::MaxPacketSize {
+                address: 0x01,
+                size: *size
+            })
+        );
+    }
+
+    // Interrupt sizes in between are fine.
+    let ep = EP01.bmAttributes(0b11).wMaxPacketSize(10);
+    let eps = [types::Endpoint::new(&ep)];
+    let interface = VENDOR.bNumEndpoints(1);
+    assert_eq!(
+        check(&[types::Interface::new(&interface, &[], &eps)]),
+        Ok(())
+    );
+}
+
+#[test]
+fn double_buffered() {
+    let interrupt = EP81.bmAttributes(0b11);
+    let eps = [
+        types::Endpoint::new(&EP01),
+        types::Endpoint::new(&interrupt).double_buffered(),
+    ];
+    assert_eq!(
+        check(&[types::Interface::new(&VENDOR, &[], &eps)]),
+        Err(DescriptorError::DoubleBuffered(0x81))
+    );
+}
+
+#[test]
+fn shared_double_buffered() {
+    // A double buffered EP1 OUT takes EP1R, EP1 IN can't have it too.
+    let eps = [
+        types::Endpoint::new(&EP01).double_buffered(),
+        types::Endpoint::new(&EP81),
+    ];
+    assert_eq!(
+        check(&[types::Interface::new(&VENDOR, &[], &eps)]),
+        Err(DescriptorError::SharedDoubleBuffered(0x01))
+    );
+
+    // Likewise an isochronous one.
+    let iso = EP82.bmAttributes(0b01);
+    let eps = [types::Endpoint::new(&EP01), types::Endpoint::new(&EP81)];
+    let second = [types::Endpoint::new(&EP02), types::Endpoint::new(&iso)];
+    assert_eq!(
+        check(&[
+            types::Interface::new(&VENDOR, &[], &eps),
+            types::Interface::new(&SECOND, &[], &second),
+        ]),
+        Err(DescriptorError::SharedDoubleBuffered(0x02))
+    );
+}
+
+#[test]
+fn mixed_type() {
+    // EP1R has one EP_TYPE, an interrupt EP1 IN and a bulk EP1 OUT can't both have it.
+    let interrupt = EP81.bmAttributes(0b11);
+    let eps = [
+        types::Endpoint::new(&EP01),
+        types::Endpoint::new(&interrupt),
+    ];
+    assert_eq!(
+        check(&[types::Interface::new(&VENDOR, &[], &eps)]),
+        Err(DescriptorError::MixedType(0x01))
+    );
+
+    // Across interfaces too.
+    let eps = [types::Endpoint::new(&EP01), types::Endpoint::new(&EP02)];
+    let second = [
+        types::Endpoint::new(&interrupt),
+        types::Endpoint::new(&EP82),
+    ];
+    assert_eq!(
+        check(&[
+            types::Interface::new(&VENDOR, &[], &eps),
+            types::Interface::new(&SECOND, &[], &second),
+        ]),
+        Err(DescriptorError::MixedType(0x01))
+    );
+}
+
+#[test]
+fn missing_string() {
+    // Device, configuration and interface strings.
+    assert_eq!(
+        check_with(&DEVICE.iSerialNumber(4), &HEADER, &[], &TABLES),
+        Err(DescriptorError::MissingString(4))
+    );
+    assert_eq!(
+        check_with(&DEVICE, &HEADER.iConfiguration(5), &[], &TABLES),
+        Err(DescriptorError::MissingString(5))
+    );
+    let named = VENDOR.iInterface(6);
+    let eps = [types::Endpoint::new(&EP01), types::Endpoint::new(&EP81)];
+    assert_eq!(
+        check(&[types::Interface::new(&named, &[], &eps)]),
+        Err(DescriptorError::MissingString(6))
+    );
+
+    // No strings at all, and a second language that is short of one.
+    assert_eq!(
+        check_with(&DEVICE, &HEADER, &[], &[]),
+        Err(DescriptorError::MissingString(1))
+    );
+    let german = ["bentwire", "STM32F072 USB"];
+    let tables = [TABLES[0], StringTable::new(0x0407, &german)];
+    assert_eq!(
+        check_with(&DEVICE.iSerialNumber(3), &HEADER, &[], &tables),
+        Err(DescriptorError::MissingString(3))
+    );
+}
+
+#[test]
+fn pma_exhausted() {
+    // BTABLE and EP0 take 192 bytes, six double buffered 64 byte endpoints 768 more.
+    let ins = [0x81, 0x82, 0x83].map(|address| EP81.bEndpointAddress(address));
+    let outs = [0x04, 0x05, 0x06].map(|address| EP01.bEndpointAddress(address));
+    let eps: Vec<types::Endpoint> = ins
+        .iter()
+        .chain(outs.iter())
+        .map(|ep| types::Endpoint::new(ep).double_buffered())
+        .collect();
+    let interface = VENDOR.bNumEndpoints(6);
+    assert_eq!(
+        check(&[types::Interface::new(&interface, &[], &eps)]),
+        Ok(())
+    );
+
+    // One more 64 byte endpoint fills the PMA exactly.
+    let interrupt = EP81.bEndpointAddress(0x87).bmAttributes(0b11);
+    let mut more = eps.clone();
+    more.push(types::Endpoint::new(&interrupt));
+    let interface = VENDOR.bNumEndpoints(7);
+    assert_eq!(
+        check(&[types::Interface::new(&interface, &[], &more)]),
+        Ok(())
+    );
+
+    // An isochronous one of only 40 bytes doesn't fit, it needs two buffers.
+    let iso = interrupt.bmAttributes(0b01).wMaxPacketSize(40);
+    more[6] = types::Endpoint::new(&iso);
+    assert_eq!(
+        check(&[types::Interface::new(&interface, &[], &more)]),
+        Err(DescriptorError::PmaExhausted {
+            required: 1040,
+            available: 1024
+        })
+    );
+}
+
+#[test]
+fn length() {
+    // A class specific descriptor one byte short of its bLength.
+    let functional: [u8; 4] = [5, 0x24, 0x00, 0x10];
+    let others: [&[u8]; 1] = [&functional];
+    let eps = [types::Endpoint::new(&EP01), types::Endpoint::new(&EP81)];
+    assert_eq!(
+        check(&[types::Interface::new(&VENDOR, &others, &eps)]),
+        Err(DescriptorError::Length {
+            descriptor_type: 0x24,
+            declared: 5,
+            actual: 4
+        })
+    );
+
+    let empty: [&[u8]; 1] = [&[]];
+    assert_eq!(
+        check(&[types::Interface::new(&VENDOR, &empty, &eps)]),
+        Err(DescriptorError::Length {
+            descriptor_type: 0,
+            declared: 0,
+            actual: 0
+        })
+    );
+
+    // A device descriptor from raw bytes.
+    let mut bytes = DEVICE.to_bytes();
+    bytes[0] = 17;
+    assert_eq!(
+        check_with(&Device::from(bytes), &HEADER, &[], &TABLES),
+        Err(DescriptorError::Length {
+            descriptor_type: 0x01,
+            declared: 17,
+            actual: 18
+        })
+    );
+}
@@ -1,8 +1,13 @@
 MEMORY
 {
   /* NOTE K = KiBi = 1024 bytes */
-  FLASH : ORIGIN = 0x08000000, LENGTH = 128K
-  RAM : ORIGIN = 0x20000000, LENGTH = 16K
+  /* The lower half of the 128K, the upper half stages firmware updates (UF2, DFU) */
+  /* before they are copied over this one. Keep src/usb/flash.rs in step. */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 64K
+  STAGING : ORIGIN = 0x08010000, LENGTH = 64K
+  /* The last 8 bytes of RAM are left out, they hold the boot request over a reset. */
+  /* Keep src/boot.rs in step. */
+  RAM : ORIGIN = 0x20000000, LENGTH = 16K - 8
 }
 
 /* This is where the call stack will be allocated. */
@@ -0,0 +1,86 @@
+use core::ptr::{read_volatile, write_volatile};
+
+use cortex_m_rt::pre_init;
+
+// Where to go after a reset, left in the RAM memory.x keeps out of the program's way.
+// Nothing clears it at startup, only a power cycle loses it.
+const REQUEST: *mut u32 = 0x2000_3FF8 as *mut u32;
+
+const REQUEST_UPDATE: u32 = 0x5550_4454; // "UPDT"
+const REQUEST_SYSTEM_MEMORY: u32 = 0x524F_4D21; // "ROM!"
+
+// AN2606, the ROM bootloader of the STM32F072.
+const SYSTEM_MEMORY: u32 = 0x1FFF_C800;
+
+// RCC and SYSCFG registers (RM0091), for mapping system memory at 0.
+const RCC_APB2ENR: *mut u32 = 0x4002_1018 as *mut u32;
+const RCC_APB2ENR_SYSCFGEN: u32 = 1 << 0;
+const SYSCFG_CFGR1: *mut u32 = 0x4001_0000 as *mut u32;
+const SYSCFG_CFGR1_MEM_MODE: u32 = 0b11;
+const SYSCFG_CFGR1_MEM_MODE_SYSTEM: u32 = 0b01;
+
+// SCB AIRCR with VECTKEY and SYSRESETREQ.
+const AIRCR: *mut u32 = 0xE000_ED0C as *mut u32;
+const AIRCR_SYSRESETREQ: u32 = 0x05FA_0004;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Boot {
+    // This firmware, in update mode.
+    Update,
+    // The ROM DFU bootloader, as with BOOT0 high.
+    SystemMemory,
+}
+
+// Reset in to target.
+pub fn reboot(target: Boot) -> ! {
+    let request = match target {
+        Boot::Update => REQUEST_UPDATE,
+        Boot::SystemMemory => REQUEST_SYSTEM_MEMORY,
+    };
+    cortex_m::interrupt::disable();
+    unsafe {
+        write_volatile(REQUEST, request);
+        cortex_m::asm::dsb();
+        write_volatile(AIRCR, AIRCR_SYSRESETREQ);
+    }
+    loop {}
+}
+
+// Whether the last reset was reboot(Boot::Update). Only the first call says so.
+pub fn update_requested() -> bool {
+    unsafe {
+        let requested = read_volatile(REQUEST) == REQUEST_UPDATE;
+        write_volatile(REQUEST, 0);
+        requested
+    }
+}
+
+// Straight after reset, before RAM is set up. The ROM bootloader expects the chip much
+// as it comes out of reset, so it is started from here. A Cortex-M0 has no VTOR, system
+// memory is mapped at 0 for the bootloader's vector table to be the one in use.
+#[pre_init]
+unsafe fn before_main() {
+    if read_volatile(REQUEST) != REQUEST_SYSTEM_MEMORY {
+        return;
+    }
+    write_volatile(REQUEST, 0);
+
+    write_volatile(
+        RCC_APB2ENR,
+        read_volatile(RCC_APB2ENR) | RCC_APB2ENR_SYSCFGEN,
+    );
+    write_volatile(
+        SYSCFG_CFGR1,
+        (read_volatile(SYSCFG_CFGR1) & !SYSCFG_CFGR1_MEM_MODE) | SYSCFG_CFGR1_MEM_MODE_SYSTEM,
+    );
+
+    let sp = read_volatile(SYSTEM_MEMORY as *const u32);
+    let reset = read_volatile((SYSTEM_MEMORY + 4) as *const u32);
+    core::arch::asm!(
+        "msr msp, {sp}",
+        "bx {reset}",
+        sp = in(reg) sp,
+        reset = in(reg) reset,
+        options(noreturn),
+    );
+}
@@ -0,0 +1,49 @@
+use core::fmt::{self, Write};
+
+use crate::usb::ring::RingBuffer;
+
+// The last N bytes of numbered event lines, the oldest lines make room for new ones.
+pub struct EventLog<const N: usize> {
+    text: RingBuffer<N>,
+    count: u32,
+}
+
+impl<const N: usize> EventLog<N> {
+    pub const fn new() -> Self {
+        EventLog {
+            text: RingBuffer::new(),
+            count: 0,
+        }
+    }
+
+    // One line, e.g. log.event(format_args!("USB {:?}", state)).
+    pub fn event(&mut self, args: fmt::Arguments) {
+        let count = self.count;
+        self.count = self.count.wrapping_add(1);
+        write!(self, "{:5} {}\r\n", count, args).ok();
+    }
+
+    pub fn write_to(&self, w: &mut dyn fmt::Write) -> fmt::Result {
+        for b in self.text.iter() {
+            w.write_char(b as char)?;
+        }
+        Ok(())
+    }
+}
+
+impl<const N: usize> fmt::Write for EventLog<N> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &b in s.as_bytes() {
+            if self.text.is_full() {
+                // Drop the oldest line.
+                while let Some(old) = self.text.pop() {
+                    if old == b'\n' {
+                        break;
+                    }
+                }
+            }
+            self.text.push(b);
+        }
+        Ok(())
+    }
+}
@@ -13,7 +13,6 @@ extern crate embedded_hal;
 extern crate stm32f0;
 extern crate stm32f0xx_hal as hal;
 
-use core::mem::size_of;
 use stm32f0::stm32f0x2;
 
 use hal::delay::Delay;
@@ -23,7 +22,6 @@ use hal::prelude::*;
 
 use cortex_m::interrupt::Mutex;
 use cortex_m::peripheral::Peripherals as c_m_Peripherals;
-use cortex_m_semihosting::{debug, hprintln};
 //use cortex_m_rt::{entry, interrupt};
 use cortex_m_rt::entry;
 
@@ -34,16 +32,34 @@ pub use hal::stm32;
 pub use hal::stm32::{interrupt, Interrupt, Peripherals, EXTI, I2C1, USB};
 //pub use hal::stm32::*;
 
+use usb_device::device::{StringDescriptors, UsbDeviceBuilder, UsbVidPid};
+
 use embedded_graphics::fonts::Font6x8;
 use embedded_graphics::prelude::*;
 use ssd1306::prelude::*;
 use ssd1306::Builder;
 
 use core::cell::RefCell;
+use core::fmt;
 use core::ops::DerefMut;
+mod boot;
+mod event_log;
 mod usb;
 
+use crate::boot::Boot;
+use crate::event_log::EventLog;
+use crate::usb::cdc;
 use crate::usb::descriptors::*;
+use crate::usb::dfu;
+use crate::usb::fat;
+use crate::usb::hid;
+use crate::usb::internal_flash::InternalFlash;
+use crate::usb::keyboard;
+use crate::usb::msc;
+use crate::usb::raw_hid;
+use crate::usb::scsi;
+use crate::usb::types;
+use crate::usb::uf2;
 
 // Make our LED globally available
 static LED: Mutex<RefCell<Option<gpioa::PA5<Output<PushPull>>>>> = Mutex::new(RefCell::new(None));
@@ -59,6 +75,85 @@ static USBDEV: Mutex<
     RefCell<Option<usb::Usb<USB, (gpioa::PA11<Alternate<AF0>>, gpioa::PA12<Alternate<AF0>>)>>>,
 > = Mutex::new(RefCell::new(None));
 
+// CDC-ACM port on the USB device, shared between the USB interrupt and main.
+static SERIAL: Mutex<RefCell<Option<cdc::CdcAcm>>> = Mutex::new(RefCell::new(None));
+
+// Boot keyboard on the USB device, the button types MACRO on it.
+static KEYBOARD: Mutex<RefCell<Option<keyboard::Keyboard>>> = Mutex::new(RefCell::new(None));
+
+// Raw HID channel on the USB device, messages from the host are sent back.
+static RAW_HID: Mutex<RefCell<Option<raw_hid::RawHid>>> = Mutex::new(RefCell::new(None));
+
+// Mass storage on the USB device, a read-only drive with DRIVE_FILES on it.
+static MSC: Mutex<RefCell<Option<msc::MassStorage<fat::VirtualFat<DriveStatus>>>>> =
+    Mutex::new(RefCell::new(None));
+
+// DFU runtime, DETACH or the vendor request reboots the device, see boot.rs.
+static DFU_RUNTIME: Mutex<RefCell<Option<dfu::DfuRuntime>>> = Mutex::new(RefCell::new(None));
+
+// The update drive and DFU, in place of all of the above when the button is held at
+// reset.
+type UpdateDrive = msc::MassStorage<fat::VirtualFat<uf2::Uf2<InternalFlash>>>;
+static UF2: Mutex<RefCell<Option<UpdateDrive>>> = Mutex::new(RefCell::new(None));
+static DFU: Mutex<RefCell<Option<dfu::Dfu<InternalFlash>>>> = Mutex::new(RefCell::new(None));
+
+static UF2_FILES: [fat::File<uf2::Uf2<InternalFlash>>; 2] = uf2::files();
+
+// Recent events, for LOG.TXT.
+static LOG: Mutex<RefCell<EventLog<512>>> = Mutex::new(RefCell::new(EventLog::new()));
+
+// What INFO.TXT shows, kept up to date by the USB interrupt.
+struct DriveStatus {
+    state: usb::UsbState,
+}
+
+static DRIVE_FILES: [fat::File<DriveStatus>; 2] = [
+    fat::File {
+        name: "INFO.TXT",
+        generate: info_txt,
+    },
+    fat::File {
+        name: "LOG.TXT",
+        generate: log_txt,
+    },
+];
+
+fn info_txt(status: &DriveStatus, w: &mut dyn fmt::Write) -> fmt::Result {
+    write!(w, "{}\r\n", STRINGS_EN_US[1])?;
+    write!(w, "Firmware {}\r\n", env!("CARGO_PKG_VERSION"))?;
+    write!(w, "Serial number {}\r\n", STRINGS_EN_US[2])?;
+    write!(w, "USB {:?}\r\n", status.state)
+}
+
+fn log_txt(_: &DriveStatus, w: &mut dyn fmt::Write) -> fmt::Result {
+    cortex_m::interrupt::free(|cs| LOG.borrow(cs).borrow().write_to(w))
+}
+
+fn log(args: fmt::Arguments) {
+    cortex_m::interrupt::free(|cs| LOG.borrow(cs).borrow_mut().event(args));
+}
+
+// Copy a .uf2 file on to it to update the firmware.
+fn update_drive(flash: InternalFlash) -> UpdateDrive {
+    let uf2 = uf2::Uf2::new(
+        flash,
+        concat!("v", env!("CARGO_PKG_VERSION")),
+        UPDATE_STRINGS_EN_US[1],
+        "STM32F072RB-Nucleo",
+        "https://github.com/bentwire/stm32f072-usb",
+    );
+    let drive =
+        fat::VirtualFat::new("F072UPDATE", 0x0002_F072, &UF2_FILES, uf2).on_write(uf2::write);
+    let disk = scsi::Scsi::new(drive, "bentwire", "STM32F072 update", "0001");
+    msc::MassStorage::new(0, EP86_DESC.address(), EP06_DESC.address(), disk)
+}
+
+const MACRO: &str = "Hello from the STM32F072!\n";
+
+// Data stage buffer for EP0 control transfers, DFU blocks included.
+static mut EP0_BUF: [u8; 256] = [0; 256];
+
+// Composite device, the class is given by each interface.
 const DEV_DESC: Device = Device::new()
     .iManufacturer(1)
     .iProduct(2)
@@ -67,38 +162,256 @@ const DEV_DESC: Device = Device::new()
 
 const DEV_QUAL: DeviceQualifier = DeviceQualifier::new().bcdUSB(0x0200);
 
-const INTERFACE_DESC: Interface = Interface::new().bNumEndpoints(1).iInterface(5);
+// CDC-ACM, a communication interface with the notification endpoint and a data
+// interface with the bulk endpoints.
+const COMM_INTERFACE_DESC: Interface = Interface::new()
+    .bInterfaceNumber(0)
+    .bNumEndpoints(1)
+    .bInterfaceClass(cdc::CLASS_CDC)
+    .bInterfaceSubClass(cdc::SUBCLASS_ACM)
+    .bInterfaceProtocol(cdc::PROTOCOL_NONE)
+    .iInterface(5);
+
+const DATA_INTERFACE_DESC: Interface = Interface::new()
+    .bInterfaceNumber(1)
+    .bNumEndpoints(2)
+    .bInterfaceClass(cdc::CLASS_CDC_DATA)
+    .bInterfaceSubClass(0)
+    .bInterfaceProtocol(0);
+
+const KEYBOARD_INTERFACE_DESC: Interface = Interface::new()
+    .bInterfaceNumber(2)
+    .bNumEndpoints(1)
+    .bInterfaceClass(hid::CLASS_HID)
+    .bInterfaceSubClass(keyboard::SUBCLASS)
+    .bInterfaceProtocol(keyboard::PROTOCOL)
+    .iInterface(6);
+
+const RAW_HID_INTERFACE_DESC: Interface = Interface::new()
+    .bInterfaceNumber(3)
+    .bNumEndpoints(2)
+    .bInterfaceClass(hid::CLASS_HID)
+    .bInterfaceSubClass(hid::SUBCLASS_NONE)
+    .bInterfaceProtocol(hid::PROTOCOL_NONE)
+    .iInterface(7);
+
+const MSC_INTERFACE_DESC: Interface = Interface::new()
+    .bInterfaceNumber(4)
+    .bNumEndpoints(2)
+    .bInterfaceClass(msc::CLASS_MSC)
+    .bInterfaceSubClass(msc::SUBCLASS_SCSI)
+    .bInterfaceProtocol(msc::PROTOCOL_BOT)
+    .iInterface(8);
+
+// Control requests only, no endpoints.
+const DFU_RUNTIME_INTERFACE_DESC: Interface = Interface::new()
+    .bInterfaceNumber(5)
+    .bNumEndpoints(0)
+    .bInterfaceClass(dfu::CLASS_APPLICATION)
+    .bInterfaceSubClass(dfu::SUBCLASS_DFU)
+    .bInterfaceProtocol(dfu::PROTOCOL_RUNTIME)
+    .iInterface(9);
+
+const CDC_HEADER: [u8; 5] = cdc::header(0x0110);
+const CDC_CALL_MANAGEMENT: [u8; 5] = cdc::call_management(0x00, 1);
+const CDC_ACM: [u8; 4] = cdc::acm(0x02); // Line coding and control line state.
+const CDC_UNION: [u8; 5] = cdc::union(0, 1);
+const CDC_FUNCTIONAL: [&[u8]; 4] = [&CDC_HEADER, &CDC_CALL_MANAGEMENT, &CDC_ACM, &CDC_UNION];
+
+const EP83_DESC: Endpoint = Endpoint::new()
+    .bEndpointAddress(0x83)
+    .bmAttributes(0b000000_11) // Interrupt.
+    .wMaxPacketSize(8)
+    .bInterval(255);
 
 const EP01_DESC: Endpoint = Endpoint::new()
     .bEndpointAddress(0x01)
     .wMaxPacketSize(64)
     .bInterval(1);
 
+const EP82_DESC: Endpoint = Endpoint::new()
+    .bEndpointAddress(0x82)
+    .wMaxPacketSize(64)
+    .bInterval(1);
+
+const EP84_DESC: Endpoint = Endpoint::new()
+    .bEndpointAddress(0x84)
+    .bmAttributes(0b000000_11) // Interrupt.
+    .wMaxPacketSize(keyboard::REPORT_SIZE as u16)
+    .bInterval(10);
+
+const EP05_DESC: Endpoint = Endpoint::new()
+    .bEndpointAddress(0x05)
+    .bmAttributes(0b000000_11) // Interrupt.
+    .wMaxPacketSize(64)
+    .bInterval(1);
+
+const EP85_DESC: Endpoint = Endpoint::new()
+    .bEndpointAddress(0x85)
+    .bmAttributes(0b000000_11) // Interrupt.
+    .wMaxPacketSize(64)
+    .bInterval(1);
+
+const EP06_DESC: Endpoint = Endpoint::new()
+    .bEndpointAddress(0x06)
+    .wMaxPacketSize(64)
+    .bInterval(1);
+
+const EP86_DESC: Endpoint = Endpoint::new()
+    .bEndpointAddress(0x86)
+    .wMaxPacketSize(64)
+    .bInterval(1);
+
+// wTotalLength and bNumInterfaces are filled in from the tree below.
 const CONF_DESC: Configuration = Configuration::new()
-    .wTotalLength(
-        size_of::<Configuration>() as u16
-            + size_of::<Interface>() as u16
-            + size_of::<Endpoint>() as u16 * 1,
-    ) // add all descs up.
-    .bNumInterfaces(1)
     .bConfigurationValue(1)
     .iConfiguration(4)
     .bmAttributes(0b1_1_0_00000) // Self powered no remote wakeup.
     .bMaxPower(0xFA); // 500mA.
 
-const ints: [Interface; 1] = [INTERFACE_DESC];
-const eps: [Endpoint; 1] = [EP01_DESC];
+const comm_eps: [types::Endpoint; 1] = [types::Endpoint::new(&EP83_DESC)];
+const data_eps: [types::Endpoint; 2] = [
+    types::Endpoint::new(&EP01_DESC).double_buffered(),
+    types::Endpoint::new(&EP82_DESC),
+];
+const keyboard_eps: [types::Endpoint; 1] = [types::Endpoint::new(&EP84_DESC)];
+const KEYBOARD_CLASS: [&[u8]; 1] = [&keyboard::DESCRIPTOR];
+const raw_hid_eps: [types::Endpoint; 2] = [
+    types::Endpoint::new(&EP05_DESC),
+    types::Endpoint::new(&EP85_DESC),
+];
+const RAW_HID_CLASS: [&[u8]; 1] = [&raw_hid::DESCRIPTOR];
+const msc_eps: [types::Endpoint; 2] = [
+    types::Endpoint::new(&EP06_DESC),
+    types::Endpoint::new(&EP86_DESC),
+];
+// The device resets itself after DETACH, dfu-util needn't reset the bus.
+const DFU_RUNTIME_FUNCTIONAL: [u8; 9] = dfu::functional(
+    dfu::CAN_DNLOAD | dfu::CAN_UPLOAD | dfu::WILL_DETACH,
+    1000,
+    dfu::TRANSFER_SIZE as u16,
+);
+const DFU_RUNTIME_DESCRIPTORS: [&[u8]; 1] = [&DFU_RUNTIME_FUNCTIONAL];
+
+const ints: [types::Interface; 6] = [
+    types::Interface::new(&COMM_INTERFACE_DESC, &CDC_FUNCTIONAL, &comm_eps),
+    types::Interface::new(&DATA_INTERFACE_DESC, &[], &data_eps),
+    types::Interface::new(&KEYBOARD_INTERFACE_DESC, &KEYBOARD_CLASS, &keyboard_eps),
+    types::Interface::new(&RAW_HID_INTERFACE_DESC, &RAW_HID_CLASS, &raw_hid_eps),
+    types::Interface::new(&MSC_INTERFACE_DESC, &[], &msc_eps),
+    types::Interface::new(&DFU_RUNTIME_INTERFACE_DESC, &DFU_RUNTIME_DESCRIPTORS, &[]),
+];
+const confs: [types::Configuration; 1] = [types::Configuration::new(&CONF_DESC, &ints)];
+
+// Indices match the i* fields above.
+const STRINGS_EN_US: [&str; 9] = [
+    "bentwire",      // iManufacturer
+    "STM32F072 USB", // iProduct
+    "0001",          // iSerialNumber
+    "Default",       // iConfiguration
+    "Serial port",   // iInterface
+    "Keyboard",      // iInterface
+    "Raw HID",       // iInterface
+    "Mass storage",  // iInterface
+    "DFU",           // iInterface
+];
+const strs: [StringTable; 1] = [StringTable::new(0x0409, &STRINGS_EN_US)];
 
 const DESCS: usb::Descriptors = usb::Descriptors {
-    Device: DEV_DESC,
-    Configuration: CONF_DESC,
-    Interfaces: &ints,
-    Endpoints: &eps,
+    Device: types::Device::new(&DEV_DESC, &confs),
+    DeviceQualifier: None, // Full speed only, qualifier requests get a STALL.
+    Strings: &strs,
+    Bos: None,
+};
+
+// Refuse to build with descriptors that contradict themselves or the driver.
+const _: () = match usb::validate::validate(&DESCS) {
+    Ok(()) => (),
+    Err(_) => panic!("inconsistent USB descriptors"),
 };
 
+// Update mode, the UF2 drive and the DFU interface. A product id of its own so the host
+// doesn't take it for the composite device.
+const UPDATE_DEV_DESC: Device = DEV_DESC.idProduct(0xfffe);
+
+const UF2_INTERFACE_DESC: Interface = Interface::new()
+    .bInterfaceNumber(0)
+    .bNumEndpoints(2)
+    .bInterfaceClass(msc::CLASS_MSC)
+    .bInterfaceSubClass(msc::SUBCLASS_SCSI)
+    .bInterfaceProtocol(msc::PROTOCOL_BOT)
+    .iInterface(5);
+
+// Control requests only, no endpoints.
+const DFU_INTERFACE_DESC: Interface = Interface::new()
+    .bInterfaceNumber(1)
+    .bNumEndpoints(0)
+    .bInterfaceClass(dfu::CLASS_APPLICATION)
+    .bInterfaceSubClass(dfu::SUBCLASS_DFU)
+    .bInterfaceProtocol(dfu::PROTOCOL_DFU_MODE)
+    .iInterface(6);
+
+// Not manifestation tolerant, installing the update resets the device.
+const DFU_FUNCTIONAL: [u8; 9] = dfu::functional(
+    dfu::CAN_DNLOAD | dfu::CAN_UPLOAD,
+    1000,
+    dfu::TRANSFER_SIZE as u16,
+);
+const DFU_DESCRIPTORS: [&[u8]; 1] = [&DFU_FUNCTIONAL];
+
+const update_ints: [types::Interface; 2] = [
+    types::Interface::new(&UF2_INTERFACE_DESC, &[], &msc_eps),
+    types::Interface::new(&DFU_INTERFACE_DESC, &DFU_DESCRIPTORS, &[]),
+];
+const update_confs: [types::Configuration; 1] =
+    [types::Configuration::new(&CONF_DESC, &update_ints)];
+
+const UPDATE_STRINGS_EN_US: [&str; 6] = [
+    "bentwire",         // iManufacturer
+    "STM32F072 update", // iProduct
+    "0001",             // iSerialNumber
+    "Default",          // iConfiguration
+    "UF2 update",       // iInterface
+    "Internal flash",   // iInterface, the DFU alternate setting name dfu-util shows.
+];
+const update_strs: [StringTable; 1] = [StringTable::new(0x0409, &UPDATE_STRINGS_EN_US)];
+
+const UPDATE_DESCS: usb::Descriptors = usb::Descriptors {
+    Device: types::Device::new(&UPDATE_DEV_DESC, &update_confs),
+    DeviceQualifier: None,
+    Strings: &update_strs,
+    Bos: None,
+};
+
+const _: () = match usb::validate::validate(&UPDATE_DESCS) {
+    Ok(()) => (),
+    Err(_) => panic!("inconsistent USB descriptors"),
+};
+
+// The usb-device stack on usb::bus::UsbBus in place of Usb, a bare device with no
+// classes. Only run with --features usb-device-stack, but built either way so the UsbBus
+// impl keeps up with usb-device.
+fn usb_device_stack(
+    usb: USB,
+    pins: (gpioa::PA11<Alternate<AF0>>, gpioa::PA12<Alternate<AF0>>),
+) -> ! {
+    let bus = usb::bus::UsbBus::new(usb, pins);
+    let strings = StringDescriptors::default()
+        .manufacturer(STRINGS_EN_US[0])
+        .product(STRINGS_EN_US[1])
+        .serial_number(STRINGS_EN_US[2]);
+    let mut device = UsbDeviceBuilder::new(&bus, UsbVidPid(0xffff, 0xffff))
+        .strings(&[strings])
+        .unwrap()
+        .build();
+    log(format_args!("usb-device stack"));
+    loop {
+        device.poll(&mut []);
+    }
+}
+
 #[entry]
 fn main() -> ! {
-    hprintln!("main()").unwrap();
     if let (Some(p), Some(cp)) = (Peripherals::take(), c_m_Peripherals::take()) {
         let gpioa = p.GPIOA.split();
         let gpiob = p.GPIOB.split();
@@ -113,6 +426,10 @@ fn main() -> ! {
         // Configure PC13 as input (button)
         let _ = gpioc.pc13.into_pull_down_input();
 
+        // Held (low) at reset, or asked for with DFU, come up in update mode.
+        let button = unsafe { (*stm32::GPIOC::ptr()).idr.read().idr13().bit_is_clear() };
+        let update_mode = boot::update_requested() || button;
+
         // Configure PA5 as output (LED)
         let mut led = gpioa.pa5.into_push_pull_output();
 
@@ -128,9 +445,12 @@ fn main() -> ! {
             .hclk(48.mhz())
             .pclk(48.mhz())
             .freeze();
-        hprintln!("sysclk: {}", clocks.sysclk().0).unwrap();
-        hprintln!("hclk: {}", clocks.hclk().0).unwrap();
-        hprintln!("pclk: {}", clocks.pclk().0).unwrap();
+        log(format_args!(
+            "sysclk {} hclk {} pclk {}",
+            clocks.sysclk().0,
+            clocks.hclk().0,
+            clocks.pclk().0
+        ));
         // Initialise delay provider
         let mut delay = Delay::new(cp.SYST, clocks);
 
@@ -147,8 +467,25 @@ fn main() -> ! {
 
         let dm = gpioa.pa11.into_alternate_af0();
         let dp = gpioa.pa12.into_alternate_af0();
+        if cfg!(feature = "usb-device-stack") {
+            usb_device_stack(p.USB, (dm, dp));
+        }
 
-        let usb = usb::Usb::usb(p.USB, (dm, dp), DESCS);
+        let descriptors = if update_mode { UPDATE_DESCS } else { DESCS };
+        let usb = usb::Usb::usb(p.USB, (dm, dp), descriptors, unsafe { &mut EP0_BUF });
+        let serial = cdc::CdcAcm::new(0, EP01_DESC.address(), EP82_DESC.address());
+        let keyboard = keyboard::Keyboard::new(2, EP84_DESC.address());
+        let raw_hid = raw_hid::RawHid::new(3, EP85_DESC.address(), EP05_DESC.address());
+        let drive = fat::VirtualFat::new(
+            "STM32F072",
+            0x0001_F072,
+            &DRIVE_FILES,
+            DriveStatus { state: usb.state() },
+        );
+        let disk = scsi::Scsi::new(drive, "bentwire", "STM32F072 USB", "0001");
+        let msc = msc::MassStorage::new(4, EP86_DESC.address(), EP06_DESC.address(), disk);
+        let dfu_runtime = dfu::DfuRuntime::new(5);
+        let flash = InternalFlash::new(p.FLASH);
 
         // Configure I2C
         let scl = gpiob
@@ -179,6 +516,16 @@ fn main() -> ! {
             *DELAY.borrow(cs).borrow_mut() = Some(delay);
             *INT.borrow(cs).borrow_mut() = Some(exti);
             *USBDEV.borrow(cs).borrow_mut() = Some(usb);
+            if update_mode {
+                *UF2.borrow(cs).borrow_mut() = Some(update_drive(flash));
+                *DFU.borrow(cs).borrow_mut() = Some(dfu::Dfu::new(1, flash));
+            } else {
+                *SERIAL.borrow(cs).borrow_mut() = Some(serial);
+                *KEYBOARD.borrow(cs).borrow_mut() = Some(keyboard);
+                *RAW_HID.borrow(cs).borrow_mut() = Some(raw_hid);
+                *MSC.borrow(cs).borrow_mut() = Some(msc);
+                *DFU_RUNTIME.borrow(cs).borrow_mut() = Some(dfu_runtime);
+            }
         });
 
         // Enable EXTI IRQ, set prio 1 and clear any pending IRQs
@@ -188,7 +535,7 @@ fn main() -> ! {
         unsafe { nvic.set_priority(Interrupt::EXTI4_15, 0) };
         cortex_m::peripheral::NVIC::unpend(Interrupt::EXTI4_15);
 
-        hprintln!("init complete.").unwrap();
+        log(format_args!("Init complete"));
 
         disp.draw(
             Font6x8::render_str("this is a test")
@@ -198,8 +545,119 @@ fn main() -> ! {
 
         disp.flush().unwrap();
     }
+
+    // Echo whatever the terminal sends. Bytes that don't fit the TX ring wait in buf, and
+    // the RX ring filling up NAKs the host.
+    let mut buf = [0u8; 64];
+    let mut pending = 0;
+    let mut connected = false;
+    let mut message = [0u8; usb::framing::MAX_MESSAGE];
     loop {
-        // your code goes here
+        cortex_m::interrupt::free(|cs| {
+            if let (&mut Some(ref mut usb), &mut Some(ref mut serial)) = (
+                USBDEV.borrow(cs).borrow_mut().deref_mut(),
+                SERIAL.borrow(cs).borrow_mut().deref_mut(),
+            ) {
+                // Greet each time a terminal opens the port.
+                if serial.dtr() != connected {
+                    connected = serial.dtr();
+                    log(format_args!(
+                        "Terminal {}",
+                        if connected { "open" } else { "closed" }
+                    ));
+                    if connected {
+                        let coding = serial.line_coding();
+                        writeln!(
+                            serial.serial(usb),
+                            "\r\nSTM32F072 USB, {} baud",
+                            coding.dwDTERate
+                        )
+                        .ok();
+                    }
+                }
+
+                if pending == 0 {
+                    pending = serial.read(&mut buf);
+                }
+                let written = serial.write(&buf[..pending]);
+                buf.copy_within(written..pending, 0);
+                pending -= written;
+                serial.poll(usb);
+            }
+        });
+
+        // Starts a macro from the button, the USB interrupt carries on from there.
+        cortex_m::interrupt::free(|cs| {
+            if let (&mut Some(ref mut usb), &mut Some(ref mut keyboard)) = (
+                USBDEV.borrow(cs).borrow_mut().deref_mut(),
+                KEYBOARD.borrow(cs).borrow_mut().deref_mut(),
+            ) {
+                keyboard.poll(usb);
+            }
+        });
+
+        // Raw HID echo. A message is only taken once the last reply is on its way, until
+        // then the host is NAKed.
+        cortex_m::interrupt::free(|cs| {
+            if let (&mut Some(ref mut usb), &mut Some(ref mut raw_hid)) = (
+                USBDEV.borrow(cs).borrow_mut().deref_mut(),
+                RAW_HID.borrow(cs).borrow_mut().deref_mut(),
+            ) {
+                if !raw_hid.is_sending() {
+                    if let Some(len) = raw_hid.receive(&mut message) {
+                        raw_hid.send(&message[..len]);
+                        raw_hid.poll(usb);
+                    }
+                }
+            }
+        });
+
+        // A whole .uf2 file is in. Give the CSW for its last block time to go out, then
+        // copy the update over this image and reset in to it.
+        let done = cortex_m::interrupt::free(|cs| match UF2.borrow(cs).borrow_mut().deref_mut() {
+            Some(drive) => drive.scsi().device().context().is_done(),
+            None => false,
+        });
+        if done {
+            cortex_m::asm::delay(48_000_000 / 10);
+            cortex_m::interrupt::free(|cs| {
+                if let Some(drive) = UF2.borrow(cs).borrow_mut().deref_mut() {
+                    drive.scsi().device().context_mut().update().install();
+                }
+            });
+        }
+
+        // Likewise once a DFU download has been manifested. dfu-util asks for the status
+        // again a second later and with -R sends a DETACH and resets the bus, let all of
+        // that go by first.
+        let manifested = cortex_m::interrupt::free(|cs| match DFU.borrow(cs).borrow().as_ref() {
+            Some(dfu) => dfu.state() == dfu::State::ManifestWaitReset,
+            None => false,
+        });
+        if manifested {
+            cortex_m::asm::delay(2 * 48_000_000);
+            cortex_m::interrupt::free(|cs| {
+                if let Some(dfu) = DFU.borrow(cs).borrow_mut().deref_mut() {
+                    dfu.update().install();
+                }
+            });
+        }
+
+        // DETACH or the vendor request. The status stage goes out before the reset.
+        let detach = cortex_m::interrupt::free(|cs| {
+            DFU_RUNTIME
+                .borrow(cs)
+                .borrow()
+                .as_ref()
+                .and_then(|runtime| runtime.detach())
+        });
+        if let Some(target) = detach {
+            cortex_m::asm::delay(48_000_000 / 100);
+            boot::reboot(match target {
+                dfu::Target::Update => Boot::Update,
+                dfu::Target::SystemMemory => Boot::SystemMemory,
+            });
+        }
     }
 }
 
@@ -207,8 +665,58 @@ fn main() -> ! {
 fn USB() {
     //hprintln!("USB_ISR:").unwrap();
     cortex_m::interrupt::free(|cs| {
-        if let &mut Some(ref mut usb) = USBDEV.borrow(cs).borrow_mut().deref_mut() {
-            usb.interrupt();
+        if let (&mut Some(ref mut usb), &mut Some(ref mut update), &mut Some(ref mut dfu)) = (
+            USBDEV.borrow(cs).borrow_mut().deref_mut(),
+            UF2.borrow(cs).borrow_mut().deref_mut(),
+            DFU.borrow(cs).borrow_mut().deref_mut(),
+        ) {
+            usb.interrupt(&mut [&mut *update, &mut *dfu]);
+            update.poll(usb);
+            return;
+        }
+
+        if let (
+            &mut Some(ref mut usb),
+            &mut Some(ref mut serial),
+            &mut Some(ref mut keyboard),
+            &mut Some(ref mut raw_hid),
+            &mut Some(ref mut msc),
+            &mut Some(ref mut dfu_runtime),
+            &mut Some(ref mut led),
+        ) = (
+            USBDEV.borrow(cs).borrow_mut().deref_mut(),
+            SERIAL.borrow(cs).borrow_mut().deref_mut(),
+            KEYBOARD.borrow(cs).borrow_mut().deref_mut(),
+            RAW_HID.borrow(cs).borrow_mut().deref_mut(),
+            MSC.borrow(cs).borrow_mut().deref_mut(),
+            DFU_RUNTIME.borrow(cs).borrow_mut().deref_mut(),
+            LED.borrow(cs).borrow_mut().deref_mut(),
+        ) {
+            usb.interrupt(&mut [
+                &mut *serial,
+                &mut *keyboard,
+                &mut *raw_hid,
+                &mut *msc,
+                &mut *dfu_runtime,
+            ]);
+
+            let status = msc.scsi().device().context_mut();
+            if status.state != usb.state() {
+                status.state = usb.state();
+                log(format_args!("USB {:?}", status.state));
+            }
+
+            serial.poll(usb);
+            keyboard.poll(usb);
+            raw_hid.poll(usb);
+            msc.poll(usb);
+
+            // The LED is on while the host has Caps Lock or Num Lock on.
+            if keyboard.leds() & (keyboard::LED_CAPS_LOCK | keyboard::LED_NUM_LOCK) != 0 {
+                led.set_high();
+            } else {
+                led.set_low();
+            }
         }
     });
 }
@@ -216,29 +724,19 @@ fn USB() {
 #[interrupt]
 fn EXTI4_15() {
     // Enter critical section
-    hprintln!("BUTTON_PRESS").unwrap();
     cortex_m::interrupt::free(|cs| {
-        // Obtain all Mutex protected resources
-        if let (&mut Some(ref mut led), &mut Some(ref mut delay), &mut Some(ref mut exti)) = (
-            LED.borrow(cs).borrow_mut().deref_mut(),
-            DELAY.borrow(cs).borrow_mut().deref_mut(),
-            INT.borrow(cs).borrow_mut().deref_mut(),
-        ) {
-            //hprintln!("Borrow OK!").unwrap();
-            // Turn on LED
-            led.set_high();
-
-            //hprintln!("Led ON OK!").unwrap();
-            // Wait a second
-            delay.delay_ms(100_u16);
-
-            //hprintln!("Delay OK!").unwrap();
-            // Turn off LED
-            led.set_low();
-
-            //hprintln!("Led OFF OK!").unwrap();
-            // Clear interrupt
+        // Clear interrupt, whether or not there is a keyboard to type on. In update mode
+        // there isn't, and a pending PR13 would fire this again forever.
+        if let &mut Some(ref mut exti) = INT.borrow(cs).borrow_mut().deref_mut() {
             exti.pr.modify(|_, w| w.pif13().set_bit());
         }
+
+        // Ignored while the last one is still being typed, which also takes care of the
+        // button bouncing. The USB interrupt and main loop send the reports.
+        if let &mut Some(ref mut keyboard) = KEYBOARD.borrow(cs).borrow_mut().deref_mut() {
+            if keyboard.type_str(MACRO) {
+                log(format_args!("Button, typing the macro"));
+            }
+        }
     });
 }
@@ -1,4 +1,4 @@
-use crate::usb::pma::PMA;
+use crate::usb::pma::{PmaAllocator, PmaError, PMA};
 
 use core::cmp::min;
 
@@ -6,46 +6,76 @@ use hal::gpio::gpioa::{PA11, PA12};
 use hal::gpio::{Alternate, AF0};
 use hal::prelude::*;
 
-use cortex_m_semihosting::{debug, hprintln};
-
 pub use hal::stm32;
 pub use hal::stm32::{CRS, RCC, USB};
 
-//use pma::PMA;
+pub mod block;
+pub mod bot;
+pub mod bus;
+pub mod cdc;
+pub mod class;
 pub mod constants;
+pub mod control;
 pub mod descriptors;
+pub mod dfu;
+pub mod endpoint;
+pub mod fat;
+pub mod flash;
+pub mod framing;
+pub mod hid;
+pub mod internal_flash;
+pub mod keyboard;
+pub mod msc;
 mod pma;
+pub mod raw_hid;
+pub mod ring;
+pub mod scsi;
+pub mod serial;
 pub mod types;
+pub mod uf2;
 mod usb_ext;
+pub mod validate;
 
-use self::constants::{Destination, Direction, Type, UsbRequest, UsbRequestType};
+use self::class::UsbClass;
+use self::constants::{
+    Destination, Direction, EndpointType, Type, UsbDescriptorType, UsbRequest, UsbRequestType,
+};
+use self::control::{ControlPipe, ControlResponse, ControlState, SetupPacket};
 use self::descriptors::*;
-use self::usb_ext::UsbEpExt;
+use self::endpoint::{EndpointError, EndpointState, Endpoints, Events, IsoStatus};
+pub use self::types::Descriptors;
+use self::usb_ext::{EpStatus, EpType, UsbEpExt};
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum UsbState {
     BootReset,
     Reset,
     Initialized,
     Addressed(u8),
-}
-
-#[derive(Debug)]
-pub struct Descriptors<'a> {
-    pub Device: Device,
-    pub Configuration: Configuration,
-    pub Interfaces: &'a [Interface],
-    pub Endpoints: &'a [Endpoint],
+    Configured(u8),
 }
 
 const MAX_PACKET_SIZE: u32 = 64;
 
+// EP1..EP7, indexed by endpoint number. EP0 is the control pipe.
+const NUM_ENDPOINTS: usize = 8;
+
+// USB 2.0 table 9-6 feature selector, for SET_FEATURE/CLEAR_FEATURE on an endpoint.
+const ENDPOINT_HALT: u16 = 0;
+
 pub struct Usb<USB, PINS> {
     usb: USB,
     pins: PINS,
     state: UsbState,
     pma: &'static mut PMA,
+    pma_alloc: PmaAllocator,
     descriptors: Descriptors<'static>,
+    control: ControlPipe,
+    ep_in: [Option<EndpointState>; NUM_ENDPOINTS],
+    ep_out: [Option<EndpointState>; NUM_ENDPOINTS],
+    events: Events,
+    // ESOFs seen, frames the host didn't send a SOF for.
+    missed_sofs: u32,
 }
 
 pub trait Pins<Usb> {}
@@ -58,35 +88,58 @@ pub enum Error {
     INITFAIL,
 }
 
+// Clocks on and the transceiver out of power down, shared with bus::UsbBus.
+fn power_up(usb: &USB) {
+    // NOTE(unsafe) This executes only during initialisation
+    let rcc = unsafe { &(*RCC::ptr()) };
+    let crs = unsafe { &(*CRS::ptr()) };
+
+    // Enable USB clock. and Clock recovery
+    rcc.apb1enr
+        .modify(|_, w| w.usben().set_bit().crsen().set_bit());
+    let _ = rcc.apb1enr.read(); // Delay
+
+    // Initialize clock recovery
+    // Set autotrim enabled.
+    crs.cr.modify(|_, w| w.autotrimen().set_bit());
+    // Enable CR
+    crs.cr.modify(|_, w| w.cen().set_bit());
+
+    // ENable USB
+    usb.cntr.modify(|_, w| w.pdwn().clear_bit());
+
+    // tSTARTUP, the transceiver needs up to 1us before FRES is released.
+    cortex_m::asm::delay(48);
+}
+
+// Hosts ask for plenty this device doesn't do, the request error (USB 2.0 8.5.3.4) is
+// how it says so. This runs in the USB interrupt, with no debugger to print to.
+fn unhandled(setup: &SetupPacket) -> ControlResponse {
+    crate::log(format_args!(
+        "USB unhandled request {:02x} {:02x} {:04x} {:04x}",
+        setup.bmRequestType, setup.bRequest, setup.wValue, setup.wIndex
+    ));
+    ControlResponse::Stall
+}
+
 impl<PINS> Usb<USB, PINS> {
-    pub fn usb(usb: USB, pins: PINS, descriptors: Descriptors<'static>) -> Self
+    pub fn usb(
+        usb: USB,
+        pins: PINS,
+        descriptors: Descriptors<'static>,
+        control_buffer: &'static mut [u8],
+    ) -> Self
     where
         PINS: Pins<USB>,
     {
         // NOTE(unsafe) This executes only during initialisation
-        let rcc = unsafe { &(*RCC::ptr()) };
         let pma = unsafe { &mut *PMA.get() };
-        let crs = unsafe { &(*CRS::ptr()) };
-
-        // Enable USB clock. and Clock recovery
-        rcc.apb1enr
-            .modify(|_, w| w.usben().set_bit().crsen().set_bit());
-        let _ = rcc.apb1enr.read(); // Delay
 
-        // Initialize clock recovery
-        // Set autotrim enabled.
-        crs.cr.modify(|_, w| w.autotrimen().set_bit());
-        // Enable CR
-        crs.cr.modify(|_, w| w.cen().set_bit());
-
-        // ENable USB
-        usb.cntr.modify(|_, w| w.pdwn().clear_bit());
+        power_up(&usb);
 
         // Clear PMA
         pma.zero();
 
-        hprintln!("DELAY").unwrap();
-
         // Set BTable address to default.
         usb.btable.reset();
 
@@ -118,24 +171,32 @@ impl<PINS> Usb<USB, PINS> {
         usb.bcdr.modify(|_, w| w.dppu().set_bit());
 
         let state = UsbState::BootReset;
+        let control = ControlPipe::new(
+            control_buffer,
+            descriptors.Device.descriptor().max_packet_size0() as usize,
+        );
 
         Usb {
             usb,
             pins,
             state,
             pma,
+            pma_alloc: PmaAllocator::new(),
             descriptors,
+            control,
+            ep_in: [None; NUM_ENDPOINTS],
+            ep_out: [None; NUM_ENDPOINTS],
+            events: Events::default(),
+            missed_sofs: 0,
         }
     }
 
-    fn reset(&mut self) {
-        // Init EP0
-        self.pma.pma_area.set_u16(0, 0x0040); // ADDR0_TX, buffer at offset 0x40 in PMA.
-        self.pma.pma_area.set_u16(2, 0); // COUNT0_TX, 0 bytes in buffer
-        self.pma.pma_area.set_u16(4, 0x0020); // ADDR0_RX, buffer at offset 0x20 in PMA.
-        self.pma
-            .pma_area
-            .set_u16(6, (0x8000 | ((MAX_PACKET_SIZE / 32) - 1) << 10) as u16); // COUNT0_RX, Set buffer count.
+    fn reset(&mut self, classes: &mut [&mut dyn UsbClass]) {
+        // Bus reset drops every endpoint, start handing out PMA from scratch.
+        self.deconfigure();
+        for class in classes.iter_mut() {
+            class.reset();
+        }
 
         self.usb.ep0r.write(|w| unsafe {
             w.ep_type()
@@ -148,143 +209,834 @@ impl<PINS> Usb<USB, PINS> {
 
         self.usb.daddr.write(|w| w.ef().set_bit());
 
+        self.control.reset();
+        self.missed_sofs = 0;
         self.state = UsbState::Reset;
+    }
 
-        //hprintln!("USB RESET COMPLETE").unwrap();
+    fn ep0_alloc(&mut self) -> Result<(), PmaError> {
+        let tx = self.pma_alloc.alloc_tx(MAX_PACKET_SIZE as usize)?;
+        let (rx, rx_size) = self.pma_alloc.alloc_rx(MAX_PACKET_SIZE as usize)?;
+
+        let bd = self.pma.descriptor(0);
+        bd.set_addr_tx(tx);
+        bd.set_count_tx(0);
+        bd.set_addr_rx(rx);
+        bd.set_rx_size(rx_size);
+        Ok(())
     }
 
-    fn do_work(&mut self) {
-        if self.usb.istr.read().dir().bit_is_set() {
-            self.rx()
-        } else {
-            self.tx()
+    fn ep0_read_setup(&mut self) -> SetupPacket {
+        let rx = self.pma.descriptor(0).addr_rx();
+        SetupPacket::from([
+            self.pma.pma_area.get_u16(rx), // First u16 in RX buffer
+            self.pma.pma_area.get_u16(rx + 2), // Second u16 in RX buffer
+            self.pma.pma_area.get_u16(rx + 4), // Third...
+            self.pma.pma_area.get_u16(rx + 6), // Fourth...
+        ])
+    }
+
+    fn ep0_setup(&mut self, classes: &mut [&mut dyn UsbClass]) {
+        let setup = self.ep0_read_setup();
+        self.ep(0).clear_ctr_rx();
+        self.control.setup(setup);
+
+        // Host to device with data, the request is handled once all of it has arrived.
+        if let Some(Direction::OUT) = setup.direction() {
+            if setup.wLength > 0 {
+                if self.control.data_out() {
+                    self.ep0_expect_out();
+                } else {
+                    self.ep0_stall();
+                }
+                return;
+            }
+        }
+
+        match self.request(&setup, classes) {
+            // IN request with wLength of zero, there is no data stage to run.
+            ControlResponse::Data(_) if setup.wLength == 0 => self.ep0_status_in(),
+            ControlResponse::Data(len) => {
+                self.control.data_in(len);
+                self.ep0_write_next();
+            }
+            ControlResponse::Accept => self.ep0_status_in(),
+            ControlResponse::Stall => self.ep0_stall(),
         }
     }
 
-    fn parse_ctrl_request(
+    // For OUT requests the data stage is in self.control.data() by the time this runs.
+    // Anything but a standard device request is offered to the classes first.
+    fn request(
         &mut self,
-    ) -> (
-        (Option<Direction>, Option<Type>, Option<Destination>),
-        UsbRequest,
-        u16,
-        u16,
-        u16,
-    ) {
-        // Hard coded to ep0, fix this later
-        let request16 = self.pma.pma_area.get_u16(0x20); // First u16 in RX buffer
-        let value = self.pma.pma_area.get_u16(0x22); // Second u16 in RX buffer
-        let index = self.pma.pma_area.get_u16(0x24); // Third...
-        let length = self.pma.pma_area.get_u16(0x26); // Fourth...
-
-        //hprintln!("Decode: {:x}", request16).unwrap();
-        // set COUNT0_RX to max acceptable size. fix hardcoded endpoint later
-        self.pma
-            .pma_area
-            .set_u16(6, (0x8000 | ((MAX_PACKET_SIZE / 32) - 1) << 10) as u16);
-
-        let request_type = (request16 & 0xff) as u8;
-        //let request_type = UsbRequestType::from(request_type);
-        let request = UsbRequest::from(((request16 & 0xff00) >> 8) as u8);
-
-        (
-            (
-                Direction::from_bits(request_type),
-                Type::from_bits(request_type),
-                Destination::from_bits(request_type),
-            ),
-            request,
-            value,
-            index,
-            length,
-        )
-    }
-
-    fn rx(&mut self) {
-        //hprintln!("v: {:x}, i: {:x}, l: {:x}", value, index, length).unwrap();
-        let (request_type, request, value, index, length) = self.parse_ctrl_request();
-
-        match (request_type, request) {
+        setup: &SetupPacket,
+        classes: &mut [&mut dyn UsbClass],
+    ) -> ControlResponse {
+        let device = matches!(
+            (setup.request_type(), setup.destination()),
+            (Some(Type::Standard), Some(Destination::Device))
+        );
+        if !device {
+            let len = match setup.direction() {
+                Some(Direction::OUT) => self.control.offset(),
+                _ => self.control.buffer().len(),
+            };
+            let data = &mut self.control.buffer()[..len];
+            for class in classes.iter_mut() {
+                if let Some(response) = class.control(setup, data) {
+                    return response;
+                }
+            }
+        }
+
+        match setup.request_type() {
+            Some(Type::Standard) => self.standard_request(setup),
+            _ => unhandled(setup),
+        }
+    }
+
+    fn standard_request(&mut self, setup: &SetupPacket) -> ControlResponse {
+        match (setup.direction(), setup.destination(), setup.request()) {
+            (Some(Direction::IN), Some(Destination::Device), Some(UsbRequest::GetStatus)) => {
+                let buf = self.control.buffer();
+                buf[0] = 0x00; // No status bits reported yet.
+                buf[1] = 0x00;
+                ControlResponse::Data(2)
+            }
+
+            // Interfaces have no status bits (USB 2.0 9.4.5).
+            (Some(Direction::IN), Some(Destination::Interface), Some(UsbRequest::GetStatus))
+                if self.has_interface(setup.wIndex as u8) =>
+            {
+                let buf = self.control.buffer();
+                buf[0] = 0x00;
+                buf[1] = 0x00;
+                ControlResponse::Data(2)
+            }
+
+            // configure() only opens alternate setting 0, and that is the only one taken.
+            (Some(Direction::IN), Some(Destination::Interface), Some(UsbRequest::GetInterface))
+                if self.has_interface(setup.wIndex as u8) =>
+            {
+                self.control.buffer()[0] = 0;
+                ControlResponse::Data(1)
+            }
+
             (
-                (Some(Direction::OUT), Some(Type::Standard), Some(Destination::Device)),
-                UsbRequest::GetStatus,
-            ) => {
-                self.usb.ep0r.toggle_tx_stall();
-                //hprintln!("GET STATUS: {:x}", self.usb.ep0r.read().bits() as u16).unwrap();
+                Some(Direction::OUT),
+                Some(Destination::Interface),
+                Some(UsbRequest::SetInterface),
+            ) if setup.wValue == 0 && self.has_interface(setup.wIndex as u8) => {
+                ControlResponse::Accept
+            }
+
+            // Bit 0 is the halt.
+            (Some(Direction::IN), Some(Destination::Endpoint), Some(UsbRequest::GetStatus)) => {
+                match self.is_stalled(setup.wIndex as u8) {
+                    Ok(halted) => {
+                        let buf = self.control.buffer();
+                        buf[0] = halted as u8;
+                        buf[1] = 0x00;
+                        ControlResponse::Data(2)
+                    }
+                    Err(_) => ControlResponse::Stall,
+                }
             }
 
             (
-                (Some(Direction::OUT), Some(Type::Standard), Some(Destination::Device)),
-                UsbRequest::SetAddress,
-            ) => {
-                //hprintln!("Set Address: {:x}", value as u16).unwrap();
-//                self.usb
-//                    .daddr
-//                    .modify(|_, w| unsafe { w.add().bits(value as u8).ef().set_bit() });
-//
-                self.state = UsbState::Addressed(value as u8);
-                self.usb.ep0r.toggle_0();
+                Some(Direction::OUT),
+                Some(Destination::Endpoint),
+                Some(request @ UsbRequest::SetFeature),
+            )
+            | (
+                Some(Direction::OUT),
+                Some(Destination::Endpoint),
+                Some(request @ UsbRequest::ClearFeature),
+            ) if setup.wValue == ENDPOINT_HALT => {
+                let stalled = matches!(request, UsbRequest::SetFeature);
+                match self.set_stalled(setup.wIndex as u8, stalled) {
+                    Ok(()) => ControlResponse::Accept,
+                    Err(_) => ControlResponse::Stall,
+                }
+            }
+
+            (Some(Direction::OUT), Some(Destination::Device), Some(UsbRequest::SetAddress)) => {
+                // The new address only takes effect once the status stage is done, see ep0_in.
+                ControlResponse::Accept
+            }
+
+            (Some(Direction::IN), Some(Destination::Device), Some(UsbRequest::GetDescriptor)) => {
+                self.get_descriptor(setup)
             }
 
             (
-                (Some(Direction::IN), Some(Type::Standard), Some(Destination::Device)),
-                UsbRequest::GetDescriptor,
+                Some(Direction::IN),
+                Some(Destination::Device),
+                Some(UsbRequest::GetConfiguration),
             ) => {
-                let desc = unsafe{ as_u8_arry(&self.descriptors.Device) };
-                self.pma.pma_area.write_buffer_u8(0x40, desc);
-                self.pma
-                    .pma_area
-                    .set_u16(2, min(length, desc.len() as u16));
-                self.usb.ep0r.toggle_out();
-               
-                //hprintln!("v: {:x}, i: {:x}, l: {:x}", value, index, length).unwrap();
-
-//                hprintln!(
-//                    "rt: {:?}, r: {:?}, v: {:x}, i: {:x}, l: {:x}",
-//                    request_type,
-//                    request,
-//                    value,
-//                    index,
-//                    length
-//                )
-//                .unwrap();
+                let value = match self.state {
+                    UsbState::Configured(value) => value,
+                    _ => 0,
+                };
+                self.control.buffer()[0] = value;
+                ControlResponse::Data(1)
             }
 
+            (
+                Some(Direction::OUT),
+                Some(Destination::Device),
+                Some(UsbRequest::SetConfiguration),
+            ) => match setup.wValue as u8 {
+                // Back to the Address state (USB 2.0 9.4.7).
+                0 => {
+                    self.deconfigure();
+                    self.state = UsbState::Addressed(self.address());
+                    ControlResponse::Accept
+                }
+                value => match self.configure(value) {
+                    Ok(true) => {
+                        self.state = UsbState::Configured(value);
+                        ControlResponse::Accept
+                    }
+                    Ok(false) => ControlResponse::Stall,
+                    // validate() rules this out for the descriptors main.rs builds with.
+                    Err(e) => {
+                        crate::log(format_args!("USB SET_CONFIGURATION {}: {:?}", value, e));
+                        self.deconfigure();
+                        self.state = UsbState::Addressed(self.address());
+                        ControlResponse::Stall
+                    }
+                },
+            },
+
             // Fall though
-            (_, _) => {
-                hprintln!("RequestType: {:?}, Request: {:?}", request_type, request).unwrap();
-            }
+            (_, _, _) => unhandled(setup),
         }
     }
 
-    fn tx(&mut self) {
-        //hprintln!("TX").unwrap();
-        match self.state {
-            UsbState::Addressed(address) => {
-                self.usb
-                    .daddr
-                    .modify(|_, w| unsafe { w.add().bits(address).ef().set_bit() });
+    // Interface requests are only for the Configured state (USB 2.0 9.4), and for an
+    // interface of that configuration.
+    fn has_interface(&self, interface: u8) -> bool {
+        let configuration = match self.state {
+            UsbState::Configured(value) => self.descriptors.Device.configuration(value),
+            _ => None,
+        };
+        configuration.map_or(false, |configuration| {
+            configuration
+                .interfaces()
+                .iter()
+                .any(|i| i.descriptor().interface_number() == interface)
+        })
+    }
 
-                
+    // DADDR.ADD, set once SET_ADDRESS has completed.
+    fn address(&self) -> u8 {
+        self.usb.daddr.read().add().bits()
+    }
+
+    // wValue is descriptor type in the high byte, index in the low byte (USB 2.0 9.4.3).
+    fn get_descriptor(&mut self, setup: &SetupPacket) -> ControlResponse {
+        let desc_type = UsbDescriptorType::from_bits((setup.wValue >> 8) as u8);
+        let index = (setup.wValue & 0x00ff) as usize;
+        let descriptors = &self.descriptors;
+        let mut writer = DescriptorWriter::new(self.control.buffer());
+
+        match (desc_type, index) {
+            (Some(UsbDescriptorType::Device), 0) => descriptors.Device.write(&mut writer),
+
+            (Some(UsbDescriptorType::Configuration), index) => {
+                match descriptors.Device.configurations().get(index) {
+                    Some(configuration) => configuration.write(&mut writer),
+                    None => return ControlResponse::Stall,
+                }
+            }
+
+            (Some(UsbDescriptorType::StringDesc), 0) if !descriptors.Strings.is_empty() => {
+                StringTable::write_langids(descriptors.Strings, &mut writer);
+            }
+
+            // wIndex carries the LANGID for string requests.
+            (Some(UsbDescriptorType::StringDesc), index) => {
+                let table = descriptors
+                    .Strings
+                    .iter()
+                    .find(|t| t.langid() == setup.wIndex)
+                    .or_else(|| descriptors.Strings.first());
+
+                match table {
+                    Some(table) if table.write(index, &mut writer) => {}
+                    _ => return ControlResponse::Stall,
+                }
+            }
+
+            (Some(UsbDescriptorType::DeviceQualifier), 0) => match descriptors.DeviceQualifier {
+                Some(ref qualifier) => writer.write(&qualifier.to_bytes()),
+                None => return ControlResponse::Stall,
             },
 
-            _ => {
-                self.pma.pma_area.set_u16(6, 0); // Set COUNT0_RX to 0.
+            // Only exists for devices that can run at another speed, i.e. have a qualifier.
+            (Some(UsbDescriptorType::OtherSpeedConfiguration), index)
+                if descriptors.DeviceQualifier.is_some() =>
+            {
+                match descriptors.Device.configurations().get(index) {
+                    Some(configuration) => configuration.write_other_speed(&mut writer),
+                    None => return ControlResponse::Stall,
+                }
             }
+
+            (Some(UsbDescriptorType::Bos), 0) => match descriptors.Bos {
+                Some(bos) => writer.write(bos),
+                None => return ControlResponse::Stall,
+            },
+
+            (_, _) => return ControlResponse::Stall,
         }
-        self.usb.ep0r.toggle_tx_out();
+
+        ControlResponse::Data(writer.len())
     }
 
-    pub fn interrupt(&mut self) {
-        let istr = self.usb.istr.read();
-        let istr_val: u32 = istr.bits();
+    // Load the next packet of the IN data stage into the EP0 TX buffer, once the last
+    // one has gone out wait for the host's status OUT instead.
+    fn ep0_write_next(&mut self) {
+        match self.control.next_in() {
+            Some(chunk) => {
+                let bd = self.pma.descriptor(0);
+                self.pma.pma_area.write_buffer_u8(bd.addr_tx(), chunk);
+                bd.set_count_tx(chunk.len());
+                // TX valid, and let the host cut the transfer short with an early status OUT.
+                let ep0 = self.ep(0);
+                ep0.set_kind(true); // STATUS_OUT
+                ep0.set_stat(EpStatus::Valid, EpStatus::Valid);
+            }
+            // RX is still valid with STATUS_OUT set from the last packet.
+            None => self.control.status_out(),
+        }
+    }
+
+    // Zero length IN packet to acknowledge the request.
+    fn ep0_status_in(&mut self) {
+        self.control.status_in();
+        self.pma.descriptor(0).set_count_tx(0);
+        let ep0 = self.ep(0);
+        ep0.set_kind(false);
+        ep0.set_stat(EpStatus::Valid, EpStatus::Valid);
+    }
+
+    // OUT data or status stage next, nothing to send.
+    fn ep0_expect_out(&self) {
+        let ep0 = self.ep(0);
+        ep0.set_kind(false);
+        ep0.set_stat(EpStatus::Nak, EpStatus::Valid);
+    }
+
+    fn ep0_stall(&mut self) {
+        self.control.stall();
+        self.ep(0).set_stat(EpStatus::Stall, EpStatus::Stall);
+    }
+
+    // CTR_TX on EP0, the host has taken the packet we loaded.
+    fn ep0_in(&mut self) {
+        self.ep(0).clear_ctr_tx();
+
+        match self.control.state() {
+            ControlState::DataIn => self.ep0_write_next(),
+
+            ControlState::StatusIn => {
+                if let Some(UsbRequest::SetAddress) = self.control.request().request() {
+                    let address = (self.control.request().wValue & 0x7f) as u8;
+                    self.usb
+                        .daddr
+                        .modify(|_, w| unsafe { w.add().bits(address).ef().set_bit() });
+                    self.state = UsbState::Addressed(address);
+                }
+                self.control.reset();
+            }
+
+            _ => {}
+        }
+    }
+
+    // CTR_RX on EP0 without SETUP, either OUT data or the status stage of an IN transfer.
+    fn ep0_out(&mut self, classes: &mut [&mut dyn UsbClass]) {
+        let count = self.pma.descriptor(0).count_rx();
+        self.ep(0).clear_ctr_rx();
+
+        match self.control.state() {
+            ControlState::DataOut => {
+                let chunk = self.control.next_out(count);
+                let rx = self.pma.descriptor(0).addr_rx();
+                self.pma.pma_area.read_buffer_u8(rx, chunk);
+
+                // All of wLength is in, or the host ended the stage with a short packet.
+                if self.control.out_complete() || count < self.control.max_packet() {
+                    let setup = *self.control.request();
+                    match self.request(&setup, classes) {
+                        ControlResponse::Stall => self.ep0_stall(),
+                        _ => self.ep0_status_in(),
+                    }
+                } else {
+                    self.ep0_expect_out();
+                }
+            }
+
+            ControlState::DataIn | ControlState::StatusOut => {
+                self.control.reset();
+                self.ep0_expect_out();
+            }
+
+            _ => self.ep0_expect_out(),
+        }
+    }
+
+    // Open every endpoint of the first alternate setting of each interface. Ok(false) if
+    // there is no such configuration.
+    fn configure(&mut self, value: u8) -> Result<bool, PmaError> {
+        let configuration = match self.descriptors.Device.configuration(value) {
+            Some(configuration) => configuration,
+            None => return Ok(false),
+        };
+
+        self.deconfigure();
+        for interface in configuration.interfaces() {
+            if interface.descriptor().alternate_setting() != 0 {
+                continue;
+            }
+            for endpoint in interface.endpoints() {
+                self.open_endpoint(endpoint)?;
+            }
+        }
+        Ok(true)
+    }
+
+    // Disable EP1..EP7 and give their PMA back, EP0 keeps the buffers it had.
+    fn deconfigure(&mut self) {
+        for number in 1..NUM_ENDPOINTS as u8 {
+            let ep = self.ep(number);
+            ep.set_stat(EpStatus::Disabled, EpStatus::Disabled);
+            ep.set_kind(false);
+        }
+        self.ep_in = [None; NUM_ENDPOINTS];
+        self.ep_out = [None; NUM_ENDPOINTS];
+        self.events = Events::default();
+
+        // SOF/ESOF are only needed to keep track of isochronous frames.
+        self.usb
+            .cntr
+            .modify(|_, w| w.sofm().clear_bit().esofm().clear_bit());
+
+        // EP0 is always allocated first, so it ends up where it already is. Can't run
+        // out of PMA this early.
+        self.pma_alloc.reset();
+        self.ep0_alloc().unwrap();
+    }
+
+    fn open_endpoint(&mut self, endpoint: &types::Endpoint) -> Result<(), PmaError> {
+        let desc = endpoint.descriptor();
+        let number = desc.address() & 0x0f;
+        let n = number as usize;
+        let size = desc.max_packet_size() as usize;
+        let ep_type = desc.transfer_type();
+        let double_buffered = endpoint.is_double_buffered() && ep_type == EndpointType::Bulk;
+        let isochronous = ep_type == EndpointType::Isochronous;
+
+        let bd = self.pma.descriptor(n);
+        let ep = usb_ext::endpoint(&self.usb, number);
+        ep.set_address(number);
+        ep.set_type(EpType::from(ep_type));
+        ep.set_kind(double_buffered); // DBL_BUF
+
+        let state = EndpointState::new(ep_type, size, double_buffered);
 
-        //hprintln!("ISTR: {:x}", istr_val).unwrap();
+        if isochronous {
+            self.usb
+                .cntr
+                .modify(|_, w| w.sofm().set_bit().esofm().set_bit());
+        }
+
+        if desc.address() & 0x80 != 0 {
+            if isochronous {
+                // Both buffers start out empty, a frame with nothing queued sends a zero
+                // length packet.
+                for buf in 0..2 {
+                    bd.set_addr_buf(buf, self.pma_alloc.alloc_tx(size)?);
+                    bd.set_count_buf(buf, 0);
+                }
+                ep.reset_dtog_tx();
+                ep.set_stat(EpStatus::Valid, EpStatus::Disabled);
+            } else if double_buffered {
+                for buf in 0..2 {
+                    bd.set_addr_buf(buf, self.pma_alloc.alloc_tx(size)?);
+                    bd.set_count_buf(buf, 0);
+                }
+                // DTOG_TX == SW_BUF, the hardware NAKs until the first write.
+                ep.reset_dtog_tx();
+                ep.reset_dtog_rx();
+                ep.set_stat(EpStatus::Valid, EpStatus::Disabled);
+            } else {
+                bd.set_addr_tx(self.pma_alloc.alloc_tx(size)?);
+                bd.set_count_tx(0);
+                ep.reset_dtog_tx();
+                ep.set_stat_tx(EpStatus::Nak);
+            }
+            self.ep_in[n] = Some(state);
+        } else {
+            if isochronous {
+                for buf in 0..2 {
+                    let (addr, bits) = self.pma_alloc.alloc_rx(size)?;
+                    bd.set_addr_buf(buf, addr);
+                    bd.set_count_buf(buf, bits);
+                }
+                ep.reset_dtog_rx();
+                ep.set_stat(EpStatus::Disabled, EpStatus::Valid);
+            } else if double_buffered {
+                for buf in 0..2 {
+                    let (addr, bits) = self.pma_alloc.alloc_rx(size)?;
+                    bd.set_addr_buf(buf, addr);
+                    bd.set_count_buf(buf, bits);
+                }
+                // The hardware receives in to buffer 0, SW_BUF points at the empty buffer 1.
+                ep.reset_dtog_rx();
+                ep.reset_dtog_tx();
+                ep.toggle_dtog_tx();
+                ep.set_stat(EpStatus::Disabled, EpStatus::Valid);
+            } else {
+                let (addr, bits) = self.pma_alloc.alloc_rx(size)?;
+                bd.set_addr_rx(addr);
+                bd.set_rx_size(bits);
+                ep.reset_dtog_rx();
+                ep.set_stat_rx(EpStatus::Valid);
+            }
+            self.ep_out[n] = Some(state);
+        }
+        Ok(())
+    }
+
+    // Queue one packet on an IN endpoint, at most wMaxPacketSize bytes. Isochronous
+    // endpoints take one packet per frame, it goes out in the frame after the current one.
+    // While the endpoint is halted it blocks, until the host clears the halt.
+    pub fn write(&mut self, address: u8, data: &[u8]) -> Result<usize, EndpointError> {
+        let n = (address & 0x0f) as usize;
+        let state = match self.ep_in.get_mut(n) {
+            Some(Some(state)) => state,
+            _ => return Err(EndpointError::InvalidEndpoint),
+        };
+        if data.len() > state.max_packet() {
+            return Err(EndpointError::BufferOverflow);
+        }
+
+        let bd = self.pma.descriptor(n);
+        let ep = usb_ext::endpoint(&self.usb, n as u8);
+
+        // A halt stays until the host clears it, only set_stalled takes it off.
+        if !state.is_isochronous() && ep.stat_tx() == EpStatus::Stall {
+            return Err(EndpointError::WouldBlock);
+        }
+
+        if state.is_isochronous() {
+            // With a CTR pending DTOG_TX has already moved on, and ctr_ep would empty
+            // the buffer this is about to fill.
+            if state.is_ready() || ep.ctr_tx() {
+                return Err(EndpointError::WouldBlock);
+            }
+
+            // The hardware sends from the buffer DTOG_TX points at, queue in the other.
+            let buf = !ep.dtog_tx() as usize;
+            self.pma.pma_area.write_buffer_u8(bd.addr_buf(buf), data);
+            bd.set_count_buf(buf, data.len() as u16);
+            state.set_ready(true);
+        } else if state.is_double_buffered() {
+            if state.is_ready() {
+                return Err(EndpointError::WouldBlock);
+            }
+
+            // SW_BUF is DTOG_RX on a TX endpoint.
+            let buf = ep.dtog_rx() as usize;
+            self.pma.pma_area.write_buffer_u8(bd.addr_buf(buf), data);
+            bd.set_count_buf(buf, data.len() as u16);
+
+            if ep.dtog_tx() == ep.dtog_rx() {
+                // Hardware is idle, hand the buffer straight over.
+                ep.toggle_dtog_rx();
+            } else {
+                // Hardware is still sending the other one, ctr_ep hands this over.
+                state.set_ready(true);
+            }
+        } else {
+            if ep.stat_tx() == EpStatus::Valid {
+                return Err(EndpointError::WouldBlock);
+            }
+
+            self.pma.pma_area.write_buffer_u8(bd.addr_tx(), data);
+            bd.set_count_tx(data.len());
+            ep.set_stat_tx(EpStatus::Valid);
+        }
+
+        Ok(data.len())
+    }
+
+    // Take the next packet from an OUT endpoint. If buf is too small the packet stays put.
+    // Isochronous endpoints return the bytes that arrived in the last frame.
+    pub fn read(&mut self, address: u8, buf: &mut [u8]) -> Result<usize, EndpointError> {
+        let n = (address & 0x0f) as usize;
+        let state = match self.ep_out.get_mut(n) {
+            Some(Some(state)) => state,
+            _ => return Err(EndpointError::InvalidEndpoint),
+        };
+        if !state.is_ready() {
+            return Err(EndpointError::WouldBlock);
+        }
+
+        let bd = self.pma.descriptor(n);
+        let ep = usb_ext::endpoint(&self.usb, n as u8);
+
+        // Reading re-enables reception, which mustn't take a halt off either.
+        if !state.is_isochronous() && ep.stat_rx() == EpStatus::Stall {
+            return Err(EndpointError::WouldBlock);
+        }
+
+        if state.is_isochronous() {
+            // A pending CTR means a newer frame, wait for ctr_ep to account for it.
+            if ep.ctr_rx() {
+                return Err(EndpointError::WouldBlock);
+            }
+
+            // The hardware receives in to the buffer DTOG_RX points at, the last frame is
+            // in the other.
+            let last = !ep.dtog_rx() as usize;
+            let count = bd.count_buf(last);
+            if count > buf.len() {
+                return Err(EndpointError::BufferOverflow);
+            }
+            self.pma.pma_area.read_buffer_u8(bd.addr_buf(last), &mut buf[..count]);
+
+            state.set_ready(false);
+            Ok(count)
+        } else if state.is_double_buffered() {
+            // SW_BUF is DTOG_TX on an RX endpoint.
+            let sw_buf = ep.dtog_tx() as usize;
+            let count = bd.count_buf(sw_buf);
+            if count > buf.len() {
+                return Err(EndpointError::BufferOverflow);
+            }
+            self.pma.pma_area.read_buffer_u8(bd.addr_buf(sw_buf), &mut buf[..count]);
+
+            // DTOG_RX == SW_BUF means the hardware filled the other buffer meanwhile and is
+            // NAKing, take that one and give it this one back.
+            if ep.dtog_rx() == ep.dtog_tx() {
+                ep.toggle_dtog_tx();
+            } else {
+                state.set_ready(false);
+            }
+            Ok(count)
+        } else {
+            let count = bd.count_rx();
+            if count > buf.len() {
+                return Err(EndpointError::BufferOverflow);
+            }
+            self.pma.pma_area.read_buffer_u8(bd.addr_rx(), &mut buf[..count]);
+
+            state.set_ready(false);
+            ep.set_stat_rx(EpStatus::Valid);
+            Ok(count)
+        }
+    }
+
+    // Halt a bulk or interrupt endpoint, or take the halt off. Clearing always starts the
+    // endpoint over from DATA0 (USB 2.0 9.4.5), and drops whatever was queued on it.
+    pub fn set_stalled(&mut self, address: u8, stalled: bool) -> Result<(), EndpointError> {
+        let n = (address & 0x0f) as usize;
+        let is_in = address & 0x80 != 0;
+        let states = if is_in {
+            &mut self.ep_in
+        } else {
+            &mut self.ep_out
+        };
+        let state = match states.get_mut(n) {
+            Some(Some(state)) if !state.is_isochronous() => state,
+            _ => return Err(EndpointError::InvalidEndpoint),
+        };
+        let ep = usb_ext::endpoint(&self.usb, n as u8);
+
+        match (is_in, stalled) {
+            (true, true) => ep.set_stat_tx(EpStatus::Stall),
+            (false, true) => ep.set_stat_rx(EpStatus::Stall),
+            (true, false) => {
+                state.set_ready(false);
+                ep.reset_dtog_tx();
+                if state.is_double_buffered() {
+                    // SW_BUF back on buffer 0 with DTOG_TX, as open_endpoint leaves it.
+                    ep.reset_dtog_rx();
+                    ep.set_stat_tx(EpStatus::Valid);
+                } else {
+                    ep.set_stat_tx(EpStatus::Nak);
+                }
+            }
+            (false, false) => {
+                state.set_ready(false);
+                ep.reset_dtog_rx();
+                if state.is_double_buffered() {
+                    ep.reset_dtog_tx();
+                    ep.toggle_dtog_tx();
+                }
+                ep.set_stat_rx(EpStatus::Valid);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_stalled(&self, address: u8) -> Result<bool, EndpointError> {
+        let n = (address & 0x0f) as usize;
+        let is_in = address & 0x80 != 0;
+        let states = if is_in { &self.ep_in } else { &self.ep_out };
+        match states.get(n) {
+            Some(Some(state)) if !state.is_isochronous() => {}
+            _ => return Err(EndpointError::InvalidEndpoint),
+        }
+        let ep = usb_ext::endpoint(&self.usb, n as u8);
+        let status = if is_in { ep.stat_tx() } else { ep.stat_rx() };
+        Ok(status == EpStatus::Stall)
+    }
+
+    // Whether the host has yet to take what was written to an IN endpoint. Setting a halt
+    // on it meanwhile would throw the packet away.
+    pub fn in_flight(&self, address: u8) -> Result<bool, EndpointError> {
+        let n = (address & 0x0f) as usize;
+        let state = match self.ep_in.get(n) {
+            Some(Some(state)) if address & 0x80 != 0 => state,
+            _ => return Err(EndpointError::InvalidEndpoint),
+        };
+        let ep = usb_ext::endpoint(&self.usb, n as u8);
+        Ok(if state.is_isochronous() {
+            state.is_ready()
+        } else if state.is_double_buffered() {
+            // Until the hardware catches up with SW_BUF it has a buffer left to send.
+            state.is_ready() || ep.dtog_tx() != ep.dtog_rx()
+        } else {
+            ep.stat_tx() == EpStatus::Valid
+        })
+    }
+
+    // Transfers finished since the last call. Polling read()/write() works just as well,
+    // this saves trying endpoints nothing happened on.
+    pub fn events(&mut self) -> Events {
+        core::mem::take(&mut self.events)
+    }
+
+    // Frame counters of an isochronous endpoint since the last call.
+    pub fn iso_status(&mut self, address: u8) -> Result<IsoStatus, EndpointError> {
+        let n = (address & 0x0f) as usize;
+        let states = if address & 0x80 != 0 {
+            &mut self.ep_in
+        } else {
+            &mut self.ep_out
+        };
+        match states.get_mut(n) {
+            Some(Some(state)) if state.is_isochronous() => Ok(state.take_status()),
+            _ => Err(EndpointError::InvalidEndpoint),
+        }
+    }
+
+    pub fn state(&self) -> UsbState {
+        self.state
+    }
+
+    // Frames the host didn't send a SOF for since the bus was reset.
+    pub fn missed_sofs(&self) -> u32 {
+        self.missed_sofs
+    }
+
+    // FNR.FN, the frame number from the last SOF.
+    pub fn frame_number(&self) -> u16 {
+        (self.usb.fnr.read().bits() & 0x07ff) as u16
+    }
+
+    // CTR on EP1..EP7.
+    fn ctr_ep(&mut self, number: u8) {
+        let n = number as usize;
+        let bd = self.pma.descriptor(n);
+        let ep = usb_ext::endpoint(&self.usb, number);
+
+        if ep.ctr_tx() {
+            ep.clear_ctr_tx();
+            if let Some(state) = self.ep_in[n].as_mut() {
+                self.events.in_complete |= 1 << n;
+
+                if state.is_isochronous() {
+                    // DTOG_TX has moved on to the buffer queued for the next frame, late
+                    // if nothing was. The one just sent is the application's again.
+                    state.transfer(!state.is_ready());
+                    bd.set_count_buf(!ep.dtog_tx() as usize, 0);
+                    state.set_ready(false);
+                } else if state.is_double_buffered() && state.is_ready() {
+                    // The hardware has moved DTOG_TX on to the application's buffer and
+                    // is NAKing, it has been filled meanwhile so send it.
+                    ep.toggle_dtog_rx();
+                    state.set_ready(false);
+                }
+            }
+        }
+
+        if ep.ctr_rx() {
+            ep.clear_ctr_rx();
+            if let Some(state) = self.ep_out[n].as_mut() {
+                self.events.out_received |= 1 << n;
+
+                if state.is_isochronous() {
+                    // No NAK to hold the host off, an unread frame is simply replaced.
+                    state.transfer(state.is_ready());
+                } else if state.is_double_buffered() && !state.is_ready() {
+                    // Swap the full buffer for the empty one the application holds so the
+                    // host can carry on. If the application still has data the swap waits
+                    // for read() and the hardware NAKs until then.
+                    ep.toggle_dtog_tx();
+                }
+                state.set_ready(true);
+            }
+        }
+    }
+
+    // Start of frame, close the last one on every isochronous endpoint.
+    fn sof(&mut self) {
+        let states = self.ep_in.iter_mut().chain(self.ep_out.iter_mut());
+        for state in states.flatten() {
+            if state.is_isochronous() {
+                state.frame();
+            }
+        }
+    }
+
+    fn ep(&self, number: u8) -> &dyn UsbEpExt {
+        usb_ext::endpoint(&self.usb, number)
+    }
+
+    fn ctr_ep0(&mut self, classes: &mut [&mut dyn UsbClass]) {
+        let (ctr_tx, ctr_rx, setup) = {
+            let ep0 = self.ep(0);
+            (ep0.ctr_tx(), ep0.ctr_rx(), ep0.setup())
+        };
+
+        if ctr_tx {
+            self.ep0_in();
+        }
+
+        if ctr_rx {
+            if setup {
+                self.ep0_setup(classes);
+            } else {
+                self.ep0_out(classes);
+            }
+        }
+    }
+
+    // classes get the control requests meant for them, see UsbClass.
+    pub fn interrupt(&mut self, classes: &mut [&mut dyn UsbClass]) {
+        let istr = self.usb.istr.read();
         if istr.reset().bit_is_set() {
             // Clear reset bit
             self.usb.istr.modify(|_, w| w.reset().clear_bit());
 
             // Execute reset
-            self.reset();
+            self.reset(classes);
         }
 
         if istr.err().bit_is_set() {
@@ -292,64 +1044,55 @@ impl<PINS> Usb<USB, PINS> {
             return;
         }
 
-        // Ignore these for now...
-        self.usb
-            .istr
-            .modify(|_, w| w.susp().clear_bit().sof().clear_bit().esof().clear_bit());
+        if istr.sof().bit_is_set() {
+            self.usb.istr.modify(|_, w| w.sof().clear_bit());
+            self.sof();
+        }
 
-        let istr = self.usb.istr.read();
+        if istr.esof().bit_is_set() {
+            self.usb.istr.modify(|_, w| w.esof().clear_bit());
+            self.missed_sofs = self.missed_sofs.wrapping_add(1);
+        }
 
-        // As long as ctr is set, do work.
-        if istr.ctr().bit_is_set() {
-            //hprintln!("ISTR: {:x} EP0R: {:x}", istr.bits() as u16, self.usb.ep0r.read().bits() as u16).unwrap();
+        // Ignore these for now...
+        self.usb.istr.modify(|_, w| w.susp().clear_bit());
+
+        // As long as ctr is set, do work. EP_ID is the highest priority endpoint with a CTR
+        // pending, each pass clears that one's.
+        loop {
+            let istr = self.usb.istr.read();
+            if istr.ctr().bit_is_clear() {
+                break;
+            }
             let ep = istr.ep_id().bits();
-            let dir = istr.dir().bit_is_set();
 
             if ep == 0 {
-                if dir {
-                    self.rx();
-                //self.usb.ep0r.write(|w| w.ctr_rx().clear_bit());
-                // Setup packet?
-                //                    if self.usb.ep0r.read().setup().bit_is_set() {
-                //                        let rx_count =  self.pma.pma_area.get_u16(3) & 0x03FF;
-                //                        //hprintln!("E0: {:x} {}", self.usb.ep0r.read().bits(), rx_count).unwrap();
-                //                        //self.usb.ep0r.write(|w| w.ctr_rx().clear_bit());
-                //                        //self.usb.ep0r.toggle_out();
-                //                        //self.usb.ep0r.toggle_tx();
-                //                        //self.usb.ep0r.toggle_tx_stall();
-                //                        //self.usb.ep0r.toggle_0();
-                //                        self.usb.ep0r.toggle_rx();
-                //                        self.usb.ep0r.clear_ctr_rx();
-                //                        //hprintln!("S! {}", rx_count).unwrap();
-                //
-                //                    } else if self.usb.ep0r.read().ctr_rx().bit_is_set() {
-                //                        self.usb.ep0r.write(|w| w.ctr_rx().clear_bit());
-                //                        let rx_count =  self.pma.pma_area.get_u16(3) & 0x03FF;
-                //                        hprintln!("DATA OUT! {}", rx_count).unwrap();
-                //                    }
-                //                    self.pma
-                //                        .pma_area.set_u16(3, (0x8000 | ((MAX_PACKET_SIZE / 32) - 1) << 10) as u16);
-                } else {
-                    self.tx();
-                    //self.usb.ep0r.write(|w| w.ctr_tx().clear_bit());
-                    //hprintln!("Foo: {:?}", FOO).unwrap();
-                }
+                self.ctr_ep0(classes);
+            } else {
+                self.ctr_ep(ep);
             }
-            //hprintln!("EP: {}", ep).unwrap();
-            //self.do_work();
         }
     }
 }
 
-//#[derive(Debug)]
-//#[repr(C, packed)]
-//struct Foo {
-//    bar: u8,
-//    baz: u8,
-//    bat: u8,
-//    zee: u8,
-//    zip: u8,
-//    yak: u8,
-//}
-//
-//const FOO : Foo = Foo { bar: 0xff, baz: 0x00, bat: 0x55, zee: 0xaa, zip: 0xff, yak: 0x00 };
+impl<PINS> Endpoints for Usb<USB, PINS> {
+    fn write(&mut self, address: u8, data: &[u8]) -> Result<usize, EndpointError> {
+        Usb::write(self, address, data)
+    }
+
+    fn read(&mut self, address: u8, buf: &mut [u8]) -> Result<usize, EndpointError> {
+        Usb::read(self, address, buf)
+    }
+
+    fn set_stalled(&mut self, address: u8, stalled: bool) -> Result<(), EndpointError> {
+        Usb::set_stalled(self, address, stalled)
+    }
+
+    fn is_stalled(&self, address: u8) -> Result<bool, EndpointError> {
+        Usb::is_stalled(self, address)
+    }
+
+    fn in_flight(&self, address: u8) -> Result<bool, EndpointError> {
+        Usb::in_flight(self, address)
+    }
+}
@@ -0,0 +1,90 @@
+#![allow(dead_code)]
+
+// Storage behind the mass storage class, see scsi::Scsi. Blocks are always 512 bytes,
+// what every host's FAT code expects.
+pub const BLOCK_SIZE: usize = 512;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum BlockError {
+    // Past block_count, scsi checks this before asking.
+    OutOfRange,
+    // The medium failed to read or write the block.
+    Io,
+    // Writes aren't taken.
+    ReadOnly,
+}
+
+pub trait BlockDevice {
+    fn block_count(&self) -> u32;
+
+    fn read_block(&mut self, lba: u32, block: &mut [u8; BLOCK_SIZE]) -> Result<(), BlockError>;
+
+    fn write_block(&mut self, lba: u32, block: &[u8; BLOCK_SIZE]) -> Result<(), BlockError>;
+
+    // Reported to the host with MODE SENSE, it won't try to write.
+    fn is_read_only(&self) -> bool {
+        false
+    }
+}
+
+// So a device in a static can be handed over without moving it.
+impl<B: BlockDevice> BlockDevice for &mut B {
+    fn block_count(&self) -> u32 {
+        (**self).block_count()
+    }
+
+    fn read_block(&mut self, lba: u32, block: &mut [u8; BLOCK_SIZE]) -> Result<(), BlockError> {
+        (**self).read_block(lba, block)
+    }
+
+    fn write_block(&mut self, lba: u32, block: &[u8; BLOCK_SIZE]) -> Result<(), BlockError> {
+        (**self).write_block(lba, block)
+    }
+
+    fn is_read_only(&self) -> bool {
+        (**self).is_read_only()
+    }
+}
+
+// N blocks of RAM. Put it in a static, it is too big for the stack.
+pub struct RamDisk<const N: usize> {
+    blocks: [[u8; BLOCK_SIZE]; N],
+}
+
+impl<const N: usize> RamDisk<N> {
+    pub const fn new() -> Self {
+        RamDisk {
+            blocks: [[0; BLOCK_SIZE]; N],
+        }
+    }
+}
+
+impl<const N: usize> Default for RamDisk<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> BlockDevice for RamDisk<N> {
+    fn block_count(&self) -> u32 {
+        N as u32
+    }
+
+    fn read_block(&mut self, lba: u32, block: &mut [u8; BLOCK_SIZE]) -> Result<(), BlockError> {
+        let data = self
+            .blocks
+            .get(lba as usize)
+            .ok_or(BlockError::OutOfRange)?;
+        block.copy_from_slice(data);
+        Ok(())
+    }
+
+    fn write_block(&mut self, lba: u32, block: &[u8; BLOCK_SIZE]) -> Result<(), BlockError> {
+        let data = self
+            .blocks
+            .get_mut(lba as usize)
+            .ok_or(BlockError::OutOfRange)?;
+        data.copy_from_slice(block);
+        Ok(())
+    }
+}
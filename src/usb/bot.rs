@@ -0,0 +1,323 @@
+#![allow(dead_code)]
+
+use core::cmp::min;
+use core::convert::TryInto;
+
+// super rather than crate::usb, host/ builds these files on their own for its tests.
+use super::block::{BlockDevice, BLOCK_SIZE};
+use super::scsi::{Scsi, Transfer};
+
+// USB Mass Storage Class Bulk-Only Transport 1.0. Each command is a CBW on bulk OUT, an
+// optional data phase, then a CSW on bulk IN.
+const CBW_SIGNATURE: u32 = 0x4342_5355; // "USBC"
+const CSW_SIGNATURE: u32 = 0x5342_5355; // "USBS"
+const CBW_SIZE: usize = 31;
+const CSW_SIZE: usize = 13;
+
+// Bulk packets, the data phase goes in chunks of this.
+pub const PACKET_SIZE: usize = 64;
+
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum CswStatus {
+    Passed = 0x00,
+    Failed = 0x01,
+    // The host and device disagree on the data phase, the host will reset.
+    PhaseError = 0x02,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum State {
+    // Waiting for a CBW.
+    Command,
+    DataIn,
+    DataOut,
+    // The CSW goes out once the host has cleared any halt.
+    Status,
+    // An invalid CBW came in. Both endpoints stay halted until a Bulk-Only Mass Storage
+    // Reset, BOT 6.6.1.
+    ResetRecovery,
+}
+
+// Endpoint halts the transport wants, see Bot::take_stalls.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct Stalls {
+    pub bulk_in: bool,
+    pub bulk_out: bool,
+}
+
+// The transport on one LUN, in terms of packets so it runs without the hardware. The
+// driver side (msc::MassStorage) moves packets and applies the stalls:
+//
+//   take_stalls()                      halt the endpoints it asks for, IN once the host
+//                                      has its last packet
+//   packet_in() / packet_sent()        next bulk IN packet, once the endpoint took it
+//   wants_out() / packet_out()         bulk OUT packets, only while it wants them
+//
+// Mismatches between what the host expects and what the command does follow the thirteen
+// cases of BOT 6.7.
+pub struct Bot<B> {
+    scsi: Scsi<B>,
+    state: State,
+    tag: u32,
+    // dCBWDataTransferLength still to go, the CSW residue.
+    host_remaining: u32,
+    // Data phase bytes the command still has to move.
+    device_remaining: u32,
+    // The host expects less than the command has (cases 7 and 13).
+    phase_error: bool,
+    // Next block of a READ or WRITE.
+    lba: u32,
+    blocks: bool,
+    buf: [u8; BLOCK_SIZE],
+    buf_len: usize,
+    buf_pos: usize,
+    csw: [u8; CSW_SIZE],
+    stalls: Stalls,
+}
+
+impl<B: BlockDevice> Bot<B> {
+    pub fn new(scsi: Scsi<B>) -> Self {
+        Bot {
+            scsi,
+            state: State::Command,
+            tag: 0,
+            host_remaining: 0,
+            device_remaining: 0,
+            phase_error: false,
+            lba: 0,
+            blocks: false,
+            buf: [0; BLOCK_SIZE],
+            buf_len: 0,
+            buf_pos: 0,
+            csw: [0; CSW_SIZE],
+            stalls: Stalls::default(),
+        }
+    }
+
+    pub fn scsi(&mut self) -> &mut Scsi<B> {
+        &mut self.scsi
+    }
+
+    // Bulk-Only Mass Storage Reset, or a bus reset. The host clears the halts itself.
+    pub fn reset(&mut self) {
+        self.state = State::Command;
+        self.stalls = Stalls::default();
+        self.scsi.reset();
+    }
+
+    // Waiting for the reset after an invalid CBW, CLEAR_FEATURE mustn't take the halts
+    // off until then.
+    pub fn needs_reset(&self) -> bool {
+        self.state == State::ResetRecovery
+    }
+
+    pub fn take_stalls(&mut self) -> Stalls {
+        core::mem::take(&mut self.stalls)
+    }
+
+    pub fn wants_out(&self) -> bool {
+        matches!(self.state, State::Command | State::DataOut)
+    }
+
+    // A packet from bulk OUT.
+    pub fn packet_out(&mut self, packet: &[u8]) {
+        match self.state {
+            State::Command => self.command(packet),
+            State::DataOut => self.data_out(packet),
+            _ => {}
+        }
+    }
+
+    // The next bulk IN packet, None if there is nothing to send.
+    pub fn packet_in(&mut self) -> Option<&[u8]> {
+        match self.state {
+            State::DataIn => {
+                if self.buf_pos == self.buf_len && !self.next_block() {
+                    return None;
+                }
+                let len = min(PACKET_SIZE, self.buf_len - self.buf_pos);
+                Some(&self.buf[self.buf_pos..self.buf_pos + len])
+            }
+            State::Status => Some(&self.csw),
+            _ => None,
+        }
+    }
+
+    // The packet from packet_in has gone out.
+    pub fn packet_sent(&mut self) {
+        match self.state {
+            State::DataIn => {
+                let len = min(PACKET_SIZE, self.buf_len - self.buf_pos);
+                self.buf_pos += len;
+                self.host_remaining -= len as u32;
+                self.device_remaining -= len as u32;
+                if self.buf_pos == self.buf_len
+                    && (self.device_remaining == 0 || self.host_remaining == 0)
+                {
+                    self.end_data_in();
+                }
+            }
+            State::Status => self.state = State::Command,
+            _ => {}
+        }
+    }
+
+    fn command(&mut self, packet: &[u8]) {
+        // BOT 6.2.1 valid and 6.2.2 meaningful, one LUN and no reserved bits set.
+        let valid = packet.len() == CBW_SIZE
+            && u32::from_le_bytes(packet[0..4].try_into().unwrap()) == CBW_SIGNATURE
+            && packet[12] & 0x7F == 0
+            && packet[13] == 0
+            && (1..=16).contains(&packet[14]);
+        if !valid {
+            self.state = State::ResetRecovery;
+            self.stalls = Stalls {
+                bulk_in: true,
+                bulk_out: true,
+            };
+            return;
+        }
+
+        self.tag = u32::from_le_bytes(packet[4..8].try_into().unwrap());
+        self.host_remaining = u32::from_le_bytes(packet[8..12].try_into().unwrap());
+        let host_in = packet[12] & 0x80 != 0;
+        let cdb = &packet[15..15 + packet[14] as usize];
+
+        self.phase_error = false;
+        self.blocks = false;
+        self.buf_len = 0;
+        self.buf_pos = 0;
+
+        let transfer = match self.scsi.command(cdb, &mut self.buf) {
+            Ok(transfer) => transfer,
+            Err(_) => {
+                self.no_data(host_in, CswStatus::Failed);
+                return;
+            }
+        };
+
+        match transfer {
+            Transfer::None
+            | Transfer::In(0)
+            | Transfer::Read { blocks: 0, .. }
+            | Transfer::Write { blocks: 0, .. } => self.no_data(host_in, CswStatus::Passed),
+
+            Transfer::In(_) | Transfer::Read { .. } if self.host_remaining == 0 || !host_in => {
+                // Cases 2 and 10.
+                self.no_data(host_in, CswStatus::PhaseError)
+            }
+
+            Transfer::In(len) => {
+                self.device_remaining = len as u32;
+                self.buf_len = min(len, self.host_remaining as usize);
+                self.phase_error = self.device_remaining > self.host_remaining;
+                self.state = State::DataIn;
+                if self.buf_len == 0 {
+                    self.end_data_in();
+                }
+            }
+
+            Transfer::Read { lba, blocks } => {
+                self.lba = lba;
+                self.blocks = true;
+                self.device_remaining = blocks * BLOCK_SIZE as u32;
+                self.phase_error = self.device_remaining > self.host_remaining;
+                self.state = State::DataIn;
+            }
+
+            Transfer::Write { .. } if self.host_remaining == 0 || host_in => {
+                // Cases 3 and 8.
+                self.no_data(host_in, CswStatus::PhaseError)
+            }
+
+            Transfer::Write { lba, blocks } => {
+                self.lba = lba;
+                self.blocks = true;
+                self.device_remaining = blocks * BLOCK_SIZE as u32;
+                self.phase_error = self.device_remaining > self.host_remaining;
+                self.state = State::DataOut;
+            }
+        }
+    }
+
+    // Load the next block of a READ, false if it failed and the command has ended.
+    fn next_block(&mut self) -> bool {
+        if !self.blocks || self.device_remaining == 0 || self.host_remaining == 0 {
+            return false;
+        }
+        if self.scsi.read(self.lba, &mut self.buf).is_err() {
+            // The rest of the host's data phase is cut off with a halt.
+            self.no_data(true, CswStatus::Failed);
+            return false;
+        }
+        self.lba += 1;
+        self.buf_pos = 0;
+        self.buf_len = min(
+            BLOCK_SIZE,
+            min(self.device_remaining, self.host_remaining) as usize,
+        );
+        true
+    }
+
+    fn data_out(&mut self, packet: &[u8]) {
+        let len = min(
+            packet.len(),
+            min(self.host_remaining, self.device_remaining) as usize,
+        );
+        let len = min(len, BLOCK_SIZE - self.buf_len);
+        self.buf[self.buf_len..self.buf_len + len].copy_from_slice(&packet[..len]);
+        self.buf_len += len;
+        self.host_remaining -= len as u32;
+        self.device_remaining -= len as u32;
+
+        if self.buf_len == BLOCK_SIZE {
+            if self.scsi.write(self.lba, &self.buf).is_err() {
+                self.no_data(false, CswStatus::Failed);
+                return;
+            }
+            self.lba += 1;
+            self.buf_len = 0;
+        }
+
+        if self.device_remaining == 0 {
+            // Case 11, the host has more to send than the command takes.
+            self.stalls.bulk_out = self.host_remaining > 0;
+            self.status(CswStatus::Passed);
+        } else if self.host_remaining == 0 {
+            // Case 13, what fits of a partial block is dropped.
+            self.status(CswStatus::PhaseError);
+        }
+    }
+
+    fn end_data_in(&mut self) {
+        if self.phase_error {
+            // Case 7, the host has had all it asked for.
+            self.status(CswStatus::PhaseError);
+        } else {
+            // Case 5, less data than the host expects. The halt ends the data phase.
+            self.stalls.bulk_in = self.host_remaining > 0;
+            self.status(CswStatus::Passed);
+        }
+    }
+
+    // No (more) data phase: the host's is halted if it expects one, cases 4 and 9.
+    fn no_data(&mut self, host_in: bool, status: CswStatus) {
+        if self.host_remaining > 0 {
+            if host_in {
+                self.stalls.bulk_in = true;
+            } else {
+                self.stalls.bulk_out = true;
+            }
+        }
+        self.status(status);
+    }
+
+    fn status(&mut self, status: CswStatus) {
+        self.csw[0..4].copy_from_slice(&CSW_SIGNATURE.to_le_bytes());
+        self.csw[4..8].copy_from_slice(&self.tag.to_le_bytes());
+        self.csw[8..12].copy_from_slice(&self.host_remaining.to_le_bytes());
+        self.csw[12] = status as u8;
+        self.state = State::Status;
+    }
+}
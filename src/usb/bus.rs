@@ -0,0 +1,383 @@
+// usb_device::bus::UsbBus on top of the same register and PMA code as Usb, so the class
+// crates (usbd-serial, usbd-hid, ...) can be used instead of our own control handling:
+//
+//   static mut BUS: Option<UsbBusAllocator<UsbBus<PINS>>> = None;
+//   let bus = unsafe { BUS.get_or_insert(UsbBus::new(p.USB, (dm, dp))) };
+//   let serial = SerialPort::new(bus);
+//   let dev = UsbDeviceBuilder::new(bus, UsbVidPid(0x0483, 0x5740)).build();
+//
+// and dev.poll(&mut [&mut serial]) from the USB interrupt. Endpoints run single buffered,
+// isochronous ones are only supported by Usb. main.rs runs it when built with
+// --features usb-device-stack.
+
+use cortex_m::interrupt::{self, Mutex};
+use usb_device::bus::{PollResult, UsbBusAllocator};
+use usb_device::endpoint::{EndpointAddress, EndpointType};
+use usb_device::{Result, UsbDirection, UsbError};
+
+use crate::usb::pma::{PmaAllocator, PmaError, PMA, PMA_Area};
+use crate::usb::usb_ext::{self, EpStatus, EpType};
+use crate::usb::{power_up, Pins, USB};
+
+const NUM_ENDPOINTS: usize = 8;
+
+// What alloc_ep handed out for one endpoint number, reset() programs the hardware from it.
+#[derive(Debug, Copy, Clone, Default)]
+struct EndpointConfig {
+    // IN and OUT of one number share EP_TYPE.
+    ep_type: Option<EpType>,
+    in_size: usize, // Zero when the direction isn't used.
+    out_size: usize,
+    tx_addr: usize,
+    rx_addr: usize,
+    rx_bits: u16,
+}
+
+pub struct UsbBus<PINS> {
+    usb: Mutex<USB>,
+    _pins: PINS,
+    pma_alloc: PmaAllocator,
+    endpoints: [EndpointConfig; NUM_ENDPOINTS],
+}
+
+impl From<PmaError> for UsbError {
+    fn from(_: PmaError) -> Self {
+        UsbError::EndpointMemoryOverflow
+    }
+}
+
+impl<PINS> UsbBus<PINS>
+where
+    PINS: Pins<USB> + Sync,
+{
+    pub fn new(usb: USB, pins: PINS) -> UsbBusAllocator<Self> {
+        power_up(&usb);
+
+        UsbBusAllocator::new(UsbBus {
+            usb: Mutex::new(usb),
+            _pins: pins,
+            pma_alloc: PmaAllocator::new(),
+            endpoints: [EndpointConfig::default(); NUM_ENDPOINTS],
+        })
+    }
+
+    fn pma(&self) -> &PMA_Area {
+        // NOTE(unsafe) Only touched from within interrupt::free, like the registers.
+        unsafe { &(*PMA.get()).pma_area }
+    }
+
+    fn config(&self, ep_addr: EndpointAddress) -> Result<&EndpointConfig> {
+        let config = self
+            .endpoints
+            .get(ep_addr.index())
+            .ok_or(UsbError::InvalidEndpoint)?;
+        let size = match ep_addr.direction() {
+            UsbDirection::In => config.in_size,
+            UsbDirection::Out => config.out_size,
+        };
+        if size == 0 {
+            return Err(UsbError::InvalidEndpoint);
+        }
+        Ok(config)
+    }
+}
+
+impl<PINS> usb_device::bus::UsbBus for UsbBus<PINS>
+where
+    PINS: Pins<USB> + Sync,
+{
+    fn alloc_ep(
+        &mut self,
+        ep_dir: UsbDirection,
+        ep_addr: Option<EndpointAddress>,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        _interval: u8,
+    ) -> Result<EndpointAddress> {
+        let ep_type = match ep_type {
+            EndpointType::Control => EpType::Control,
+            EndpointType::Bulk => EpType::Bulk,
+            EndpointType::Interrupt => EpType::Interrupt,
+            EndpointType::Isochronous { .. } => return Err(UsbError::Unsupported),
+        };
+        let size = max_packet_size as usize;
+
+        let numbers = match ep_addr {
+            Some(addr) => addr.index()..addr.index() + 1,
+            None => 0..NUM_ENDPOINTS,
+        };
+
+        for n in numbers {
+            let config = match self.endpoints.get_mut(n) {
+                Some(config) => config,
+                None => return Err(UsbError::InvalidEndpoint),
+            };
+            if config.ep_type.map_or(false, |t| t != ep_type) {
+                continue;
+            }
+            // Left to pick, EP0 is for the control pipe and only for that.
+            if ep_addr.is_none() && (n == 0) != (ep_type == EpType::Control) {
+                continue;
+            }
+
+            match ep_dir {
+                UsbDirection::In if config.in_size == 0 => {
+                    config.tx_addr = self.pma_alloc.alloc_tx(size)?;
+                    config.in_size = size;
+                }
+                UsbDirection::Out if config.out_size == 0 => {
+                    let (addr, bits) = self.pma_alloc.alloc_rx(size)?;
+                    config.rx_addr = addr;
+                    config.rx_bits = bits;
+                    config.out_size = size;
+                }
+                _ => continue,
+            }
+
+            config.ep_type = Some(ep_type);
+            return Ok(EndpointAddress::from_parts(n, ep_dir));
+        }
+
+        Err(match ep_addr {
+            Some(_) => UsbError::InvalidEndpoint,
+            None => UsbError::EndpointOverflow,
+        })
+    }
+
+    fn enable(&mut self) {
+        interrupt::free(|cs| {
+            let usb = self.usb.borrow(cs);
+
+            // Set BTable address to default.
+            usb.btable.reset();
+
+            usb.cntr.modify(|_, w| {
+                w.ctrm()
+                    .set_bit()
+                    .wkupm()
+                    .set_bit()
+                    .suspm()
+                    .set_bit()
+                    .resetm()
+                    .set_bit()
+            });
+
+            // Take out of reset.
+            usb.cntr.modify(|_, w| w.fres().clear_bit());
+
+            // Clear interrupts
+            usb.istr.reset();
+
+            // Enable pu
+            usb.bcdr.modify(|_, w| w.dppu().set_bit());
+        });
+    }
+
+    // Bus reset clears every EPnR, put back what alloc_ep handed out.
+    fn reset(&self) {
+        interrupt::free(|cs| {
+            let usb = self.usb.borrow(cs);
+
+            for (n, config) in self.endpoints.iter().enumerate() {
+                let ep_type = match config.ep_type {
+                    Some(ep_type) => ep_type,
+                    None => continue,
+                };
+                let bd = self.pma().descriptor(n);
+                let ep = usb_ext::endpoint(usb, n as u8);
+
+                ep.set_address(n as u8);
+                ep.set_type(ep_type);
+                ep.set_kind(false);
+
+                let mut tx = EpStatus::Disabled;
+                if config.in_size != 0 {
+                    bd.set_addr_tx(config.tx_addr);
+                    bd.set_count_tx(0);
+                    ep.reset_dtog_tx();
+                    tx = EpStatus::Nak;
+                }
+
+                let mut rx = EpStatus::Disabled;
+                if config.out_size != 0 {
+                    bd.set_addr_rx(config.rx_addr);
+                    bd.set_rx_size(config.rx_bits);
+                    ep.reset_dtog_rx();
+                    rx = EpStatus::Valid;
+                }
+
+                ep.set_stat(tx, rx);
+            }
+
+            usb.daddr.write(|w| w.ef().set_bit());
+        });
+    }
+
+    // usb-device calls this once the SET_ADDRESS status stage is done.
+    fn set_device_address(&self, addr: u8) {
+        interrupt::free(|cs| {
+            self.usb
+                .borrow(cs)
+                .daddr
+                .write(|w| unsafe { w.add().bits(addr) }.ef().set_bit());
+        });
+    }
+
+    fn write(&self, ep_addr: EndpointAddress, buf: &[u8]) -> Result<usize> {
+        if !ep_addr.is_in() {
+            return Err(UsbError::InvalidEndpoint);
+        }
+        let config = self.config(ep_addr)?;
+        if buf.len() > config.in_size {
+            return Err(UsbError::BufferOverflow);
+        }
+
+        interrupt::free(|cs| {
+            let ep = usb_ext::endpoint(self.usb.borrow(cs), ep_addr.index() as u8);
+            // Last packet still waiting for the host.
+            if ep.stat_tx() == EpStatus::Valid {
+                return Err(UsbError::WouldBlock);
+            }
+
+            let bd = self.pma().descriptor(ep_addr.index());
+            self.pma().write_buffer_u8(bd.addr_tx(), buf);
+            bd.set_count_tx(buf.len());
+            ep.set_stat_tx(EpStatus::Valid);
+            Ok(buf.len())
+        })
+    }
+
+    // CTR_RX stays set until the packet is read here, poll keeps reporting it until then.
+    fn read(&self, ep_addr: EndpointAddress, buf: &mut [u8]) -> Result<usize> {
+        if !ep_addr.is_out() {
+            return Err(UsbError::InvalidEndpoint);
+        }
+        self.config(ep_addr)?;
+
+        interrupt::free(|cs| {
+            let ep = usb_ext::endpoint(self.usb.borrow(cs), ep_addr.index() as u8);
+            if !ep.ctr_rx() {
+                return Err(UsbError::WouldBlock);
+            }
+
+            let bd = self.pma().descriptor(ep_addr.index());
+            let count = bd.count_rx();
+            if count > buf.len() {
+                return Err(UsbError::BufferOverflow);
+            }
+            self.pma().read_buffer_u8(bd.addr_rx(), &mut buf[..count]);
+
+            ep.clear_ctr_rx();
+            ep.set_stat_rx(EpStatus::Valid);
+            Ok(count)
+        })
+    }
+
+    // Clearing a halt also resets the data toggle (USB 2.0 9.4.5).
+    fn set_stalled(&self, ep_addr: EndpointAddress, stalled: bool) {
+        if self.config(ep_addr).is_err() {
+            return;
+        }
+
+        interrupt::free(|cs| {
+            let ep = usb_ext::endpoint(self.usb.borrow(cs), ep_addr.index() as u8);
+            match (ep_addr.direction(), stalled) {
+                (UsbDirection::In, true) => ep.set_stat_tx(EpStatus::Stall),
+                (UsbDirection::Out, true) => ep.set_stat_rx(EpStatus::Stall),
+                (UsbDirection::In, false) if ep.stat_tx() == EpStatus::Stall => {
+                    ep.reset_dtog_tx();
+                    ep.set_stat_tx(EpStatus::Nak);
+                }
+                (UsbDirection::Out, false) if ep.stat_rx() == EpStatus::Stall => {
+                    ep.reset_dtog_rx();
+                    ep.set_stat_rx(EpStatus::Valid);
+                }
+                _ => {}
+            }
+        });
+    }
+
+    fn is_stalled(&self, ep_addr: EndpointAddress) -> bool {
+        if self.config(ep_addr).is_err() {
+            return false;
+        }
+
+        interrupt::free(|cs| {
+            let ep = usb_ext::endpoint(self.usb.borrow(cs), ep_addr.index() as u8);
+            let status = match ep_addr.direction() {
+                UsbDirection::In => ep.stat_tx(),
+                UsbDirection::Out => ep.stat_rx(),
+            };
+            status == EpStatus::Stall
+        })
+    }
+
+    fn suspend(&self) {
+        interrupt::free(|cs| {
+            self.usb
+                .borrow(cs)
+                .cntr
+                .modify(|_, w| w.fsusp().set_bit().lpmode().set_bit());
+        });
+    }
+
+    fn resume(&self) {
+        interrupt::free(|cs| {
+            self.usb
+                .borrow(cs)
+                .cntr
+                .modify(|_, w| w.fsusp().clear_bit().lpmode().clear_bit());
+        });
+    }
+
+    fn poll(&self) -> PollResult {
+        interrupt::free(|cs| {
+            let usb = self.usb.borrow(cs);
+            let istr = usb.istr.read();
+
+            if istr.wkup().bit_is_set() {
+                usb.istr.modify(|_, w| w.wkup().clear_bit());
+                return PollResult::Resume;
+            }
+
+            if istr.reset().bit_is_set() {
+                usb.istr.modify(|_, w| w.reset().clear_bit());
+                return PollResult::Reset;
+            }
+
+            if istr.susp().bit_is_set() {
+                usb.istr.modify(|_, w| w.susp().clear_bit());
+                return PollResult::Suspend;
+            }
+
+            if istr.ctr().bit_is_clear() {
+                return PollResult::None;
+            }
+
+            // CTR_TX is reported once, CTR_RX is left for read() to clear.
+            let mut ep_out = 0;
+            let mut ep_in_complete = 0;
+            let mut ep_setup = 0;
+            for n in 0..NUM_ENDPOINTS {
+                let ep = usb_ext::endpoint(usb, n as u8);
+                if ep.ctr_tx() {
+                    ep.clear_ctr_tx();
+                    ep_in_complete |= 1 << n;
+                }
+                if ep.ctr_rx() {
+                    if ep.setup() {
+                        ep_setup |= 1 << n;
+                    } else {
+                        ep_out |= 1 << n;
+                    }
+                }
+            }
+
+            PollResult::Data {
+                ep_out,
+                ep_in_complete,
+                ep_setup,
+            }
+        })
+    }
+}
@@ -0,0 +1,239 @@
+#![allow(non_snake_case)]
+#![allow(dead_code)]
+
+use crate::usb::class::UsbClass;
+use crate::usb::constants::{Destination, Direction, Type};
+use crate::usb::control::{ControlResponse, SetupPacket};
+use crate::usb::descriptors::{hi, lo};
+use crate::usb::ring::RingBuffer;
+use crate::usb::serial::Serial;
+use crate::usb::{Usb, MAX_PACKET_SIZE, USB};
+
+// CDC 1.2 / PSTN 1.2 class codes.
+pub const CLASS_CDC: u8 = 0x02; // Device and communication interface class.
+pub const SUBCLASS_ACM: u8 = 0x02;
+pub const PROTOCOL_NONE: u8 = 0x00;
+pub const CLASS_CDC_DATA: u8 = 0x0A;
+
+// Functional descriptors, CDC 1.2 5.2.3. These go in the communication interface's
+// other_descriptors, header first.
+pub const CS_INTERFACE: u8 = 0x24;
+
+pub const fn header(bcdCDC: u16) -> [u8; 5] {
+    [5, CS_INTERFACE, 0x00, lo(bcdCDC), hi(bcdCDC)]
+}
+
+// bmCapabilities 0, the device doesn't handle call management itself.
+pub const fn call_management(bmCapabilities: u8, bDataInterface: u8) -> [u8; 5] {
+    [5, CS_INTERFACE, 0x01, bmCapabilities, bDataInterface]
+}
+
+// bmCapabilities bit 1, line coding and control line state requests are supported.
+pub const fn acm(bmCapabilities: u8) -> [u8; 4] {
+    [4, CS_INTERFACE, 0x02, bmCapabilities]
+}
+
+pub const fn union(bControlInterface: u8, bSubordinateInterface0: u8) -> [u8; 5] {
+    [5, CS_INTERFACE, 0x06, bControlInterface, bSubordinateInterface0]
+}
+
+// PSTN 1.2 6.3 class requests.
+const SEND_ENCAPSULATED_COMMAND: u8 = 0x00;
+const GET_ENCAPSULATED_RESPONSE: u8 = 0x01;
+const SET_LINE_CODING: u8 = 0x20;
+const GET_LINE_CODING: u8 = 0x21;
+const SET_CONTROL_LINE_STATE: u8 = 0x22;
+const SEND_BREAK: u8 = 0x23;
+
+const LINE_CODING_SIZE: usize = 7;
+
+// PSTN 1.2 6.3.11. Nothing is done with it, there is no real UART behind the port, but
+// terminals expect to read back what they set.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct LineCoding {
+    pub dwDTERate: u32,
+    pub bCharFormat: u8, // 0 - 1 stop bit, 1 - 1.5, 2 - 2.
+    pub bParityType: u8, // 0 - none, 1 - odd, 2 - even, 3 - mark, 4 - space.
+    pub bDataBits: u8,
+}
+
+impl LineCoding {
+    pub const fn new() -> Self {
+        LineCoding {
+            dwDTERate: 115_200,
+            bCharFormat: 0,
+            bParityType: 0,
+            bDataBits: 8,
+        }
+    }
+
+    fn to_bytes(&self) -> [u8; LINE_CODING_SIZE] {
+        let rate = self.dwDTERate.to_le_bytes();
+        [
+            rate[0],
+            rate[1],
+            rate[2],
+            rate[3],
+            self.bCharFormat,
+            self.bParityType,
+            self.bDataBits,
+        ]
+    }
+
+    fn from_bytes(b: &[u8]) -> Self {
+        LineCoding {
+            dwDTERate: u32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+            bCharFormat: b[4],
+            bParityType: b[5],
+            bDataBits: b[6],
+        }
+    }
+}
+
+const BUFFER_SIZE: usize = 256;
+const PACKET_SIZE: usize = MAX_PACKET_SIZE as usize;
+
+// CDC-ACM virtual serial port: a communication interface with a notification endpoint
+// and a data interface with a bulk endpoint each way. Bytes go through the rx/tx ring
+// buffers, poll moves them to and from the endpoints.
+pub struct CdcAcm {
+    comm_interface: u8,
+    data_out: u8,
+    data_in: u8,
+    line_coding: LineCoding,
+    dtr: bool,
+    rts: bool,
+    rx: RingBuffer<BUFFER_SIZE>,
+    tx: RingBuffer<BUFFER_SIZE>,
+    // The last packet sent was a full one, a ZLP has to end the transfer.
+    zlp: bool,
+}
+
+impl CdcAcm {
+    // Endpoint addresses as in the descriptors. The notification endpoint only has to be
+    // in the descriptors, nothing is ever sent on it.
+    pub const fn new(comm_interface: u8, data_out: u8, data_in: u8) -> Self {
+        CdcAcm {
+            comm_interface,
+            data_out,
+            data_in,
+            line_coding: LineCoding::new(),
+            dtr: false,
+            rts: false,
+            rx: RingBuffer::new(),
+            tx: RingBuffer::new(),
+            zlp: false,
+        }
+    }
+
+    pub fn line_coding(&self) -> LineCoding {
+        self.line_coding
+    }
+
+    // A terminal has the port open.
+    pub fn dtr(&self) -> bool {
+        self.dtr
+    }
+
+    pub fn rts(&self) -> bool {
+        self.rts
+    }
+
+    // Bytes received from the host, as many as fit in buf.
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        self.rx.read(buf)
+    }
+
+    // Queue bytes for the host, returns how many fit. poll sends them.
+    pub fn write(&mut self, data: &[u8]) -> usize {
+        self.tx.write(data)
+    }
+
+    pub fn available(&self) -> usize {
+        self.rx.len()
+    }
+
+    pub fn write_space(&self) -> usize {
+        self.tx.free()
+    }
+
+    // Everything written has been handed to the endpoint.
+    pub fn flushed(&self) -> bool {
+        self.tx.is_empty() && !self.zlp
+    }
+
+    // embedded-hal and core::fmt view of the port, see serial::Serial.
+    pub fn serial<'a, PINS>(&'a mut self, usb: &'a mut Usb<USB, PINS>) -> Serial<'a, PINS> {
+        Serial::new(self, usb)
+    }
+
+    // Move data between the endpoints and the ring buffers. Call it after Usb::interrupt,
+    // and after write so queued bytes go out without waiting for the next interrupt.
+    pub fn poll<PINS>(&mut self, usb: &mut Usb<USB, PINS>) {
+        let mut packet = [0u8; PACKET_SIZE];
+
+        // Only take a packet once all of it fits, until then it stays in the PMA and the
+        // host is NAKed.
+        while self.rx.free() >= PACKET_SIZE {
+            match usb.read(self.data_out, &mut packet) {
+                Ok(count) => {
+                    self.rx.write(&packet[..count]);
+                }
+                Err(_) => break,
+            }
+        }
+
+        while !self.tx.is_empty() || self.zlp {
+            let count = self.tx.peek(&mut packet);
+            match usb.write(self.data_in, &packet[..count]) {
+                Ok(_) => {
+                    self.tx.consume(count);
+                    self.zlp = count == PACKET_SIZE;
+                }
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+impl UsbClass for CdcAcm {
+    fn control(&mut self, setup: &SetupPacket, data: &mut [u8]) -> Option<ControlResponse> {
+        match (setup.request_type(), setup.destination()) {
+            (Some(Type::Class), Some(Destination::Interface))
+                if setup.wIndex as u8 == self.comm_interface => {}
+            _ => return None,
+        }
+
+        let response = match (setup.direction(), setup.bRequest) {
+            (Some(Direction::OUT), SET_LINE_CODING) if data.len() >= LINE_CODING_SIZE => {
+                self.line_coding = LineCoding::from_bytes(data);
+                ControlResponse::Accept
+            }
+
+            (Some(Direction::IN), GET_LINE_CODING) => {
+                data[..LINE_CODING_SIZE].copy_from_slice(&self.line_coding.to_bytes());
+                ControlResponse::Data(LINE_CODING_SIZE)
+            }
+
+            // wValue bit 0 DTR, bit 1 RTS.
+            (Some(Direction::OUT), SET_CONTROL_LINE_STATE) => {
+                self.dtr = setup.wValue & 0x0001 != 0;
+                self.rts = setup.wValue & 0x0002 != 0;
+                ControlResponse::Accept
+            }
+
+            (Some(Direction::OUT), SEND_BREAK) => ControlResponse::Accept,
+
+            _ => ControlResponse::Stall,
+        };
+        Some(response)
+    }
+
+    fn reset(&mut self) {
+        self.dtr = false;
+        self.rts = false;
+        self.rx.clear();
+        self.tx.clear();
+        self.zlp = false;
+    }
+}
@@ -0,0 +1,14 @@
+use crate::usb::control::{ControlResponse, SetupPacket};
+
+// A USB function on top of Usb, e.g. cdc::CdcAcm. Usb::interrupt hands every control
+// request that isn't a standard device request to the classes in turn, the first one
+// to return Some answers it. Endpoint data is left to the class's own poll, which goes
+// through Usb::read/write.
+pub trait UsbClass {
+    // IN requests fill data, the whole control buffer, and return Data(len). OUT requests
+    // get their data stage in data. None if the request isn't for this class.
+    fn control(&mut self, setup: &SetupPacket, data: &mut [u8]) -> Option<ControlResponse>;
+
+    // Bus reset, the host will configure the device from scratch.
+    fn reset(&mut self) {}
+}
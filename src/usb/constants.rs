@@ -19,6 +19,26 @@ pub enum UsbRequest {
     SynchFrame = 0x0C,
 }
 
+impl UsbRequest {
+    pub fn from_bits(bits: u8) -> Option<Self> {
+        match bits {
+            0x00 => Some(UsbRequest::GetStatus),
+            0x01 => Some(UsbRequest::ClearFeature),
+            0x02 => Some(UsbRequest::Two),
+            0x03 => Some(UsbRequest::SetFeature),
+            0x05 => Some(UsbRequest::SetAddress),
+            0x06 => Some(UsbRequest::GetDescriptor),
+            0x07 => Some(UsbRequest::SetDescriptor),
+            0x08 => Some(UsbRequest::GetConfiguration),
+            0x09 => Some(UsbRequest::SetConfiguration),
+            0x0A => Some(UsbRequest::GetInterface),
+            0x0B => Some(UsbRequest::SetInterface),
+            0x0C => Some(UsbRequest::SynchFrame),
+            _ => None,
+        }
+    }
+}
+
 #[repr(u8)]
 #[derive(Debug, Copy, Clone)]
 pub enum Direction {
@@ -75,7 +95,7 @@ pub enum Destination {
 
 impl Destination {
     pub const fn to_bits(self) -> u8 {
-        (self as u8)
+        self as u8
     }
 
     pub fn from_bits(bits: u8) -> Option<Self> {
@@ -149,6 +169,25 @@ pub enum UsbDescriptorType {
     HidReport = 0x22,
 }
 
+impl UsbDescriptorType {
+    pub fn from_bits(bits: u8) -> Option<Self> {
+        match bits {
+            1 => Some(UsbDescriptorType::Device),
+            2 => Some(UsbDescriptorType::Configuration),
+            3 => Some(UsbDescriptorType::StringDesc),
+            4 => Some(UsbDescriptorType::Interface),
+            5 => Some(UsbDescriptorType::Endpoint),
+            6 => Some(UsbDescriptorType::DeviceQualifier),
+            7 => Some(UsbDescriptorType::OtherSpeedConfiguration),
+            0x0A => Some(UsbDescriptorType::Debug),
+            0x0F => Some(UsbDescriptorType::Bos),
+            0x21 => Some(UsbDescriptorType::Hid),
+            0x22 => Some(UsbDescriptorType::HidReport),
+            _ => None,
+        }
+    }
+}
+
 impl From<u8> for UsbDescriptorType {
     #[inline]
     fn from(b: u8) -> Self {
@@ -156,6 +195,27 @@ impl From<u8> for UsbDescriptorType {
     }
 }
 
+// bmAttributes bits 1:0 of an endpoint descriptor.
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum EndpointType {
+    Control = 0b00,
+    Isochronous = 0b01,
+    Bulk = 0b10,
+    Interrupt = 0b11,
+}
+
+impl EndpointType {
+    pub const fn from_bits(bits: u8) -> Self {
+        match bits & 0b11 {
+            0b00 => EndpointType::Control,
+            0b01 => EndpointType::Isochronous,
+            0b10 => EndpointType::Bulk,
+            _ => EndpointType::Interrupt,
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone)]
 pub enum UsbDeviceState {
     Disabled,
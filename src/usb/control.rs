@@ -0,0 +1,214 @@
+#![allow(non_snake_case)]
+#![allow(dead_code)]
+
+use core::cmp::min;
+
+use crate::usb::constants::{Destination, Direction, Type, UsbRequest};
+
+// USB 2.0 9.3, every SETUP packet is exactly 8 bytes.
+pub const SETUP_PACKET_SIZE: usize = 8;
+
+// Control pipe states (USB 2.0 8.5.3).
+//
+//  Idle -> Setup -> DataIn  -> StatusOut -> Idle
+//                -> DataOut -> StatusIn  -> Idle
+//                -> StatusIn -> Idle         (no data stage)
+//                -> Stall                    (until the next SETUP)
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ControlState {
+    Idle,
+    Setup,
+    DataIn,
+    DataOut,
+    StatusIn,
+    StatusOut,
+    Stall,
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct SetupPacket {
+    pub bmRequestType: u8,
+    pub bRequest: u8,
+    pub wValue: u16,
+    pub wIndex: u16,
+    pub wLength: u16,
+}
+
+impl SetupPacket {
+    pub fn direction(&self) -> Option<Direction> {
+        Direction::from_bits(self.bmRequestType)
+    }
+
+    pub fn request_type(&self) -> Option<Type> {
+        Type::from_bits(self.bmRequestType)
+    }
+
+    pub fn destination(&self) -> Option<Destination> {
+        Destination::from_bits(self.bmRequestType)
+    }
+
+    pub fn request(&self) -> Option<UsbRequest> {
+        UsbRequest::from_bits(self.bRequest)
+    }
+}
+
+// The setup packet as it sits in the PMA, four half words LSB first.
+impl From<[u16; 4]> for SetupPacket {
+    #[inline]
+    fn from(w: [u16; 4]) -> Self {
+        SetupPacket {
+            bmRequestType: (w[0] & 0x00ff) as u8,
+            bRequest: ((w[0] & 0xff00) >> 8) as u8,
+            wValue: w[1],
+            wIndex: w[2],
+            wLength: w[3],
+        }
+    }
+}
+
+// What a request handler wants the pipe to do after seeing the SETUP.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ControlResponse {
+    // No data stage, go straight to the status stage.
+    Accept,
+    // IN data stage, this many bytes have been placed in the control buffer.
+    Data(usize),
+    // Protocol stall, unsupported or malformed request.
+    Stall,
+}
+
+pub struct ControlPipe {
+    state: ControlState,
+    setup: SetupPacket,
+    buf: &'static mut [u8],
+    max_packet: usize, // bMaxPacketSize0
+    len: usize,        // Bytes in this data stage, never more than wLength.
+    offset: usize,     // Bytes already moved in this data stage.
+    short: bool,       // IN stage still owes the host a short (or zero length) packet.
+}
+
+impl ControlPipe {
+    pub fn new(buf: &'static mut [u8], max_packet: usize) -> Self {
+        ControlPipe {
+            state: ControlState::Idle,
+            setup: SetupPacket::from([0u16; 4]),
+            buf,
+            max_packet,
+            len: 0,
+            offset: 0,
+            short: false,
+        }
+    }
+
+    pub fn max_packet(&self) -> usize {
+        self.max_packet
+    }
+
+    pub fn state(&self) -> ControlState {
+        self.state
+    }
+
+    pub fn request(&self) -> &SetupPacket {
+        &self.setup
+    }
+
+    pub fn reset(&mut self) {
+        self.state = ControlState::Idle;
+        self.len = 0;
+        self.offset = 0;
+        self.short = false;
+    }
+
+    // A SETUP always aborts whatever transfer was in progress.
+    pub fn setup(&mut self, setup: SetupPacket) {
+        self.setup = setup;
+        self.state = ControlState::Setup;
+        self.len = setup.wLength as usize;
+        self.offset = 0;
+        self.short = false;
+    }
+
+    pub fn buffer(&mut self) -> &mut [u8] {
+        &mut self.buf[..]
+    }
+
+    pub fn length(&self) -> usize {
+        self.len
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.len - self.offset
+    }
+
+    // Never send more than the host asked for, or more than we have room for.
+    //
+    // USB 2.0 5.5.3, a data stage shorter than wLength has to end with a short packet,
+    // which is a ZLP when the data is an exact multiple of the packet size.
+    pub fn data_in(&mut self, len: usize) {
+        self.len = min(min(len, self.setup.wLength as usize), self.buf.len());
+        self.offset = 0;
+        self.short = self.len < self.setup.wLength as usize;
+        self.state = ControlState::DataIn;
+    }
+
+    // OUT data goes into the control buffer, refuse anything that will not fit.
+    pub fn data_out(&mut self) -> bool {
+        if self.setup.wLength as usize > self.buf.len() {
+            return false;
+        }
+
+        self.len = self.setup.wLength as usize;
+        self.offset = 0;
+        self.state = ControlState::DataOut;
+        true
+    }
+
+    pub fn status_in(&mut self) {
+        self.state = ControlState::StatusIn;
+    }
+
+    pub fn status_out(&mut self) {
+        self.state = ControlState::StatusOut;
+    }
+
+    pub fn stall(&mut self) {
+        self.state = ControlState::Stall;
+    }
+
+    // Next packet of the IN data stage, None once the stage is complete.
+    pub fn next_in(&mut self) -> Option<&[u8]> {
+        if self.remaining() == 0 && !self.short {
+            return None;
+        }
+
+        let start = self.offset;
+        let end = start + min(self.max_packet, self.remaining());
+        if end - start < self.max_packet {
+            self.short = false;
+        }
+        self.offset = end;
+
+        Some(&self.buf[start..end])
+    }
+
+    // Where the next OUT data packet of count bytes goes, clipped to what is left of wLength.
+    pub fn next_out(&mut self, count: usize) -> &mut [u8] {
+        let start = self.offset;
+        let end = start + min(count, self.remaining());
+        self.offset = end;
+        &mut self.buf[start..end]
+    }
+
+    pub fn out_complete(&self) -> bool {
+        self.offset == self.len
+    }
+
+    // OUT data received so far.
+    pub fn data(&self) -> &[u8] {
+        &self.buf[..self.offset]
+    }
+}
@@ -1,6 +1,7 @@
 #![allow(non_snake_case)]
 #![allow(dead_code)]
 
+use core::cmp::min;
 use core::marker::Sized;
 use core::mem::{size_of, transmute};
 use core::slice::*;
@@ -120,6 +121,49 @@ impl Device {
             ..*self
         }
     }
+
+    pub const fn max_packet_size0(&self) -> u8 {
+        self.bMaxPacketSize0
+    }
+
+    pub const fn num_configurations(&self) -> u8 {
+        self.bNumConfigurations
+    }
+
+    pub const fn manufacturer_string(&self) -> u8 {
+        self.iManufacturer
+    }
+
+    pub const fn product_string(&self) -> u8 {
+        self.iProduct
+    }
+
+    pub const fn serial_number_string(&self) -> u8 {
+        self.iSerialNumber
+    }
+
+    pub const fn to_bytes(&self) -> [u8; size_of::<Device>()] {
+        [
+            self.bLength,
+            self.bDescriptorType,
+            lo(self.bcdUSB),
+            hi(self.bcdUSB),
+            self.bDeviceClass,
+            self.bDeviceSubClass,
+            self.bDeviceProtocol,
+            self.bMaxPacketSize0,
+            lo(self.idVendor),
+            hi(self.idVendor),
+            lo(self.idProduct),
+            hi(self.idProduct),
+            lo(self.bcdDevice),
+            hi(self.bcdDevice),
+            self.iManufacturer,
+            self.iProduct,
+            self.iSerialNumber,
+            self.bNumConfigurations,
+        ]
+    }
 }
 
 impl From<[u8; size_of::<Device>()]> for Device {
@@ -136,19 +180,53 @@ pub unsafe fn as_u8_arry<T: Sized>(ptr: &T) -> &[u8]
         size_of::<T>())
 }
 
-//impl From<Device> for &[u8] {
-//    #[inline]
-//    fn from(a: Device) -> &'static[u8] {
-//       unsafe { as_u8_arry(& a) }
-//    }
-//}
-//
-//impl From<Device> for [u8; size_of::<Device>()] {
-//    #[inline]
-//    fn from(a: Device) -> [u8; size_of::<Device>()] {
-//       *as_u8_arry(&a)
-//    }
-//}
+// Appends descriptors to a byte buffer, anything past the end of the buffer is dropped
+// since the host only ever gets the first wLength bytes anyway.
+pub struct DescriptorWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> DescriptorWriter<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        DescriptorWriter { buf, len: 0 }
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            if self.len < self.buf.len() {
+                self.buf[self.len] = *b;
+            }
+            self.len += 1;
+        }
+    }
+
+    // Bytes written so far, including any that did not fit.
+    pub fn total(&self) -> usize {
+        self.len
+    }
+
+    // Bytes actually in the buffer.
+    pub fn len(&self) -> usize {
+        min(self.len, self.buf.len())
+    }
+}
+
+// Descriptors go out on the wire little endian, whatever the target is (USB 2.0 8.1).
+pub const fn lo(v: u16) -> u8 {
+    (v & 0x00ff) as u8
+}
+
+pub const fn hi(v: u16) -> u8 {
+    ((v >> 8) & 0x00ff) as u8
+}
+
+impl From<Device> for [u8; size_of::<Device>()] {
+    #[inline]
+    fn from(a: Device) -> [u8; size_of::<Device>()] {
+        a.to_bytes()
+    }
+}
 
 #[derive(Debug, Copy, Clone)]
 #[repr(C, packed)]
@@ -188,6 +266,28 @@ impl DeviceQualifier {
     pub const fn bcdUSB(&self, bcdUSB: u16) -> Self {
         Self { bcdUSB, ..*self }
     }
+
+    pub const fn to_bytes(&self) -> [u8; size_of::<DeviceQualifier>()] {
+        [
+            self.bLength,
+            self.bDescriptorType,
+            lo(self.bcdUSB),
+            hi(self.bcdUSB),
+            self.bDeviceClass,
+            self.bDeviceSubClass,
+            self.bDeviceProtocol,
+            self.bMaxPacketSize0,
+            self.bNumConfigurations,
+            self.bReserved,
+        ]
+    }
+}
+
+impl From<DeviceQualifier> for [u8; size_of::<DeviceQualifier>()] {
+    #[inline]
+    fn from(a: DeviceQualifier) -> [u8; size_of::<DeviceQualifier>()] {
+        a.to_bytes()
+    }
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -262,6 +362,51 @@ impl Configuration {
     pub const fn bMaxPower(&self, bMaxPower: u8) -> Self {
         Self { bMaxPower, ..*self }
     }
+
+    // Same configuration, described as the one used at the other speed.
+    pub const fn other_speed(&self) -> Self {
+        Self {
+            bDescriptorType: constants::UsbDescriptorType::OtherSpeedConfiguration as u8,
+            ..*self
+        }
+    }
+
+    pub const fn configuration_value(&self) -> u8 {
+        self.bConfigurationValue
+    }
+
+    pub const fn total_length(&self) -> u16 {
+        self.wTotalLength
+    }
+
+    pub const fn num_interfaces(&self) -> u8 {
+        self.bNumInterfaces
+    }
+
+    pub const fn configuration_string(&self) -> u8 {
+        self.iConfiguration
+    }
+
+    pub const fn to_bytes(&self) -> [u8; size_of::<Configuration>()] {
+        [
+            self.bLength,
+            self.bDescriptorType,
+            lo(self.wTotalLength),
+            hi(self.wTotalLength),
+            self.bNumInterfaces,
+            self.bConfigurationValue,
+            self.iConfiguration,
+            self.bmAttributes,
+            self.bMaxPower,
+        ]
+    }
+}
+
+impl From<Configuration> for [u8; size_of::<Configuration>()] {
+    #[inline]
+    fn from(a: Configuration) -> [u8; size_of::<Configuration>()] {
+        a.to_bytes()
+    }
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -320,12 +465,82 @@ impl Interface {
         }
     }
 
+    pub const fn bInterfaceClass(&self, bInterfaceClass: u8) -> Self {
+        Self {
+            bInterfaceClass,
+            ..*self
+        }
+    }
+
+    pub const fn bInterfaceSubClass(&self, bInterfaceSubClass: u8) -> Self {
+        Self {
+            bInterfaceSubClass,
+            ..*self
+        }
+    }
+
+    pub const fn bInterfaceProtocol(&self, bInterfaceProtocol: u8) -> Self {
+        Self {
+            bInterfaceProtocol,
+            ..*self
+        }
+    }
+
     pub const fn iInterface(&self, iInterface: u8) -> Self {
         Self {
             iInterface,
             ..*self
         }
     }
+
+    pub const fn interface_number(&self) -> u8 {
+        self.bInterfaceNumber
+    }
+
+    pub const fn interface_class(&self) -> u8 {
+        self.bInterfaceClass
+    }
+
+    pub const fn interface_subclass(&self) -> u8 {
+        self.bInterfaceSubClass
+    }
+
+    pub const fn interface_protocol(&self) -> u8 {
+        self.bInterfaceProtocol
+    }
+
+    pub const fn num_endpoints(&self) -> u8 {
+        self.bNumEndpoints
+    }
+
+    pub const fn alternate_setting(&self) -> u8 {
+        self.bAlternateSetting
+    }
+
+    pub const fn interface_string(&self) -> u8 {
+        self.iInterface
+    }
+
+    pub const fn to_bytes(&self) -> [u8; size_of::<Interface>()] {
+        [
+            self.bLength,
+            self.bDescriptorType,
+            self.bInterfaceNumber,
+            self.bAlternateSetting,
+            self.bNumEndpoints,
+            self.bInterfaceClass,
+            self.bInterfaceSubClass,
+            self.bInterfaceProtocol,
+            self.iInterface,
+        ]
+    }
+}
+
+impl From<Interface> for [u8; size_of::<Interface>()] {
+    #[inline]
+    fn from(a: Interface) -> [u8; size_of::<Interface>()] {
+        a.to_bytes()
+    }
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -381,20 +596,99 @@ impl Endpoint {
     pub const fn bInterval(&self, bInterval: u8) -> Self {
         Self { bInterval, ..*self }
     }
+
+    pub const fn address(&self) -> u8 {
+        self.bEndpointAddress
+    }
+
+    pub const fn attributes(&self) -> u8 {
+        self.bmAttributes
+    }
+
+    pub const fn transfer_type(&self) -> constants::EndpointType {
+        constants::EndpointType::from_bits(self.bmAttributes)
+    }
+
+    pub const fn max_packet_size(&self) -> u16 {
+        self.wMaxPacketSize
+    }
+
+    pub const fn interval(&self) -> u8 {
+        self.bInterval
+    }
+
+    pub const fn to_bytes(&self) -> [u8; size_of::<Endpoint>()] {
+        [
+            self.bLength,
+            self.bDescriptorType,
+            self.bEndpointAddress,
+            self.bmAttributes,
+            lo(self.wMaxPacketSize),
+            hi(self.wMaxPacketSize),
+            self.bInterval,
+        ]
+    }
 }
 
-#[derive(Debug, Copy, Clone)]
-#[repr(C, packed)]
-pub struct String0 {
-    bLength: u8,
-    bDescriptorType: u8,
-    wLANGID: &'static [u16],
+impl From<Endpoint> for [u8; size_of::<Endpoint>()] {
+    #[inline]
+    fn from(a: Endpoint) -> [u8; size_of::<Endpoint>()] {
+        a.to_bytes()
+    }
 }
 
+// String descriptors can't be laid out as a packed struct, they are variable length
+// UTF-16LE. Keep them as &str for one language and encode on request.
 #[derive(Debug, Copy, Clone)]
-#[repr(C, packed)]
-pub struct String {
-    bLength: u8,
-    bDescriptorType: u8,
-    uString: &'static [u8],
+pub struct StringTable<'a> {
+    wLANGID: u16,
+    strings: &'a [&'a str], // String index 1 is strings[0].
+}
+
+// bLength is a u8, so a string descriptor holds at most 126 UTF-16 code units.
+const MAX_STRING_UNITS: usize = (255 - 2) / 2;
+
+impl<'a> StringTable<'a> {
+    pub const fn new(wLANGID: u16, strings: &'a [&'a str]) -> Self {
+        StringTable { wLANGID, strings }
+    }
+
+    pub const fn langid(&self) -> u16 {
+        self.wLANGID
+    }
+
+    // Highest valid string index in this table.
+    pub const fn len(&self) -> usize {
+        self.strings.len()
+    }
+
+    // String descriptor zero, the LANGIDs of every table (USB 2.0 9.6.7).
+    pub fn write_langids(tables: &[StringTable], writer: &mut DescriptorWriter) {
+        let count = min(tables.len(), MAX_STRING_UNITS);
+        writer.write(&[
+            (2 + 2 * count) as u8,
+            constants::UsbDescriptorType::StringDesc as u8,
+        ]);
+        for table in &tables[..count] {
+            writer.write(&table.wLANGID.to_le_bytes());
+        }
+    }
+
+    // Returns false when there is no string with this index.
+    pub fn write(&self, index: usize, writer: &mut DescriptorWriter) -> bool {
+        let string = match index.checked_sub(1).and_then(|i| self.strings.get(i)) {
+            Some(string) => string,
+            None => return false,
+        };
+
+        let units = min(string.encode_utf16().count(), MAX_STRING_UNITS);
+        writer.write(&[
+            (2 + 2 * units) as u8,
+            constants::UsbDescriptorType::StringDesc as u8,
+        ]);
+        for unit in string.encode_utf16().take(units) {
+            writer.write(&unit.to_le_bytes());
+        }
+        true
+    }
 }
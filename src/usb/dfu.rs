@@ -0,0 +1,411 @@
+#![allow(non_snake_case)]
+#![allow(dead_code)]
+
+use core::cmp::min;
+
+use crate::usb::class::UsbClass;
+use crate::usb::constants::{Destination, Direction, Type};
+use crate::usb::control::{ControlResponse, SetupPacket};
+use crate::usb::descriptors::{hi, lo};
+use crate::usb::flash::{Flash, FlashError, Update, IMAGE_SIZE, IMAGE_START};
+
+// DFU 1.1 4.2.1 interface codes. Protocol 1 is the interface an application shows to
+// say it can be switched to DFU mode, 2 the interface in DFU mode itself.
+pub const CLASS_APPLICATION: u8 = 0xFE;
+pub const SUBCLASS_DFU: u8 = 0x01;
+pub const PROTOCOL_RUNTIME: u8 = 0x01;
+pub const PROTOCOL_DFU_MODE: u8 = 0x02;
+
+// Functional descriptor, DFU 1.1 4.1.3. It goes in the interface's other_descriptors.
+pub const DFU_FUNCTIONAL: u8 = 0x21;
+
+// bmAttributes bits.
+pub const CAN_DNLOAD: u8 = 1 << 0;
+pub const CAN_UPLOAD: u8 = 1 << 1;
+pub const MANIFESTATION_TOLERANT: u8 = 1 << 2;
+pub const WILL_DETACH: u8 = 1 << 3;
+
+pub const fn functional(bmAttributes: u8, wDetachTimeOut: u16, wTransferSize: u16) -> [u8; 9] {
+    [
+        9,
+        DFU_FUNCTIONAL,
+        bmAttributes,
+        lo(wDetachTimeOut),
+        hi(wDetachTimeOut),
+        lo(wTransferSize),
+        hi(wTransferSize),
+        0x10, // bcdDFUVersion 1.1.
+        0x01,
+    ]
+}
+
+// Largest DNLOAD and UPLOAD block, the wTransferSize to give. It has to fit the control
+// buffer.
+pub const TRANSFER_SIZE: usize = 256;
+
+// DFU 1.1 3 class requests.
+const DETACH: u8 = 0x00;
+const DNLOAD: u8 = 0x01;
+const UPLOAD: u8 = 0x02;
+const GETSTATUS: u8 = 0x03;
+const CLRSTATUS: u8 = 0x04;
+const GETSTATE: u8 = 0x05;
+const ABORT: u8 = 0x06;
+
+const STATUS_SIZE: usize = 6;
+
+// Vendor request to the runtime interface, like DETACH but wValue says where to: 0 for
+// the update mode, 1 for the ROM bootloader.
+pub const VENDOR_REBOOT: u8 = 0x01;
+
+// DFU 1.1 6.1.2 bState.
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum State {
+    AppIdle = 0,
+    AppDetach = 1,
+    Idle = 2,
+    DnloadSync = 3,
+    DnBusy = 4,
+    DnloadIdle = 5,
+    ManifestSync = 6,
+    Manifest = 7,
+    ManifestWaitReset = 8,
+    UploadIdle = 9,
+    Error = 10,
+}
+
+// DFU 1.1 6.1.2 bStatus.
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Status {
+    Ok = 0x00,
+    ErrTarget = 0x01,
+    ErrFile = 0x02,
+    ErrWrite = 0x03,
+    ErrErase = 0x04,
+    ErrCheckErased = 0x05,
+    ErrProg = 0x06,
+    ErrVerify = 0x07,
+    ErrAddress = 0x08,
+    ErrNotDone = 0x09,
+    ErrFirmware = 0x0A,
+    ErrVendor = 0x0B,
+    ErrUsbr = 0x0C,
+    ErrPor = 0x0D,
+    ErrUnknown = 0x0E,
+    ErrStalledPkt = 0x0F,
+}
+
+// Where the runtime interface was asked to go.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Target {
+    Update,
+    SystemMemory,
+}
+
+impl From<FlashError> for Status {
+    fn from(error: FlashError) -> Self {
+        match error {
+            FlashError::Address => Status::ErrAddress,
+            FlashError::Erase => Status::ErrErase,
+            FlashError::Program => Status::ErrProg,
+        }
+    }
+}
+
+// The DFU mode interface, for the image half of the flash. A download is the image from
+// its first byte, block after block, and is staged like any other update: nothing in
+// the image changes until the zero length DNLOAD that ends it, after which the state is
+// ManifestWaitReset and the caller installs the update. An abort or error on the way
+// leaves the running image as it was. Upload reads the image back.
+//
+// Blocks are programmed while answering the GETSTATUS that follows them, so the host
+// never sees dfuDNBUSY. The update replaces the firmware doing the manifestation, so
+// the device isn't manifestation tolerant and resets in to the new image.
+pub struct Dfu<F> {
+    interface: u8,
+    update: Update<F>,
+    state: State,
+    status: Status,
+    // Image offset of the next block down or up.
+    offset: u32,
+    block: [u8; TRANSFER_SIZE],
+    block_len: usize,
+}
+
+impl<F: Flash> Dfu<F> {
+    pub fn new(interface: u8, flash: F) -> Self {
+        Dfu {
+            interface,
+            update: Update::new(flash),
+            state: State::Idle,
+            status: Status::Ok,
+            offset: 0,
+            block: [0; TRANSFER_SIZE],
+            block_len: 0,
+        }
+    }
+
+    pub fn update(&mut self) -> &mut Update<F> {
+        &mut self.update
+    }
+
+    pub fn state(&self) -> State {
+        self.state
+    }
+
+    pub fn status(&self) -> Status {
+        self.status
+    }
+
+    // A protocol error stalls the request and leaves the state at dfuERROR until
+    // CLRSTATUS.
+    fn stall(&mut self) -> ControlResponse {
+        if self.state != State::ManifestWaitReset {
+            self.state = State::Error;
+            self.status = Status::ErrStalledPkt;
+        }
+        ControlResponse::Stall
+    }
+
+    fn fail(&mut self, status: Status) {
+        self.state = State::Error;
+        self.status = status;
+    }
+
+    fn download(&mut self, data: &[u8]) -> ControlResponse {
+        match self.state {
+            State::Idle | State::DnloadIdle if !data.is_empty() && data.len() <= TRANSFER_SIZE => {
+                if self.state == State::Idle {
+                    self.update.begin();
+                    self.offset = 0;
+                }
+                self.block[..data.len()].copy_from_slice(data);
+                self.block_len = data.len();
+                self.state = State::DnloadSync;
+                ControlResponse::Accept
+            }
+
+            // The end of the download.
+            State::DnloadIdle if data.is_empty() => {
+                self.state = State::ManifestSync;
+                ControlResponse::Accept
+            }
+
+            _ => self.stall(),
+        }
+    }
+
+    fn program_block(&mut self) {
+        // Flash takes half words, an odd last block is padded with erased flash.
+        let mut len = self.block_len;
+        if len & 1 != 0 {
+            self.block[len] = 0xFF;
+            len += 1;
+        }
+        match self
+            .update
+            .write(IMAGE_START + self.offset, &self.block[..len])
+        {
+            Ok(()) => {
+                self.offset += self.block_len as u32;
+                self.state = State::DnloadIdle;
+            }
+            Err(error) => self.fail(error.into()),
+        }
+    }
+
+    fn upload(&mut self, length: usize, data: &mut [u8]) -> ControlResponse {
+        match self.state {
+            State::Idle => self.offset = 0,
+            State::UploadIdle => {}
+            _ => return self.stall(),
+        }
+
+        let len = min(
+            min(length, TRANSFER_SIZE),
+            (IMAGE_SIZE - self.offset) as usize,
+        );
+        if let Err(error) = self
+            .update
+            .read(IMAGE_START + self.offset, &mut data[..len])
+        {
+            self.fail(error.into());
+            return ControlResponse::Stall;
+        }
+        self.offset += len as u32;
+
+        // A short block is the end of the image.
+        self.state = if len < length {
+            State::Idle
+        } else {
+            State::UploadIdle
+        };
+        ControlResponse::Data(len)
+    }
+
+    fn get_status(&mut self, data: &mut [u8]) -> ControlResponse {
+        let state = match self.state {
+            State::DnloadSync => {
+                self.program_block();
+                self.state
+            }
+            // Reported as dfuMANIFEST, the update is installed once the host has seen
+            // it and there is nothing else to be done here until then.
+            State::ManifestSync => {
+                self.state = State::ManifestWaitReset;
+                State::Manifest
+            }
+            state => state,
+        };
+
+        data[0] = self.status as u8;
+        data[1..4].copy_from_slice(&[0, 0, 0]); // bwPollTimeout, nothing is left running.
+        data[4] = state as u8;
+        data[5] = 0; // iString
+        ControlResponse::Data(STATUS_SIZE)
+    }
+}
+
+impl<F: Flash> UsbClass for Dfu<F> {
+    fn control(&mut self, setup: &SetupPacket, data: &mut [u8]) -> Option<ControlResponse> {
+        match (setup.request_type(), setup.destination()) {
+            (Some(Type::Class), Some(Destination::Interface))
+                if setup.wIndex as u8 == self.interface => {}
+            _ => return None,
+        }
+
+        // Waiting to be reset in to the update, the host may still ask how it went or
+        // send the DETACH dfu-util -R does before resetting the bus. Nothing else is
+        // answered.
+        if self.state == State::ManifestWaitReset {
+            return Some(match (setup.direction(), setup.bRequest) {
+                (Some(Direction::IN), GETSTATUS) => self.get_status(data),
+                (Some(Direction::IN), GETSTATE) => {
+                    data[0] = self.state as u8;
+                    ControlResponse::Data(1)
+                }
+                (Some(Direction::OUT), DETACH) => ControlResponse::Accept,
+                _ => ControlResponse::Stall,
+            });
+        }
+
+        let response = match (setup.direction(), setup.bRequest) {
+            (Some(Direction::OUT), DNLOAD) => self.download(data),
+
+            (Some(Direction::IN), UPLOAD) => self.upload(setup.wLength as usize, data),
+
+            (Some(Direction::IN), GETSTATUS) => self.get_status(data),
+
+            (Some(Direction::OUT), CLRSTATUS) if self.state == State::Error => {
+                self.state = State::Idle;
+                self.status = Status::Ok;
+                ControlResponse::Accept
+            }
+
+            (Some(Direction::IN), GETSTATE) => {
+                data[0] = self.state as u8;
+                ControlResponse::Data(1)
+            }
+
+            // Whatever was staged so far is dropped with the next download.
+            (Some(Direction::OUT), ABORT) if self.state != State::Error => {
+                self.state = State::Idle;
+                ControlResponse::Accept
+            }
+
+            // DETACH is for the runtime interface, in DFU mode it is an error like any
+            // other request out of place.
+            _ => self.stall(),
+        };
+        Some(response)
+    }
+
+    // A download or upload in progress is abandoned. Once the update is in it stays
+    // ready to install whatever the host does.
+    fn reset(&mut self) {
+        if self.state != State::ManifestWaitReset {
+            self.state = State::Idle;
+            self.status = Status::Ok;
+        }
+    }
+}
+
+// The runtime interface, in the application. It only tells the host that the device
+// can be switched to DFU mode, and takes the DETACH that does it. The device has
+// bitWillDetach, it resets itself once the caller sees detach return Some.
+pub struct DfuRuntime {
+    interface: u8,
+    detach: Option<Target>,
+}
+
+impl DfuRuntime {
+    pub fn new(interface: u8) -> Self {
+        DfuRuntime {
+            interface,
+            detach: None,
+        }
+    }
+
+    pub fn detach(&self) -> Option<Target> {
+        self.detach
+    }
+
+    fn state(&self) -> State {
+        match self.detach {
+            Some(_) => State::AppDetach,
+            None => State::AppIdle,
+        }
+    }
+}
+
+impl UsbClass for DfuRuntime {
+    fn control(&mut self, setup: &SetupPacket, data: &mut [u8]) -> Option<ControlResponse> {
+        let vendor = match (setup.request_type(), setup.destination()) {
+            (Some(Type::Class), Some(Destination::Interface))
+                if setup.wIndex as u8 == self.interface =>
+            {
+                false
+            }
+            (Some(Type::Vendor), Some(Destination::Interface))
+                if setup.wIndex as u8 == self.interface =>
+            {
+                true
+            }
+            _ => return None,
+        };
+
+        let response = match (vendor, setup.direction(), setup.bRequest) {
+            // wValue is how long the host waits for the reset, it comes well before.
+            (false, Some(Direction::OUT), DETACH) => {
+                self.detach = Some(Target::Update);
+                ControlResponse::Accept
+            }
+
+            (false, Some(Direction::IN), GETSTATUS) => {
+                data[0] = Status::Ok as u8;
+                data[1..4].copy_from_slice(&[0, 0, 0]);
+                data[4] = self.state() as u8;
+                data[5] = 0;
+                ControlResponse::Data(STATUS_SIZE)
+            }
+
+            (false, Some(Direction::IN), GETSTATE) => {
+                data[0] = self.state() as u8;
+                ControlResponse::Data(1)
+            }
+
+            (true, Some(Direction::OUT), VENDOR_REBOOT) if setup.wValue <= 1 => {
+                self.detach = Some(match setup.wValue {
+                    0 => Target::Update,
+                    _ => Target::SystemMemory,
+                });
+                ControlResponse::Accept
+            }
+
+            _ => ControlResponse::Stall,
+        };
+        Some(response)
+    }
+}
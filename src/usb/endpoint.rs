@@ -0,0 +1,140 @@
+#![allow(dead_code)]
+
+use crate::usb::constants::EndpointType;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum EndpointError {
+    // Nothing received yet, no free buffer to write in to, or the endpoint is halted.
+    // Try again later.
+    WouldBlock,
+    // The packet doesn't fit the endpoint, or the caller's buffer is too small for it.
+    BufferOverflow,
+    // Not an endpoint of the current configuration.
+    InvalidEndpoint,
+}
+
+// Endpoints that finished a transfer since the last Usb::events(), one bit per endpoint
+// number.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct Events {
+    // The host has taken the packet written to the IN endpoint.
+    pub in_complete: u8,
+    // A packet has arrived on the OUT endpoint, read() will have it.
+    pub out_received: u8,
+}
+
+impl Events {
+    pub fn is_empty(&self) -> bool {
+        self.in_complete == 0 && self.out_received == 0
+    }
+
+    pub fn in_complete(&self, address: u8) -> bool {
+        self.in_complete & (1 << (address & 0x07)) != 0
+    }
+
+    pub fn out_received(&self, address: u8) -> bool {
+        self.out_received & (1 << (address & 0x07)) != 0
+    }
+}
+
+// Per frame bookkeeping of an isochronous endpoint since it was last asked for, see
+// Usb::iso_status.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct IsoStatus {
+    // Frames the host didn't transfer anything in.
+    pub missed: u16,
+    // IN: frames that went out as a zero length packet as nothing was queued in time.
+    // OUT: frames that were overwritten by the next one before read() got to them.
+    pub late: u16,
+}
+
+// Driver side state of one direction of an endpoint, EP0 is handled by ControlPipe.
+//
+// Single buffered the endpoint's STAT bits do the flow control: the hardware NAKs an
+// OUT endpoint after every packet until it is read, and an IN endpoint until a packet
+// is written. Double buffered (RM0091 30.5.3) STAT stays VALID, the hardware only NAKs
+// when its DTOG and the application's SW_BUF point at the same buffer, so it is up to
+// the driver to flip SW_BUF on each CTR. Isochronous endpoints are always double buffered
+// but have no handshake, DTOG alone picks the hardware's buffer and flips every frame.
+#[derive(Debug, Copy, Clone)]
+pub struct EndpointState {
+    ep_type: EndpointType,
+    max_packet: usize,
+    double_buffered: bool,
+    // IN: double buffered only, the application's buffer is filled and waiting for the
+    // hardware to finish the other one.
+    // OUT: a packet is waiting to be read.
+    // Isochronous IN: a packet is queued for the next frame.
+    ready: bool,
+    // Isochronous only, something was transferred since the last SOF.
+    transferred: bool,
+    status: IsoStatus,
+}
+
+impl EndpointState {
+    pub fn new(ep_type: EndpointType, max_packet: usize, double_buffered: bool) -> Self {
+        EndpointState {
+            ep_type,
+            max_packet,
+            double_buffered,
+            ready: false,
+            transferred: false,
+            status: IsoStatus::default(),
+        }
+    }
+
+    pub fn ep_type(&self) -> EndpointType {
+        self.ep_type
+    }
+
+    pub fn max_packet(&self) -> usize {
+        self.max_packet
+    }
+
+    pub fn is_double_buffered(&self) -> bool {
+        self.double_buffered
+    }
+
+    pub fn is_isochronous(&self) -> bool {
+        self.ep_type == EndpointType::Isochronous
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready
+    }
+
+    pub fn set_ready(&mut self, ready: bool) {
+        self.ready = ready;
+    }
+
+    // Isochronous CTR. late is whether the application missed the frame.
+    pub fn transfer(&mut self, late: bool) {
+        self.transferred = true;
+        if late {
+            self.status.late = self.status.late.saturating_add(1);
+        }
+    }
+
+    // Isochronous SOF, closes the last frame.
+    pub fn frame(&mut self) {
+        if !self.transferred {
+            self.status.missed = self.status.missed.saturating_add(1);
+        }
+        self.transferred = false;
+    }
+
+    pub fn take_status(&mut self) -> IsoStatus {
+        core::mem::take(&mut self.status)
+    }
+}
+
+// The bulk/interrupt side of Usb, for classes that move their own packets. Usb is the
+// implementation, a model of it lets the class run on the host.
+pub trait Endpoints {
+    fn write(&mut self, address: u8, data: &[u8]) -> Result<usize, EndpointError>;
+    fn read(&mut self, address: u8, buf: &mut [u8]) -> Result<usize, EndpointError>;
+    fn set_stalled(&mut self, address: u8, stalled: bool) -> Result<(), EndpointError>;
+    fn is_stalled(&self, address: u8) -> Result<bool, EndpointError>;
+    // A packet written to the IN endpoint hasn't been taken by the host yet.
+    fn in_flight(&self, address: u8) -> Result<bool, EndpointError>;
+}
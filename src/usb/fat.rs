@@ -0,0 +1,325 @@
+#![allow(dead_code)]
+
+use core::cmp::min;
+use core::fmt;
+
+// super rather than crate::usb, host/ builds these files on their own for its tests.
+use super::block::{BlockDevice, BlockError, BLOCK_SIZE};
+
+// A FAT12 volume made up on the fly, for the mass storage class. Nothing is stored,
+// every block is generated when the host reads it, and it is read-only unless given
+// on_write:
+//
+//   0          boot sector
+//   1 - 6      FAT
+//   7 - 12     second copy of the FAT
+//   13         root directory, the volume label and one entry per file
+//   14 -       data, FILE_CLUSTERS one block clusters per file
+//
+// Files are a name and a function that writes the content, called for every block that
+// is read. Sizes come from running it to the end, so content that changes after the
+// host has read the directory is cut or padded to the size it saw. Hosts cache what
+// they read, a file that changes is best looked at after replugging.
+pub const SECTORS: u32 = 2048;
+const RESERVED_SECTORS: u32 = 1;
+const FATS: u32 = 2;
+const FAT_SECTORS: u32 = 6;
+const ROOT_ENTRIES: usize = 16;
+const ROOT_SECTORS: u32 = (ROOT_ENTRIES * DIR_ENTRY_SIZE / BLOCK_SIZE) as u32;
+const ROOT_START: u32 = RESERVED_SECTORS + FATS * FAT_SECTORS;
+const DATA_START: u32 = ROOT_START + ROOT_SECTORS;
+const CLUSTERS: u32 = SECTORS - DATA_START;
+const DIR_ENTRY_SIZE: usize = 32;
+
+// Fewer than 4085 clusters is what makes it FAT12, and the FAT has to hold them all.
+const _: () = assert!(CLUSTERS < 4085);
+const _: () = assert!((CLUSTERS as usize + 2) * 3 / 2 <= (FAT_SECTORS as usize) * BLOCK_SIZE);
+
+// Room for each file, longer content is cut short.
+pub const FILE_CLUSTERS: u32 = 64;
+pub const MAX_FILE_SIZE: usize = FILE_CLUSTERS as usize * BLOCK_SIZE;
+// The root directory less the volume label.
+pub const MAX_FILES: usize = ROOT_ENTRIES - 1;
+
+const _: () = assert!(MAX_FILES as u32 * FILE_CLUSTERS <= CLUSTERS);
+
+const MEDIA: u8 = 0xF8; // Fixed disk.
+const END_OF_CHAIN: u16 = 0xFFF;
+const ATTR_READ_ONLY: u8 = 0x01;
+const ATTR_VOLUME_ID: u8 = 0x08;
+// 2020-01-01 00:00, for every entry.
+const DATE: u16 = (40 << 9) | (1 << 5) | 1;
+const TIME: u16 = 0;
+
+// One file, name is 8.3 in upper case, e.g. "INFO.TXT".
+pub struct File<C> {
+    pub name: &'static str,
+    pub generate: fn(&C, &mut dyn fmt::Write) -> fmt::Result,
+}
+
+// Takes a block the host wrote, see VirtualFat::on_write.
+pub type WriteFn<C> = fn(&mut C, u32, &[u8; BLOCK_SIZE]) -> Result<(), BlockError>;
+
+// The volume for a table of files. context is what the generators are given, e.g. the
+// device state INFO.TXT shows.
+pub struct VirtualFat<C: 'static> {
+    label: &'static str,
+    serial: u32,
+    files: &'static [File<C>],
+    context: C,
+    write: Option<WriteFn<C>>,
+}
+
+impl<C> VirtualFat<C> {
+    // Files past MAX_FILES are left out.
+    pub const fn new(
+        label: &'static str,
+        serial: u32,
+        files: &'static [File<C>],
+        context: C,
+    ) -> Self {
+        VirtualFat {
+            label,
+            serial,
+            files,
+            context,
+            write: None,
+        }
+    }
+
+    // Make it writable, every block the host writes goes to write along with its lba.
+    // What is read back doesn't change, it is up to write to do something with them,
+    // e.g. uf2::write.
+    pub fn on_write(mut self, write: WriteFn<C>) -> Self {
+        self.write = Some(write);
+        self
+    }
+
+    pub fn context(&self) -> &C {
+        &self.context
+    }
+
+    pub fn context_mut(&mut self) -> &mut C {
+        &mut self.context
+    }
+
+    fn files(&self) -> &'static [File<C>] {
+        &self.files[..min(self.files.len(), MAX_FILES)]
+    }
+
+    // Bytes of file n, up to MAX_FILE_SIZE.
+    fn size(&self, n: usize) -> u32 {
+        let mut window = Window::new(&mut [], 0);
+        (self.files()[n].generate)(&self.context, &mut window).ok();
+        window.end as u32
+    }
+
+    fn sizes(&self) -> [u32; MAX_FILES] {
+        let mut sizes = [0; MAX_FILES];
+        for (n, size) in sizes.iter_mut().enumerate().take(self.files().len()) {
+            *size = self.size(n);
+        }
+        sizes
+    }
+
+    // FAT entry of a cluster: the next one in the file, END_OF_CHAIN or 0 for free.
+    fn fat_entry(&self, sizes: &[u32; MAX_FILES], cluster: u32) -> u16 {
+        match cluster {
+            0 => 0xF00 | MEDIA as u16,
+            1 => END_OF_CHAIN,
+            _ => {
+                let n = ((cluster - 2) / FILE_CLUSTERS) as usize;
+                let index = (cluster - 2) % FILE_CLUSTERS;
+                if n >= self.files().len() {
+                    return 0;
+                }
+                let clusters = clusters(sizes[n]);
+                if index + 1 < clusters {
+                    cluster as u16 + 1
+                } else if index + 1 == clusters {
+                    END_OF_CHAIN
+                } else {
+                    0
+                }
+            }
+        }
+    }
+
+    fn boot_sector(&self, block: &mut [u8; BLOCK_SIZE]) {
+        block[0..3].copy_from_slice(&[0xEB, 0x3C, 0x90]); // Jump over the BPB.
+        block[3..11].copy_from_slice(b"MSWIN4.1");
+        block[11..13].copy_from_slice(&(BLOCK_SIZE as u16).to_le_bytes());
+        block[13] = 1; // Sectors per cluster.
+        block[14..16].copy_from_slice(&(RESERVED_SECTORS as u16).to_le_bytes());
+        block[16] = FATS as u8;
+        block[17..19].copy_from_slice(&(ROOT_ENTRIES as u16).to_le_bytes());
+        block[19..21].copy_from_slice(&(SECTORS as u16).to_le_bytes());
+        block[21] = MEDIA;
+        block[22..24].copy_from_slice(&(FAT_SECTORS as u16).to_le_bytes());
+        block[24..26].copy_from_slice(&32u16.to_le_bytes()); // Sectors per track.
+        block[26..28].copy_from_slice(&64u16.to_le_bytes()); // Heads.
+        block[36] = 0x80; // Drive number.
+        block[38] = 0x29; // Extended boot signature, the next three are valid.
+        block[39..43].copy_from_slice(&self.serial.to_le_bytes());
+        block[43..54].copy_from_slice(&label(self.label));
+        block[54..62].copy_from_slice(b"FAT12   ");
+        block[510..512].copy_from_slice(&[0x55, 0xAA]);
+    }
+
+    // Sector of the FAT, entries are 12 bits and packed two to three bytes.
+    fn fat_sector(&self, sector: u32, block: &mut [u8; BLOCK_SIZE]) {
+        let sizes = self.sizes();
+        let first = sector as usize * BLOCK_SIZE;
+        for (i, byte) in block.iter_mut().enumerate() {
+            let offset = first + i;
+            let pair = (offset / 3 * 2) as u32;
+            let (a, b) = (
+                self.fat_entry(&sizes, pair),
+                self.fat_entry(&sizes, pair + 1),
+            );
+            *byte = match offset % 3 {
+                0 => a as u8,
+                1 => (a >> 8) as u8 & 0x0F | (b << 4) as u8,
+                _ => (b >> 4) as u8,
+            };
+        }
+    }
+
+    fn root_directory(&self, block: &mut [u8; BLOCK_SIZE]) {
+        let (volume, entries) = block.split_at_mut(DIR_ENTRY_SIZE);
+        dir_entry(volume, &label(self.label), ATTR_VOLUME_ID, 0, 0);
+
+        for (n, file) in self.files().iter().enumerate() {
+            let size = self.size(n);
+            let cluster = if size == 0 {
+                0
+            } else {
+                2 + n as u32 * FILE_CLUSTERS
+            };
+            let entry = &mut entries[n * DIR_ENTRY_SIZE..(n + 1) * DIR_ENTRY_SIZE];
+            dir_entry(
+                entry,
+                &short_name(file.name),
+                ATTR_READ_ONLY,
+                cluster as u16,
+                size,
+            );
+        }
+    }
+
+    fn data(&self, cluster: u32, block: &mut [u8; BLOCK_SIZE]) {
+        let n = (cluster / FILE_CLUSTERS) as usize;
+        if n < self.files().len() {
+            let start = (cluster % FILE_CLUSTERS) as usize * BLOCK_SIZE;
+            let mut window = Window::new(block, start);
+            (self.files()[n].generate)(&self.context, &mut window).ok();
+        }
+    }
+}
+
+impl<C> BlockDevice for VirtualFat<C> {
+    fn block_count(&self) -> u32 {
+        SECTORS
+    }
+
+    fn read_block(&mut self, lba: u32, block: &mut [u8; BLOCK_SIZE]) -> Result<(), BlockError> {
+        block.iter_mut().for_each(|b| *b = 0);
+        match lba {
+            0 => self.boot_sector(block),
+            _ if lba < ROOT_START => self.fat_sector((lba - RESERVED_SECTORS) % FAT_SECTORS, block),
+            _ if lba < DATA_START => self.root_directory(block),
+            _ if lba < SECTORS => self.data(lba - DATA_START, block),
+            _ => return Err(BlockError::OutOfRange),
+        }
+        Ok(())
+    }
+
+    fn write_block(&mut self, lba: u32, block: &[u8; BLOCK_SIZE]) -> Result<(), BlockError> {
+        match self.write {
+            _ if lba >= SECTORS => Err(BlockError::OutOfRange),
+            Some(write) => write(&mut self.context, lba, block),
+            None => Err(BlockError::ReadOnly),
+        }
+    }
+
+    fn is_read_only(&self) -> bool {
+        self.write.is_none()
+    }
+}
+
+fn clusters(size: u32) -> u32 {
+    size.div_ceil(BLOCK_SIZE as u32)
+}
+
+// Space padded, upper case.
+fn pad(field: &mut [u8], s: &str) {
+    for (i, b) in field.iter_mut().enumerate() {
+        *b = s.as_bytes().get(i).unwrap_or(&b' ').to_ascii_uppercase();
+    }
+}
+
+fn label(label: &str) -> [u8; 11] {
+    let mut field = [0; 11];
+    pad(&mut field, label);
+    field
+}
+
+// "INFO.TXT" to "INFO    TXT".
+fn short_name(name: &str) -> [u8; 11] {
+    let (base, extension) = match name.find('.') {
+        Some(dot) => (&name[..dot], &name[dot + 1..]),
+        None => (name, ""),
+    };
+    let mut field = [0; 11];
+    pad(&mut field[..8], base);
+    pad(&mut field[8..], extension);
+    field
+}
+
+fn dir_entry(entry: &mut [u8], name: &[u8; 11], attributes: u8, cluster: u16, size: u32) {
+    entry[0..11].copy_from_slice(name);
+    entry[11] = attributes;
+    entry[14..16].copy_from_slice(&TIME.to_le_bytes()); // Created.
+    entry[16..18].copy_from_slice(&DATE.to_le_bytes());
+    entry[18..20].copy_from_slice(&DATE.to_le_bytes()); // Accessed.
+    entry[22..24].copy_from_slice(&TIME.to_le_bytes()); // Written.
+    entry[24..26].copy_from_slice(&DATE.to_le_bytes());
+    entry[26..28].copy_from_slice(&cluster.to_le_bytes());
+    entry[28..32].copy_from_slice(&size.to_le_bytes());
+}
+
+// Takes the bytes of a file from start on, as many as buf holds, and counts how long it
+// is. Formatting is stopped once there is nothing more to do: buf is full, or the file
+// has hit MAX_FILE_SIZE when only counting.
+struct Window<'a> {
+    buf: &'a mut [u8],
+    start: usize,
+    end: usize,
+}
+
+impl<'a> Window<'a> {
+    fn new(buf: &'a mut [u8], start: usize) -> Self {
+        Window { buf, start, end: 0 }
+    }
+}
+
+impl fmt::Write for Window<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &b in s.as_bytes() {
+            if self.end == MAX_FILE_SIZE {
+                return Err(fmt::Error);
+            }
+            if let Some(byte) = self
+                .end
+                .checked_sub(self.start)
+                .and_then(|i| self.buf.get_mut(i))
+            {
+                *byte = b;
+            } else if !self.buf.is_empty() && self.end >= self.start {
+                return Err(fmt::Error);
+            }
+            self.end += 1;
+        }
+        Ok(())
+    }
+}
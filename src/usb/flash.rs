@@ -0,0 +1,97 @@
+#![allow(dead_code)]
+
+// The F072's flash as memory.x splits it. The firmware runs from the image half, an
+// update is written to the staging half while the old one keeps running, and install
+// copies the pages that were written over the image. Update images are linked for the
+// image half like any other build.
+pub const PAGE_SIZE: u32 = 2048;
+pub const IMAGE_START: u32 = 0x0800_0000;
+pub const IMAGE_SIZE: u32 = 64 * 1024;
+pub const STAGING_START: u32 = IMAGE_START + IMAGE_SIZE;
+pub const PAGES: u32 = IMAGE_SIZE / PAGE_SIZE;
+
+// A bit per page in a u32.
+const _: () = assert!(PAGES <= 32);
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum FlashError {
+    // Outside the image, or not on a half word.
+    Address,
+    Erase,
+    // Flash that wasn't erased, or write protected.
+    Program,
+}
+
+pub trait Flash {
+    // The page at address, which is page aligned.
+    fn erase_page(&mut self, address: u32) -> Result<(), FlashError>;
+
+    // data to erased flash, address and length are half word aligned.
+    fn program(&mut self, address: u32, data: &[u8]) -> Result<(), FlashError>;
+
+    // Fill data from address on.
+    fn read(&mut self, address: u32, data: &mut [u8]) -> Result<(), FlashError>;
+
+    // Copy the staging pages set in pages (bit n for page n) over the image and restart.
+    // Doesn't return on the hardware.
+    fn install(&mut self, pages: u32);
+}
+
+// An update being staged. Addresses are where the data goes in the image, each page is
+// erased the first time it is written to.
+pub struct Update<F> {
+    flash: F,
+    pages: u32,
+}
+
+impl<F: Flash> Update<F> {
+    pub const fn new(flash: F) -> Self {
+        Update { flash, pages: 0 }
+    }
+
+    pub fn flash(&mut self) -> &mut F {
+        &mut self.flash
+    }
+
+    // Start over, nothing written so far is installed.
+    pub fn begin(&mut self) {
+        self.pages = 0;
+    }
+
+    // Pages written since begin, bit n for page n.
+    pub fn pages(&self) -> u32 {
+        self.pages
+    }
+
+    pub fn write(&mut self, address: u32, data: &[u8]) -> Result<(), FlashError> {
+        let offset = address.wrapping_sub(IMAGE_START);
+        let len = data.len() as u32;
+        if offset >= IMAGE_SIZE || len > IMAGE_SIZE - offset || (offset | len) & 1 != 0 {
+            return Err(FlashError::Address);
+        }
+        if len == 0 {
+            return Ok(());
+        }
+
+        for page in offset / PAGE_SIZE..=(offset + len - 1) / PAGE_SIZE {
+            if self.pages & (1 << page) == 0 {
+                self.flash.erase_page(STAGING_START + page * PAGE_SIZE)?;
+                self.pages |= 1 << page;
+            }
+        }
+        self.flash.program(STAGING_START + offset, data)
+    }
+
+    // What is in the image now, not the update. Reading nothing at the end is fine.
+    pub fn read(&mut self, address: u32, data: &mut [u8]) -> Result<(), FlashError> {
+        let offset = address.wrapping_sub(IMAGE_START);
+        if offset > IMAGE_SIZE || data.len() as u32 > IMAGE_SIZE - offset {
+            return Err(FlashError::Address);
+        }
+        self.flash.read(address, data)
+    }
+
+    pub fn install(&mut self) {
+        self.flash.install(self.pages);
+    }
+}
@@ -0,0 +1,171 @@
+#![allow(dead_code)]
+
+// Message framing for the raw HID channel, see raw_hid. Messages of up to MAX_MESSAGE
+// bytes are split in to 64 byte reports:
+//
+//   byte 0     sequence number, one more than the last report in the same direction
+//   byte 1     START on the first report of a message, END on the last, both on a
+//              message that fits one report
+//   byte 2     payload bytes in this report, 0 - PAYLOAD_SIZE
+//   byte 3..   payload, zero padded
+//
+// A gap in the sequence numbers means a report was lost, the message it was part of is
+// dropped. Nothing here touches the hardware, the host codec in host/ builds this same
+// file.
+
+pub const REPORT_SIZE: usize = 64;
+pub const HEADER_SIZE: usize = 3;
+pub const PAYLOAD_SIZE: usize = REPORT_SIZE - HEADER_SIZE;
+
+// Largest message either side takes, what the device has room to reassemble.
+pub const MAX_MESSAGE: usize = 512;
+
+pub const START: u8 = 0x01;
+pub const END: u8 = 0x02;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum FrameError {
+    // Payload length bigger than the report.
+    Length(u8),
+    // A report went missing, the message in progress is dropped.
+    Sequence { expected: u8, got: u8 },
+    // A report without START while no message is in progress.
+    NoStart,
+    // The message is bigger than the reassembly buffer, it is dropped.
+    Overflow,
+}
+
+// Sending side of one direction.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Framer {
+    seq: u8,
+}
+
+impl Framer {
+    pub const fn new() -> Self {
+        Framer { seq: 0 }
+    }
+
+    pub fn reset(&mut self) {
+        self.seq = 0;
+    }
+
+    // Fill report with the part of message from offset on. Returns the offset of the next
+    // report, message.len() once the last one is out:
+    //
+    //   let mut offset = 0;
+    //   loop {
+    //       offset = framer.frame(message, offset, &mut report);
+    //       send(&report);
+    //       if offset == message.len() { break; }
+    //   }
+    //
+    // An empty message is one report with START and END.
+    pub fn frame(
+        &mut self,
+        message: &[u8],
+        offset: usize,
+        report: &mut [u8; REPORT_SIZE],
+    ) -> usize {
+        let len = core::cmp::min(message.len() - offset, PAYLOAD_SIZE);
+        let next = offset + len;
+
+        let mut flags = 0;
+        if offset == 0 {
+            flags |= START;
+        }
+        if next == message.len() {
+            flags |= END;
+        }
+
+        report[0] = self.seq;
+        report[1] = flags;
+        report[2] = len as u8;
+        report[HEADER_SIZE..HEADER_SIZE + len].copy_from_slice(&message[offset..next]);
+        for b in report[HEADER_SIZE + len..].iter_mut() {
+            *b = 0;
+        }
+
+        self.seq = self.seq.wrapping_add(1);
+        next
+    }
+}
+
+// Receiving side of one direction, reassembles messages of up to N bytes.
+#[derive(Debug, Copy, Clone)]
+pub struct Deframer<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+    // Next sequence number, None until the first report.
+    seq: Option<u8>,
+    // Between START and END.
+    active: bool,
+}
+
+impl<const N: usize> Default for Deframer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Deframer<N> {
+    pub const fn new() -> Self {
+        Deframer {
+            buf: [0; N],
+            len: 0,
+            seq: None,
+            active: false,
+        }
+    }
+
+    // Both ends start over, the next report sets the sequence number.
+    pub fn reset(&mut self) {
+        self.len = 0;
+        self.seq = None;
+        self.active = false;
+    }
+
+    // Take one report. Some(len) once a message is complete, it stays in message() until
+    // the next push. A report that fails is dropped along with the message in progress.
+    pub fn push(&mut self, report: &[u8]) -> Result<Option<usize>, FrameError> {
+        if report.len() < HEADER_SIZE {
+            return Err(FrameError::Length(0));
+        }
+        let (seq, flags, len) = (report[0], report[1], report[2] as usize);
+        if len > PAYLOAD_SIZE || HEADER_SIZE + len > report.len() {
+            return Err(FrameError::Length(len as u8));
+        }
+
+        let expected = self.seq.unwrap_or(seq);
+        self.seq = Some(seq.wrapping_add(1));
+        if seq != expected {
+            self.active = false;
+            return Err(FrameError::Sequence { expected, got: seq });
+        }
+
+        if flags & START != 0 {
+            self.active = true;
+            self.len = 0;
+        } else if !self.active {
+            return Err(FrameError::NoStart);
+        }
+
+        if self.len + len > N {
+            self.active = false;
+            return Err(FrameError::Overflow);
+        }
+        self.buf[self.len..self.len + len].copy_from_slice(&report[HEADER_SIZE..HEADER_SIZE + len]);
+        self.len += len;
+
+        if flags & END != 0 {
+            self.active = false;
+            return Ok(Some(self.len));
+        }
+        Ok(None)
+    }
+
+    // The last complete message.
+    pub fn message(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
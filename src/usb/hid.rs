@@ -0,0 +1,515 @@
+#![allow(non_snake_case)]
+#![allow(dead_code)]
+
+use core::cmp::min;
+
+use crate::usb::class::UsbClass;
+use crate::usb::constants::{Destination, Direction, Type, UsbDescriptorType, UsbRequest};
+use crate::usb::control::{ControlResponse, SetupPacket};
+use crate::usb::descriptors::{hi, lo};
+use crate::usb::endpoint::EndpointError;
+use crate::usb::{Usb, USB};
+
+// HID 1.11 4.1 - 4.3 interface class codes.
+pub const CLASS_HID: u8 = 0x03;
+pub const SUBCLASS_NONE: u8 = 0x00;
+pub const SUBCLASS_BOOT: u8 = 0x01; // Usable by the BIOS, see the boot protocol.
+pub const PROTOCOL_NONE: u8 = 0x00;
+pub const PROTOCOL_KEYBOARD: u8 = 0x01;
+pub const PROTOCOL_MOUSE: u8 = 0x02;
+
+// HID 1.11 6.2.1 class descriptor, with the one report descriptor. Goes in the
+// interface's other_descriptors, between the interface and its endpoints.
+pub const fn descriptor(bcdHID: u16, bCountryCode: u8, wDescriptorLength: u16) -> [u8; 9] {
+    [
+        9,
+        UsbDescriptorType::Hid as u8,
+        lo(bcdHID),
+        hi(bcdHID),
+        bCountryCode,
+        1, // bNumDescriptors.
+        UsbDescriptorType::HidReport as u8,
+        lo(wDescriptorLength),
+        hi(wDescriptorLength),
+    ]
+}
+
+// HID Usage Tables 1.12 3.
+#[derive(Debug, Copy, Clone)]
+pub enum UsagePage {
+    GenericDesktop,
+    Keyboard,
+    Led,
+    Button,
+    Consumer,
+    Vendor(u8), // 0xFF00 - 0xFFFF.
+}
+
+impl UsagePage {
+    pub const fn to_bits(self) -> u16 {
+        match self {
+            UsagePage::GenericDesktop => 0x01,
+            UsagePage::Keyboard => 0x07,
+            UsagePage::Led => 0x08,
+            UsagePage::Button => 0x09,
+            UsagePage::Consumer => 0x0C,
+            UsagePage::Vendor(page) => 0xFF00 | page as u16,
+        }
+    }
+}
+
+// HID 1.11 6.2.2.6.
+#[repr(u8)]
+#[derive(Debug, Copy, Clone)]
+pub enum Collection {
+    Physical = 0x00,
+    Application = 0x01,
+    Logical = 0x02,
+    Report = 0x03,
+    NamedArray = 0x04,
+    UsageSwitch = 0x05,
+    UsageModifier = 0x06,
+}
+
+// HID 1.11 6.2.2.5 Input, Output and Feature item bits, or'd together. The zero
+// values are there to spell out the default.
+pub const DATA: u8 = 0x00;
+pub const CONSTANT: u8 = 0x01;
+pub const ARRAY: u8 = 0x00;
+pub const VARIABLE: u8 = 0x02;
+pub const ABSOLUTE: u8 = 0x00;
+pub const RELATIVE: u8 = 0x04;
+pub const WRAP: u8 = 0x08;
+pub const NON_LINEAR: u8 = 0x10;
+pub const NO_PREFERRED: u8 = 0x20;
+pub const NULL_STATE: u8 = 0x40;
+pub const VOLATILE: u8 = 0x80; // Output and Feature only.
+
+// HID 1.11 6.2.2.2 short item bType.
+const MAIN: u8 = 0;
+const GLOBAL: u8 = 1;
+const LOCAL: u8 = 2;
+
+// Builds a report descriptor at compile time, in to a buffer of N bytes:
+//
+//   static REPORT: ReportDescriptor<32> = ReportDescriptor::new()
+//       .usage_page(UsagePage::GenericDesktop)
+//       .usage(0x06) // Keyboard.
+//       .collection(Collection::Application)
+//       ...
+//       .end_collection();
+//
+// Items are written in the shortest encoding for their value. Running out of room or
+// ending a collection that wasn't opened fails the build.
+#[derive(Debug, Copy, Clone)]
+pub struct ReportDescriptor<const N: usize> {
+    bytes: [u8; N],
+    len: usize,
+    depth: u8, // Open collections.
+}
+
+impl<const N: usize> ReportDescriptor<N> {
+    pub const fn new() -> Self {
+        Self {
+            bytes: [0; N],
+            len: 0,
+            depth: 0,
+        }
+    }
+
+    pub const fn len(&self) -> usize {
+        self.len
+    }
+
+    pub const fn as_bytes(&self) -> &[u8] {
+        self.bytes.split_at(self.len).0
+    }
+
+    // Global items.
+
+    pub const fn usage_page(&self, page: UsagePage) -> Self {
+        self.unsigned(GLOBAL, 0x0, page.to_bits() as u32)
+    }
+
+    pub const fn logical_minimum(&self, min: i32) -> Self {
+        self.signed(GLOBAL, 0x1, min)
+    }
+
+    pub const fn logical_maximum(&self, max: i32) -> Self {
+        self.signed(GLOBAL, 0x2, max)
+    }
+
+    pub const fn physical_minimum(&self, min: i32) -> Self {
+        self.signed(GLOBAL, 0x3, min)
+    }
+
+    pub const fn physical_maximum(&self, max: i32) -> Self {
+        self.signed(GLOBAL, 0x4, max)
+    }
+
+    // Bits per field.
+    pub const fn report_size(&self, bits: u8) -> Self {
+        self.unsigned(GLOBAL, 0x7, bits as u32)
+    }
+
+    // Every report after this starts with id, which has to be nonzero.
+    pub const fn report_id(&self, id: u8) -> Self {
+        if id == 0 {
+            panic!("report id 0 is reserved");
+        }
+        self.unsigned(GLOBAL, 0x8, id as u32)
+    }
+
+    pub const fn report_count(&self, count: u8) -> Self {
+        self.unsigned(GLOBAL, 0x9, count as u32)
+    }
+
+    // Local items, they only apply to the next main item.
+
+    pub const fn usage(&self, usage: u16) -> Self {
+        self.unsigned(LOCAL, 0x0, usage as u32)
+    }
+
+    pub const fn usage_minimum(&self, min: u16) -> Self {
+        self.unsigned(LOCAL, 0x1, min as u32)
+    }
+
+    pub const fn usage_maximum(&self, max: u16) -> Self {
+        self.unsigned(LOCAL, 0x2, max as u32)
+    }
+
+    // Main items.
+
+    pub const fn input(&self, flags: u8) -> Self {
+        self.unsigned(MAIN, 0x8, flags as u32)
+    }
+
+    pub const fn output(&self, flags: u8) -> Self {
+        self.unsigned(MAIN, 0x9, flags as u32)
+    }
+
+    pub const fn feature(&self, flags: u8) -> Self {
+        self.unsigned(MAIN, 0xB, flags as u32)
+    }
+
+    pub const fn collection(&self, collection: Collection) -> Self {
+        let d = self.unsigned(MAIN, 0xA, collection as u32);
+        Self {
+            depth: d.depth + 1,
+            ..d
+        }
+    }
+
+    pub const fn end_collection(&self) -> Self {
+        if self.depth == 0 {
+            panic!("end_collection without a collection");
+        }
+        let d = self.item(MAIN, 0xC, 0, 0);
+        Self {
+            depth: d.depth - 1,
+            ..d
+        }
+    }
+
+    const fn unsigned(&self, bType: u8, bTag: u8, data: u32) -> Self {
+        let size = if data <= 0xff {
+            1
+        } else if data <= 0xffff {
+            2
+        } else {
+            4
+        };
+        self.item(bType, bTag, data, size)
+    }
+
+    const fn signed(&self, bType: u8, bTag: u8, data: i32) -> Self {
+        let size = if data >= -0x80 && data <= 0x7f {
+            1
+        } else if data >= -0x8000 && data <= 0x7fff {
+            2
+        } else {
+            4
+        };
+        self.item(bType, bTag, data as u32, size)
+    }
+
+    // bSize 3 means 4 bytes.
+    const fn item(&self, bType: u8, bTag: u8, data: u32, size: usize) -> Self {
+        let bSize = if size == 4 { 3 } else { size as u8 };
+        let mut d = self.byte(bTag << 4 | bType << 2 | bSize);
+        let mut i = 0;
+        while i < size {
+            d = d.byte((data >> (8 * i)) as u8);
+            i += 1;
+        }
+        d
+    }
+
+    const fn byte(&self, b: u8) -> Self {
+        if self.len == N {
+            panic!("report descriptor buffer too small");
+        }
+        let mut bytes = self.bytes;
+        bytes[self.len] = b;
+        Self {
+            bytes,
+            len: self.len + 1,
+            ..*self
+        }
+    }
+}
+
+// HID 1.11 7.2 class requests.
+const GET_REPORT: u8 = 0x01;
+const GET_IDLE: u8 = 0x02;
+const GET_PROTOCOL: u8 = 0x03;
+const SET_REPORT: u8 = 0x09;
+const SET_IDLE: u8 = 0x0A;
+const SET_PROTOCOL: u8 = 0x0B;
+
+// GET_REPORT/SET_REPORT wValue high byte.
+const REPORT_INPUT: u8 = 0x01;
+const REPORT_OUTPUT: u8 = 0x02;
+const REPORT_FEATURE: u8 = 0x03;
+
+// Full speed interrupt endpoints carry at most 64 bytes a packet, and reports are sent
+// as one packet.
+pub const MAX_REPORT: usize = 64;
+
+#[repr(u8)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Protocol {
+    Boot = 0,
+    Report = 1,
+}
+
+// The last report of one kind. With report ids in use the id is the first byte.
+#[derive(Debug, Copy, Clone)]
+struct Report {
+    data: [u8; MAX_REPORT],
+    len: usize,
+}
+
+impl Report {
+    const fn new() -> Self {
+        Report {
+            data: [0; MAX_REPORT],
+            len: 0,
+        }
+    }
+
+    fn set(&mut self, data: &[u8]) {
+        let len = min(data.len(), MAX_REPORT);
+        self.data[..len].copy_from_slice(&data[..len]);
+        self.len = len;
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+
+    // GET_REPORT id 0 means the device doesn't use ids.
+    fn matches(&self, id: u8) -> bool {
+        self.len > 0 && (id == 0 || self.data[0] == id)
+    }
+}
+
+// HID interface with an interrupt IN endpoint. Output reports come in with SET_REPORT
+// on the control pipe, there is no interrupt OUT endpoint.
+pub struct Hid {
+    interface: u8,
+    endpoint_in: u8,
+    descriptor: &'static [u8],
+    report_descriptor: &'static [u8],
+    protocol: Protocol,
+    // SET_IDLE duration in 4ms units, 0 only sends reports that changed.
+    idle: u8,
+    // Frame the last input report went out in, for the idle repeat.
+    sent_frame: u16,
+    input: Report,
+    output: Report,
+    output_ready: bool,
+    feature: Report,
+}
+
+impl Hid {
+    // descriptor is the class descriptor from hid::descriptor, as in the configuration.
+    pub const fn new(
+        interface: u8,
+        endpoint_in: u8,
+        descriptor: &'static [u8],
+        report_descriptor: &'static [u8],
+    ) -> Self {
+        Hid {
+            interface,
+            endpoint_in,
+            descriptor,
+            report_descriptor,
+            protocol: Protocol::Report,
+            idle: 0,
+            sent_frame: 0,
+            input: Report::new(),
+            output: Report::new(),
+            output_ready: false,
+            feature: Report::new(),
+        }
+    }
+
+    // Boot protocol devices have to send the fixed boot report layout when it is Boot.
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    pub fn idle(&self) -> u8 {
+        self.idle
+    }
+
+    // Send an input report on the interrupt endpoint. WouldBlock until the host has
+    // taken the last one. The report is also what GET_REPORT and the idle repeat send.
+    pub fn send_report<PINS>(
+        &mut self,
+        usb: &mut Usb<USB, PINS>,
+        report: &[u8],
+    ) -> Result<usize, EndpointError> {
+        if report.len() > MAX_REPORT {
+            return Err(EndpointError::BufferOverflow);
+        }
+        let count = usb.write(self.endpoint_in, report)?;
+        self.input.set(report);
+        self.sent_frame = usb.frame_number();
+        Ok(count)
+    }
+
+    // The last output report from the host, once.
+    pub fn read_output(&mut self, buf: &mut [u8]) -> Option<usize> {
+        if !self.output_ready {
+            return None;
+        }
+        self.output_ready = false;
+        let len = min(buf.len(), self.output.len);
+        buf[..len].copy_from_slice(&self.output.as_bytes()[..len]);
+        Some(len)
+    }
+
+    // The feature report the host reads with GET_REPORT, and the last one it set.
+    pub fn set_feature(&mut self, report: &[u8]) {
+        self.feature.set(report);
+    }
+
+    pub fn feature(&self) -> &[u8] {
+        self.feature.as_bytes()
+    }
+
+    // Repeat the last input report every idle period, HID 1.11 7.2.4. Call it at least
+    // once a millisecond while idle is nonzero.
+    pub fn poll<PINS>(&mut self, usb: &mut Usb<USB, PINS>) {
+        if self.idle == 0 || self.input.len == 0 {
+            return;
+        }
+
+        // The frame number is 11 bits, the longest idle period is 1020ms.
+        let frame = usb.frame_number();
+        let elapsed = frame.wrapping_sub(self.sent_frame) & 0x07ff;
+        if elapsed >= self.idle as u16 * 4
+            && usb.write(self.endpoint_in, self.input.as_bytes()).is_ok()
+        {
+            self.sent_frame = frame;
+        }
+    }
+
+    fn get_descriptor(&self, setup: &SetupPacket, data: &mut [u8]) -> ControlResponse {
+        let bytes = match UsbDescriptorType::from_bits(hi(setup.wValue)) {
+            Some(UsbDescriptorType::Hid) => self.descriptor,
+            Some(UsbDescriptorType::HidReport) => self.report_descriptor,
+            _ => return ControlResponse::Stall,
+        };
+        let len = min(bytes.len(), data.len());
+        data[..len].copy_from_slice(&bytes[..len]);
+        ControlResponse::Data(len)
+    }
+
+    fn get_report(&self, setup: &SetupPacket, data: &mut [u8]) -> ControlResponse {
+        let report = match hi(setup.wValue) {
+            REPORT_INPUT => &self.input,
+            REPORT_FEATURE => &self.feature,
+            _ => return ControlResponse::Stall,
+        };
+        if !report.matches(lo(setup.wValue)) {
+            return ControlResponse::Stall;
+        }
+        data[..report.len].copy_from_slice(report.as_bytes());
+        ControlResponse::Data(report.len)
+    }
+
+    fn set_report(&mut self, setup: &SetupPacket, data: &[u8]) -> ControlResponse {
+        match hi(setup.wValue) {
+            REPORT_OUTPUT => {
+                self.output.set(data);
+                self.output_ready = true;
+            }
+            REPORT_FEATURE => self.feature.set(data),
+            _ => return ControlResponse::Stall,
+        }
+        ControlResponse::Accept
+    }
+}
+
+impl UsbClass for Hid {
+    fn control(&mut self, setup: &SetupPacket, data: &mut [u8]) -> Option<ControlResponse> {
+        if !matches!(setup.destination(), Some(Destination::Interface))
+            || setup.wIndex as u8 != self.interface
+        {
+            return None;
+        }
+
+        let response = match (setup.request_type(), setup.direction(), setup.bRequest) {
+            // The class and report descriptors are fetched from the interface, the rest
+            // of the standard interface requests are left to Usb.
+            (Some(Type::Standard), Some(Direction::IN), _) => match setup.request() {
+                Some(UsbRequest::GetDescriptor) => self.get_descriptor(setup, data),
+                _ => return None,
+            },
+            (Some(Type::Standard), _, _) => return None,
+
+            (Some(Type::Class), Some(Direction::IN), GET_REPORT) => self.get_report(setup, data),
+
+            (Some(Type::Class), Some(Direction::OUT), SET_REPORT) => self.set_report(setup, data),
+
+            (Some(Type::Class), Some(Direction::IN), GET_IDLE) => {
+                data[0] = self.idle;
+                ControlResponse::Data(1)
+            }
+
+            // wValue high byte is the duration, low byte the report id. One rate is kept
+            // for all reports.
+            (Some(Type::Class), Some(Direction::OUT), SET_IDLE) => {
+                self.idle = hi(setup.wValue);
+                ControlResponse::Accept
+            }
+
+            (Some(Type::Class), Some(Direction::IN), GET_PROTOCOL) => {
+                data[0] = self.protocol as u8;
+                ControlResponse::Data(1)
+            }
+
+            (Some(Type::Class), Some(Direction::OUT), SET_PROTOCOL) => {
+                self.protocol = match setup.wValue {
+                    0 => Protocol::Boot,
+                    _ => Protocol::Report,
+                };
+                ControlResponse::Accept
+            }
+
+            _ => ControlResponse::Stall,
+        };
+        Some(response)
+    }
+
+    // HID 1.11 7.2.6, devices come out of reset in the report protocol.
+    fn reset(&mut self) {
+        self.protocol = Protocol::Report;
+        self.idle = 0;
+        self.input = Report::new();
+        self.output_ready = false;
+    }
+}
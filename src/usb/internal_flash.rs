@@ -0,0 +1,170 @@
+use core::ptr::{read_volatile, write_volatile};
+
+use hal::stm32::{flash::RegisterBlock, FLASH};
+
+use crate::usb::flash::{Flash, FlashError, IMAGE_START, PAGES, PAGE_SIZE, STAGING_START};
+
+// RM0091 3.5, the flash interface registers.
+const KEY1: u32 = 0x4567_0123;
+const KEY2: u32 = 0xCDEF_89AB;
+
+const SR_BSY: u32 = 1 << 0;
+const SR_PGERR: u32 = 1 << 2;
+const SR_WRPRTERR: u32 = 1 << 4;
+const SR_EOP: u32 = 1 << 5;
+
+const CR_PG: u32 = 1 << 0;
+const CR_PER: u32 = 1 << 1;
+const CR_STRT: u32 = 1 << 6;
+const CR_LOCK: u32 = 1 << 7;
+
+// SCB AIRCR with VECTKEY and SYSRESETREQ.
+const AIRCR: *mut u32 = 0xE000_ED0C as *mut u32;
+const AIRCR_SYSRESETREQ: u32 = 0x05FA_0004;
+
+// The on-chip flash. Erasing and programming is limited to the staging half, the image
+// half is only written by install. Copies share the one controller, which is fine as
+// long as they are only used in critical sections, one operation at a time.
+#[derive(Copy, Clone)]
+pub struct InternalFlash {
+    flash: &'static RegisterBlock,
+}
+
+impl InternalFlash {
+    pub fn new(_flash: FLASH) -> Self {
+        InternalFlash {
+            flash: unsafe { &*FLASH::ptr() },
+        }
+    }
+
+    fn unlock(&self) {
+        if self.flash.cr.read().bits() & CR_LOCK != 0 {
+            self.flash.keyr.write(|w| unsafe { w.bits(KEY1) });
+            self.flash.keyr.write(|w| unsafe { w.bits(KEY2) });
+        }
+    }
+
+    fn lock(&self) {
+        self.flash.cr.write(|w| unsafe { w.bits(CR_LOCK) });
+    }
+
+    // Wait for the operation to finish, true if it did without an error. The status
+    // bits are cleared for the next one.
+    fn wait(&self) -> bool {
+        while self.flash.sr.read().bits() & SR_BSY != 0 {}
+        let sr = self.flash.sr.read().bits();
+        self.flash
+            .sr
+            .write(|w| unsafe { w.bits(SR_EOP | SR_PGERR | SR_WRPRTERR) });
+        sr & (SR_PGERR | SR_WRPRTERR) == 0
+    }
+}
+
+fn in_staging(address: u32, len: u32) -> bool {
+    address >= STAGING_START && address - STAGING_START + len <= PAGES * PAGE_SIZE
+}
+
+impl Flash for InternalFlash {
+    fn erase_page(&mut self, address: u32) -> Result<(), FlashError> {
+        if !in_staging(address, PAGE_SIZE) || address % PAGE_SIZE != 0 {
+            return Err(FlashError::Address);
+        }
+        self.unlock();
+        self.flash.cr.write(|w| unsafe { w.bits(CR_PER) });
+        self.flash.ar.write(|w| unsafe { w.bits(address) });
+        self.flash.cr.write(|w| unsafe { w.bits(CR_PER | CR_STRT) });
+        let ok = self.wait();
+        self.lock();
+        if ok {
+            Ok(())
+        } else {
+            Err(FlashError::Erase)
+        }
+    }
+
+    fn program(&mut self, address: u32, data: &[u8]) -> Result<(), FlashError> {
+        if !in_staging(address, data.len() as u32) || (address | data.len() as u32) & 1 != 0 {
+            return Err(FlashError::Address);
+        }
+        self.unlock();
+        self.flash.cr.write(|w| unsafe { w.bits(CR_PG) });
+        let mut ok = true;
+        for (i, half) in data.chunks(2).enumerate() {
+            let target = (address as usize + i * 2) as *mut u16;
+            unsafe { write_volatile(target, u16::from_le_bytes([half[0], half[1]])) };
+            ok = self.wait();
+            if !ok {
+                break;
+            }
+        }
+        self.lock();
+        if ok {
+            Ok(())
+        } else {
+            Err(FlashError::Program)
+        }
+    }
+
+    fn read(&mut self, address: u32, data: &mut [u8]) -> Result<(), FlashError> {
+        let len = data.len() as u32;
+        if address < IMAGE_START || address - IMAGE_START + len > 2 * PAGES * PAGE_SIZE {
+            return Err(FlashError::Address);
+        }
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = unsafe { read_volatile((address as usize + i) as *const u8) };
+        }
+        Ok(())
+    }
+
+    fn install(&mut self, pages: u32) {
+        cortex_m::interrupt::disable();
+        self.unlock();
+        let regs = self.flash;
+        unsafe {
+            copy_pages(
+                &regs.sr as *const _ as *mut u32,
+                &regs.cr as *const _ as *mut u32,
+                &regs.ar as *const _ as *mut u32,
+                pages,
+            )
+        }
+    }
+}
+
+// Copies the staged pages over the image and resets. It runs from RAM as the flash it
+// would normally run from is being rewritten, so it can't call anything in flash either:
+// registers and flash are only touched with volatile accesses, and there are no
+// iterators. Losing power part way leaves a broken image, the ROM bootloader (BOOT0)
+// gets the board back.
+#[inline(never)]
+#[link_section = ".data.install"]
+unsafe fn copy_pages(sr: *mut u32, cr: *mut u32, ar: *mut u32, pages: u32) -> ! {
+    let mut page = 0;
+    while page < PAGES {
+        if pages & (1 << page) != 0 {
+            let image = IMAGE_START + page * PAGE_SIZE;
+            let staging = STAGING_START + page * PAGE_SIZE;
+
+            write_volatile(cr, CR_PER);
+            write_volatile(ar, image);
+            write_volatile(cr, CR_PER | CR_STRT);
+            while read_volatile(sr) & SR_BSY != 0 {}
+            write_volatile(sr, SR_EOP | SR_PGERR | SR_WRPRTERR);
+
+            write_volatile(cr, CR_PG);
+            let mut offset = 0;
+            while offset < PAGE_SIZE {
+                let half = read_volatile((staging + offset) as *const u16);
+                write_volatile((image + offset) as *mut u16, half);
+                while read_volatile(sr) & SR_BSY != 0 {}
+                offset += 2;
+            }
+            write_volatile(sr, SR_EOP | SR_PGERR | SR_WRPRTERR);
+            write_volatile(cr, 0);
+        }
+        page += 1;
+    }
+
+    write_volatile(AIRCR, AIRCR_SYSRESETREQ);
+    loop {}
+}
@@ -0,0 +1,216 @@
+#![allow(dead_code)]
+
+use crate::usb::class::UsbClass;
+use crate::usb::control::{ControlResponse, SetupPacket};
+use crate::usb::hid::{self, Collection, Hid, ReportDescriptor, UsagePage};
+use crate::usb::hid::{ABSOLUTE, ARRAY, CONSTANT, DATA, VARIABLE};
+use crate::usb::{Usb, USB};
+
+// The boot keyboard report descriptor, HID 1.11 appendix B.1 / E.6. The report protocol
+// uses the same layout, so SET_PROTOCOL doesn't change what is sent.
+const BOOT_KEYBOARD: ReportDescriptor<63> = ReportDescriptor::new()
+    .usage_page(UsagePage::GenericDesktop)
+    .usage(0x06) // Keyboard.
+    .collection(Collection::Application)
+    // Modifier byte.
+    .usage_page(UsagePage::Keyboard)
+    .usage_minimum(0xE0)
+    .usage_maximum(0xE7)
+    .logical_minimum(0)
+    .logical_maximum(1)
+    .report_size(1)
+    .report_count(8)
+    .input(DATA | VARIABLE | ABSOLUTE)
+    // Reserved byte.
+    .report_count(1)
+    .report_size(8)
+    .input(CONSTANT)
+    // LED output report, 5 bits and 3 bits padding.
+    .report_count(5)
+    .report_size(1)
+    .usage_page(UsagePage::Led)
+    .usage_minimum(0x01)
+    .usage_maximum(0x05)
+    .output(DATA | VARIABLE | ABSOLUTE)
+    .report_count(1)
+    .report_size(3)
+    .output(CONSTANT)
+    // Six key codes.
+    .report_count(6)
+    .report_size(8)
+    .logical_minimum(0)
+    .logical_maximum(101)
+    .usage_page(UsagePage::Keyboard)
+    .usage_minimum(0x00)
+    .usage_maximum(101)
+    .input(DATA | ARRAY)
+    .end_collection();
+
+pub static REPORT_DESCRIPTOR: ReportDescriptor<63> = BOOT_KEYBOARD;
+
+// Class descriptor for the keyboard interface's other_descriptors.
+pub const DESCRIPTOR: [u8; 9] = hid::descriptor(0x0111, 0, BOOT_KEYBOARD.len() as u16);
+
+// Interface descriptor class, subclass and protocol.
+pub const SUBCLASS: u8 = hid::SUBCLASS_BOOT;
+pub const PROTOCOL: u8 = hid::PROTOCOL_KEYBOARD;
+
+// Output report bits.
+pub const LED_NUM_LOCK: u8 = 0x01;
+pub const LED_CAPS_LOCK: u8 = 0x02;
+pub const LED_SCROLL_LOCK: u8 = 0x04;
+
+// Modifier byte bits.
+pub const MOD_LEFT_CTRL: u8 = 0x01;
+pub const MOD_LEFT_SHIFT: u8 = 0x02;
+pub const MOD_LEFT_ALT: u8 = 0x04;
+pub const MOD_LEFT_GUI: u8 = 0x08;
+
+pub const REPORT_SIZE: usize = 8;
+
+// Modifiers, reserved, up to six keys down at once.
+pub const fn report(modifiers: u8, key: u8) -> [u8; REPORT_SIZE] {
+    [modifiers, 0, key, 0, 0, 0, 0, 0]
+}
+
+// Usage id and modifiers that type c on a US layout, HID Usage Tables 1.12 10. None for
+// characters there is no key for.
+pub const fn ascii_key(c: u8) -> Option<(u8, u8)> {
+    const SHIFT: u8 = MOD_LEFT_SHIFT;
+    let key = match c {
+        b'a'..=b'z' => (0, 0x04 + c - b'a'),
+        b'A'..=b'Z' => (SHIFT, 0x04 + c - b'A'),
+        b'1'..=b'9' => (0, 0x1E + c - b'1'),
+        b'0' => (0, 0x27),
+        b'\n' => (0, 0x28),
+        0x1b => (0, 0x29), // Escape.
+        0x08 => (0, 0x2A), // Backspace.
+        b'\t' => (0, 0x2B),
+        b' ' => (0, 0x2C),
+        b'!' => (SHIFT, 0x1E),
+        b'@' => (SHIFT, 0x1F),
+        b'#' => (SHIFT, 0x20),
+        b'$' => (SHIFT, 0x21),
+        b'%' => (SHIFT, 0x22),
+        b'^' => (SHIFT, 0x23),
+        b'&' => (SHIFT, 0x24),
+        b'*' => (SHIFT, 0x25),
+        b'(' => (SHIFT, 0x26),
+        b')' => (SHIFT, 0x27),
+        b'-' => (0, 0x2D),
+        b'_' => (SHIFT, 0x2D),
+        b'=' => (0, 0x2E),
+        b'+' => (SHIFT, 0x2E),
+        b'[' => (0, 0x2F),
+        b'{' => (SHIFT, 0x2F),
+        b']' => (0, 0x30),
+        b'}' => (SHIFT, 0x30),
+        b'\\' => (0, 0x31),
+        b'|' => (SHIFT, 0x31),
+        b';' => (0, 0x33),
+        b':' => (SHIFT, 0x33),
+        b'\'' => (0, 0x34),
+        b'"' => (SHIFT, 0x34),
+        b'`' => (0, 0x35),
+        b'~' => (SHIFT, 0x35),
+        b',' => (0, 0x36),
+        b'<' => (SHIFT, 0x36),
+        b'.' => (0, 0x37),
+        b'>' => (SHIFT, 0x37),
+        b'/' => (0, 0x38),
+        b'?' => (SHIFT, 0x38),
+        _ => return None,
+    };
+    Some(key)
+}
+
+// Boot protocol keyboard that types out strings, a key down report and an all keys up
+// report per character, and keeps the host's lock LEDs.
+pub struct Keyboard {
+    hid: Hid,
+    text: &'static [u8],
+    pos: usize,
+    // text[pos] is down, the next report releases it.
+    pressed: bool,
+    leds: u8,
+}
+
+impl Keyboard {
+    pub fn new(interface: u8, endpoint_in: u8) -> Self {
+        Keyboard {
+            hid: Hid::new(
+                interface,
+                endpoint_in,
+                &DESCRIPTOR,
+                REPORT_DESCRIPTOR.as_bytes(),
+            ),
+            text: &[],
+            pos: 0,
+            pressed: false,
+            leds: 0,
+        }
+    }
+
+    // Start typing text, false if the last string isn't done yet. Characters ascii_key
+    // doesn't know are skipped.
+    pub fn type_str(&mut self, text: &'static str) -> bool {
+        if self.is_typing() {
+            return false;
+        }
+        self.text = text.as_bytes();
+        self.pos = 0;
+        self.pressed = false;
+        true
+    }
+
+    pub fn is_typing(&self) -> bool {
+        self.pos < self.text.len()
+    }
+
+    // LED_* bits from the host's last output report.
+    pub fn leds(&self) -> u8 {
+        self.leds
+    }
+
+    // Pick up LED changes and send the next report. One report goes out per bInterval,
+    // call it from the main loop or after Usb::interrupt.
+    pub fn poll<PINS>(&mut self, usb: &mut Usb<USB, PINS>) {
+        let mut leds = [0u8; 1];
+        if let Some(1) = self.hid.read_output(&mut leds) {
+            self.leds = leds[0];
+        }
+
+        while self.is_typing() && !self.pressed && ascii_key(self.text[self.pos]).is_none() {
+            self.pos += 1;
+        }
+
+        if self.is_typing() {
+            let report = match (self.pressed, ascii_key(self.text[self.pos])) {
+                (false, Some((modifiers, key))) => report(modifiers, key),
+                _ => report(0, 0),
+            };
+            if self.hid.send_report(usb, &report).is_ok() {
+                if self.pressed {
+                    self.pos += 1;
+                }
+                self.pressed = !self.pressed;
+            }
+        }
+
+        self.hid.poll(usb);
+    }
+}
+
+impl UsbClass for Keyboard {
+    fn control(&mut self, setup: &SetupPacket, data: &mut [u8]) -> Option<ControlResponse> {
+        self.hid.control(setup, data)
+    }
+
+    fn reset(&mut self) {
+        self.hid.reset();
+        self.text = &[];
+        self.pos = 0;
+        self.pressed = false;
+        self.leds = 0;
+    }
+}
@@ -0,0 +1,130 @@
+#![allow(dead_code)]
+
+use crate::usb::block::BlockDevice;
+use crate::usb::bot::{Bot, PACKET_SIZE};
+use crate::usb::class::UsbClass;
+use crate::usb::constants::{Destination, Direction, Type, UsbRequest};
+use crate::usb::control::{ControlResponse, SetupPacket};
+use crate::usb::endpoint::Endpoints;
+use crate::usb::scsi::Scsi;
+
+// Mass Storage Class 1.4 interface codes.
+pub const CLASS_MSC: u8 = 0x08;
+pub const SUBCLASS_SCSI: u8 = 0x06; // SCSI transparent command set.
+pub const PROTOCOL_BOT: u8 = 0x50; // Bulk-Only Transport.
+
+// BOT 3.1 and 3.2 class requests.
+const BULK_ONLY_RESET: u8 = 0xFF;
+const GET_MAX_LUN: u8 = 0xFE;
+
+// Mass storage interface with a bulk endpoint each way, one LUN on a BlockDevice.
+pub struct MassStorage<B> {
+    interface: u8,
+    endpoint_in: u8,
+    endpoint_out: u8,
+    bot: Bot<B>,
+    // The transport wants the IN endpoint halted, once the host has its last packet.
+    stall_in: bool,
+}
+
+impl<B: BlockDevice> MassStorage<B> {
+    pub fn new(interface: u8, endpoint_in: u8, endpoint_out: u8, scsi: Scsi<B>) -> Self {
+        MassStorage {
+            interface,
+            endpoint_in,
+            endpoint_out,
+            bot: Bot::new(scsi),
+            stall_in: false,
+        }
+    }
+
+    pub fn scsi(&mut self) -> &mut Scsi<B> {
+        self.bot.scsi()
+    }
+
+    // Run the transport until it waits on the host. Call it after Usb::interrupt.
+    pub fn poll<E: Endpoints>(&mut self, usb: &mut E) {
+        let mut packet = [0u8; PACKET_SIZE];
+        loop {
+            let stalls = self.bot.take_stalls();
+            self.stall_in |= stalls.bulk_in;
+            if stalls.bulk_out {
+                usb.set_stalled(self.endpoint_out, true).ok();
+            }
+
+            // BOT 6.7 case 5, the short data goes out first and the halt ends it. Halting
+            // with the last packet still queued would drop it.
+            if self.stall_in && !usb.in_flight(self.endpoint_in).unwrap_or(false) {
+                usb.set_stalled(self.endpoint_in, true).ok();
+                self.stall_in = false;
+            }
+
+            let mut progress = false;
+
+            // A halted endpoint won't take it, the CSW waits until the host has cleared it.
+            if !self.stall_in {
+                if let Some(data) = self.bot.packet_in() {
+                    if usb.write(self.endpoint_in, data).is_ok() {
+                        self.bot.packet_sent();
+                        progress = true;
+                    }
+                }
+            }
+
+            if self.bot.wants_out() {
+                if let Ok(len) = usb.read(self.endpoint_out, &mut packet) {
+                    self.bot.packet_out(&packet[..len]);
+                    progress = true;
+                }
+            }
+
+            if !progress {
+                break;
+            }
+        }
+    }
+}
+
+impl<B: BlockDevice> UsbClass for MassStorage<B> {
+    fn control(&mut self, setup: &SetupPacket, data: &mut [u8]) -> Option<ControlResponse> {
+        match (setup.request_type(), setup.destination()) {
+            (Some(Type::Class), Some(Destination::Interface))
+                if setup.wIndex as u8 == self.interface => {}
+
+            // BOT 6.6.1, the halts from an invalid CBW stay on until the reset. The host
+            // is told they are cleared, that is its cue to reset.
+            (Some(Type::Standard), Some(Destination::Endpoint))
+                if self.bot.needs_reset()
+                    && matches!(setup.request(), Some(UsbRequest::ClearFeature))
+                    && (setup.wIndex as u8 == self.endpoint_in
+                        || setup.wIndex as u8 == self.endpoint_out) =>
+            {
+                return Some(ControlResponse::Accept)
+            }
+
+            _ => return None,
+        }
+
+        let response = match (setup.direction(), setup.bRequest) {
+            // Highest LUN number, there is one.
+            (Some(Direction::IN), GET_MAX_LUN) if setup.wValue == 0 => {
+                data[0] = 0;
+                ControlResponse::Data(1)
+            }
+
+            (Some(Direction::OUT), BULK_ONLY_RESET) if setup.wValue == 0 => {
+                self.bot.reset();
+                self.stall_in = false;
+                ControlResponse::Accept
+            }
+
+            _ => ControlResponse::Stall,
+        };
+        Some(response)
+    }
+
+    fn reset(&mut self) {
+        self.bot.reset();
+        self.stall_in = false;
+    }
+}
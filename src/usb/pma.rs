@@ -1,13 +1,10 @@
 #![allow(non_snake_case)]
 #![allow(dead_code)]
 
-use cortex_m_semihosting::{debug, hprintln};
-
 extern crate vcell;
 
 use self::vcell::VolatileCell;
 use bare_metal::Peripheral;
-use core::mem::size_of;
 use core::ops::Deref;
 
 // TODO: make this take-able? or at least move into the main usb part
@@ -19,7 +16,18 @@ use core::ops::Deref;
 pub const PMA: Peripheral<PMA> = unsafe { Peripheral::new(0x4000_6000) };
 pub const PMA_SIZE: usize = 1024; // Size in bytes.
 
-//const BTABLE: usize = 0;
+// Buffer descriptor table at the start of the PMA, USB_BTABLE is left at its reset value.
+pub const BTABLE: usize = 0;
+pub const BTABLE_SIZE: usize = 8 * 8; // 8 endpoints, 4 half words each.
+
+// COUNTn_RX can describe at most 32 blocks of 32 bytes.
+pub const MAX_RX_SIZE: usize = 1023;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum PmaError {
+    OutOfMemory { requested: usize, available: usize },
+    TooLarge(usize),
+}
 
 pub struct PMA {
     pub pma_area: PMA_Area,
@@ -27,7 +35,7 @@ pub struct PMA {
 
 impl PMA {
     pub fn zero(&mut self) {
-        for i in 0..PMA_SIZE/2 {
+        for i in (0..PMA_SIZE).step_by(2) {
             self.pma_area.set_u16(i, 0);
         }
     }
@@ -52,18 +60,154 @@ pub struct PMA_Area {
     words: [VolatileCell<u16>; PMA_SIZE / 2],
 }
 
-#[repr(C, packed)]
-#[derive(Debug, Copy, Clone)]
-pub struct USB_EpBufferDescriptor {
-    ADDR_TX: u16,  // Offset in to PMA where packet buffer resides.
-    COUNT_TX: u16, // Bytes to be transmitted
-    ADDR_RX: u16,  // Offset in to PMA where packet buffer resides.
-    COUNT_RX: u16, // BLSIZE, NUM_BLOCK[4:0], COUNT_RX[9:0] 0bx_xxxxx_xxxxxxxxxx
+// One endpoint's entry in the BTABLE (RM0091 30.6.2):
+//   ADDRn_TX   Offset in to PMA where packet buffer resides.
+//   COUNTn_TX  Bytes to be transmitted
+//   ADDRn_RX   Offset in to PMA where packet buffer resides.
+//   COUNTn_RX  BLSIZE, NUM_BLOCK[4:0], COUNT_RX[9:0] 0bx_xxxxx_xxxxxxxxxx
+pub struct BufferDescriptor<'a> {
+    pma: &'a PMA_Area,
+    offset: usize,
+}
+
+const ADDR_TX: usize = 0;
+const COUNT_TX: usize = 2;
+const ADDR_RX: usize = 4;
+const COUNT_RX: usize = 6;
+
+const COUNT_RX_MASK: u16 = 0x03ff;
+
+impl<'a> BufferDescriptor<'a> {
+    pub fn addr_tx(&self) -> usize {
+        self.pma.get_u16(self.offset + ADDR_TX) as usize
+    }
+
+    pub fn set_addr_tx(&self, addr: usize) {
+        self.pma.set_u16(self.offset + ADDR_TX, addr as u16);
+    }
+
+    pub fn count_tx(&self) -> usize {
+        (self.pma.get_u16(self.offset + COUNT_TX) & COUNT_RX_MASK) as usize
+    }
+
+    pub fn set_count_tx(&self, count: usize) {
+        self.pma.set_u16(self.offset + COUNT_TX, count as u16);
+    }
+
+    pub fn addr_rx(&self) -> usize {
+        self.pma.get_u16(self.offset + ADDR_RX) as usize
+    }
+
+    pub fn set_addr_rx(&self, addr: usize) {
+        self.pma.set_u16(self.offset + ADDR_RX, addr as u16);
+    }
+
+    // Bytes received in the last packet.
+    pub fn count_rx(&self) -> usize {
+        (self.pma.get_u16(self.offset + COUNT_RX) & COUNT_RX_MASK) as usize
+    }
+
+    // BL_SIZE/NUM_BLOCK as returned by rx_size_bits, the count bits are left to the hardware.
+    pub fn set_rx_size(&self, bits: u16) {
+        self.pma.set_u16(self.offset + COUNT_RX, bits);
+    }
+
+    // Double buffered endpoints use the whole entry for one direction, buffer 0 in the
+    // TX half and buffer 1 in the RX half (RM0091 30.5.3). For RX the counts hold
+    // BL_SIZE/NUM_BLOCK like COUNTn_RX, for TX just the count.
+    pub fn addr_buf(&self, buf: usize) -> usize {
+        let slot = if buf == 0 { ADDR_TX } else { ADDR_RX };
+        self.pma.get_u16(self.offset + slot) as usize
+    }
+
+    pub fn set_addr_buf(&self, buf: usize, addr: usize) {
+        let slot = if buf == 0 { ADDR_TX } else { ADDR_RX };
+        self.pma.set_u16(self.offset + slot, addr as u16);
+    }
+
+    pub fn count_buf(&self, buf: usize) -> usize {
+        let slot = if buf == 0 { COUNT_TX } else { COUNT_RX };
+        (self.pma.get_u16(self.offset + slot) & COUNT_RX_MASK) as usize
+    }
+
+    pub fn set_count_buf(&self, buf: usize, bits: u16) {
+        let slot = if buf == 0 { COUNT_TX } else { COUNT_RX };
+        self.pma.set_u16(self.offset + slot, bits);
+    }
+}
+
+// BL_SIZE/NUM_BLOCK for an RX buffer of at least size bytes, and the size that actually
+// describes. 2 byte blocks up to 62 bytes, 32 byte blocks above that.
+pub const fn rx_size_bits(size: usize) -> Result<(u16, usize), PmaError> {
+    match size {
+        0..=62 => {
+            let blocks = (size + 1) / 2;
+            Ok(((blocks as u16) << 10, blocks * 2))
+        }
+        63..=MAX_RX_SIZE => {
+            let blocks = (size + 31) / 32;
+            Ok((0x8000 | ((blocks as u16 - 1) << 10), blocks * 32))
+        }
+        _ => Err(PmaError::TooLarge(size)),
+    }
+}
+
+// Hands out packet buffers from the PMA after the BTABLE. There is no free, the whole
+// lot is given back with reset() when the host resets the bus.
+#[derive(Debug)]
+pub struct PmaAllocator {
+    next: usize,
 }
 
-// TODO - USB_DoubleBuffer{Tx,Rx}Descriptor
+impl PmaAllocator {
+    pub const fn new() -> Self {
+        PmaAllocator {
+            next: BTABLE + BTABLE_SIZE,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.next = BTABLE + BTABLE_SIZE;
+    }
+
+    pub fn available(&self) -> usize {
+        PMA_SIZE - self.next
+    }
+
+    // Half word aligned region of at least size bytes.
+    pub fn alloc(&mut self, size: usize) -> Result<usize, PmaError> {
+        let size = (size + 1) & !1;
+        if size > self.available() {
+            return Err(PmaError::OutOfMemory {
+                requested: size,
+                available: self.available(),
+            });
+        }
+
+        let addr = self.next;
+        self.next += size;
+        Ok(addr)
+    }
+
+    pub fn alloc_tx(&mut self, size: usize) -> Result<usize, PmaError> {
+        self.alloc(size)
+    }
+
+    // Returns the buffer address and the COUNTn_RX size bits for it.
+    pub fn alloc_rx(&mut self, size: usize) -> Result<(usize, u16), PmaError> {
+        let (bits, size) = rx_size_bits(size)?;
+        Ok((self.alloc(size)?, bits))
+    }
+}
 
 impl PMA_Area {
+    pub fn descriptor(&self, ep: usize) -> BufferDescriptor<'_> {
+        BufferDescriptor {
+            pma: self,
+            offset: BTABLE + ep * 8,
+        }
+    }
+
     //LSB first...
     pub fn get_u16(&self, offset: usize) -> u16 {
         self.words[offset/2].get()
@@ -77,48 +221,66 @@ impl PMA_Area {
         //self.bytes[offset + 1].set(((val >> 8) & 0x00ff) as u8);
     }
 
-//    pub fn get_u8(&self, offset: usize) -> u8 {
-//        self.bytes[offset].get()
-//    }
-//
-//    pub fn set_u8(&self, offset: usize, val: u8) {
-//        self.bytes[offset].set(val);
-//    }
-//
-//    pub fn borrow_slice(&self, offset: usize, size: usize) -> &[VolatileCell<u8>] {
-//        &self.bytes[offset..size]
-//    }
-//
-//    pub fn get_buffer_descriptor(&self, offset: usize) -> &USB_EpBufferDescriptor {
-//        unsafe { &*((&self.bytes as *const VolatileCell<u8>) as *const USB_EpBufferDescriptor) }
-//    }
-//
-//    pub fn get_buffer_descriptor2(&self, offset: usize) -> &USB_EpBufferDescriptor {
-//        let slice = self.borrow_slice(offset, size_of::<USB_EpBufferDescriptor>());
-//        unsafe { &*((slice as *const [VolatileCell<u8>]) as *const USB_EpBufferDescriptor) }
-//    }
-//
+    // The PMA is made of half words, single bytes are read out of/merged in to them.
+    pub fn get_u8(&self, offset: usize) -> u8 {
+        let hword = self.get_u16(offset & !1);
+        if offset % 2 == 0 {
+            (hword & 0x00ff) as u8
+        } else {
+            ((hword >> 8) & 0x00ff) as u8
+        }
+    }
+
+    pub fn set_u8(&self, offset: usize, val: u8) {
+        let hword = self.get_u16(offset & !1);
+        let hword = if offset % 2 == 0 {
+            (hword & 0xff00) | (val as u16)
+        } else {
+            (hword & 0x00ff) | ((val as u16) << 8)
+        };
+        self.set_u16(offset & !1, hword);
+    }
+
+    // Any offset and length, odd bytes at either end go through get_u8.
+    pub fn read_buffer_u8(&self, offset: usize, buf: &mut [u8]) {
+        let mut start = 0;
+        if offset % 2 == 1 && !buf.is_empty() {
+            buf[0] = self.get_u8(offset);
+            start = 1;
+        }
+
+        let mut offset = offset + start;
+        let mut pairs = buf[start..].chunks_exact_mut(2);
+        for pair in &mut pairs {
+            let hword = self.get_u16(offset);
+            pair[0] = (hword & 0x00ff) as u8;
+            pair[1] = ((hword >> 8) & 0x00ff) as u8;
+            offset += 2;
+        }
+
+        if let [last] = pairs.into_remainder() {
+            *last = self.get_u8(offset);
+        }
+    }
+
+    // Any offset and length, odd bytes at either end go through set_u8 so the neighbouring
+    // byte in the same half word is kept.
     pub fn write_buffer_u8(&self, offset: usize, buf: &[u8]) {
-        let mut hword: [u8; 2] = [0; 2];
-
-        for (off, val) in buf.iter().enumerate() {
-            if off % 2 == 0 {
-                hword[0] = *val;
-                // Is last byte?
-                if (off + 1) == buf.len() {
-                    hword[1] = ((self.get_u16(offset/2 + off/2) >> 8) & 0x00ff) as u8;
-                    let val16 = ((hword[0] as u16) & 0x00ff) | ((hword[1] as u16) << 8) & 0xff00;
-                    self.set_u16(offset + off, val16);
-                }
-            }
-
-            if off % 2 == 1 {
-                hword[1] = *val;
-                let val = ((hword[0] as u16) & 0x00ff) | ((hword[1] as u16) << 8) & 0xff00;
-                self.set_u16(offset + (off - 1), val);
-            }
-
-            //hprintln!("{:?}:{:x}, [{:x}, {:x}]", off, val, hword[0], hword[1]).unwrap();
+        let mut start = 0;
+        if offset % 2 == 1 && !buf.is_empty() {
+            self.set_u8(offset, buf[0]);
+            start = 1;
+        }
+
+        let mut offset = offset + start;
+        let mut pairs = buf[start..].chunks_exact(2);
+        for pair in &mut pairs {
+            self.set_u16(offset, (pair[0] as u16) | ((pair[1] as u16) << 8));
+            offset += 2;
+        }
+
+        if let [last] = pairs.remainder() {
+            self.set_u8(offset, *last);
         }
     }
 }
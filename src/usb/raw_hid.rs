@@ -0,0 +1,157 @@
+#![allow(dead_code)]
+
+use core::cmp::min;
+
+use crate::usb::class::UsbClass;
+use crate::usb::control::{ControlResponse, SetupPacket};
+use crate::usb::framing::{Deframer, Framer, MAX_MESSAGE, REPORT_SIZE};
+use crate::usb::hid::{self, Collection, Hid, ReportDescriptor, UsagePage};
+use crate::usb::hid::{ABSOLUTE, DATA, VARIABLE};
+use crate::usb::{Usb, USB};
+
+// One 64 byte input and one 64 byte output report on vendor page 0xFF00, which the
+// host's generic HID driver opens without anything being installed.
+const RAW_HID: ReportDescriptor<25> = ReportDescriptor::new()
+    .usage_page(UsagePage::Vendor(0x00))
+    .usage(0x01)
+    .collection(Collection::Application)
+    .logical_minimum(0)
+    .logical_maximum(255)
+    .report_size(8)
+    .report_count(REPORT_SIZE as u8)
+    .usage(0x02)
+    .input(DATA | VARIABLE | ABSOLUTE)
+    .usage(0x03)
+    .output(DATA | VARIABLE | ABSOLUTE)
+    .end_collection();
+
+pub static REPORT_DESCRIPTOR: ReportDescriptor<25> = RAW_HID;
+
+// Class descriptor for the interface's other_descriptors.
+pub const DESCRIPTOR: [u8; 9] = hid::descriptor(0x0111, 0, RAW_HID.len() as u16);
+
+// Vendor HID interface with an interrupt endpoint each way, carrying framed messages.
+// Output reports sent with SET_REPORT are taken as well, for hosts that don't use the
+// OUT endpoint.
+pub struct RawHid {
+    hid: Hid,
+    endpoint_out: u8,
+    framer: Framer,
+    deframer: Deframer<MAX_MESSAGE>,
+    // Length of the message waiting in the deframer. Until receive takes it, reports
+    // are left on the endpoint and the host is NAKed.
+    received: Option<usize>,
+    // Reports dropped by the deframer.
+    errors: u16,
+    tx: [u8; MAX_MESSAGE],
+    tx_len: usize,
+    tx_offset: usize,
+    // More of tx is still to be framed.
+    sending: bool,
+    // A framed report the endpoint hasn't taken yet.
+    report: [u8; REPORT_SIZE],
+    report_ready: bool,
+}
+
+impl RawHid {
+    pub fn new(interface: u8, endpoint_in: u8, endpoint_out: u8) -> Self {
+        RawHid {
+            hid: Hid::new(
+                interface,
+                endpoint_in,
+                &DESCRIPTOR,
+                REPORT_DESCRIPTOR.as_bytes(),
+            ),
+            endpoint_out,
+            framer: Framer::new(),
+            deframer: Deframer::new(),
+            received: None,
+            errors: 0,
+            tx: [0; MAX_MESSAGE],
+            tx_len: 0,
+            tx_offset: 0,
+            sending: false,
+            report: [0; REPORT_SIZE],
+            report_ready: false,
+        }
+    }
+
+    // Queue a message, false if the last one is still going out or it is bigger than
+    // MAX_MESSAGE. poll sends it.
+    pub fn send(&mut self, message: &[u8]) -> bool {
+        if self.is_sending() || message.len() > MAX_MESSAGE {
+            return false;
+        }
+        self.tx[..message.len()].copy_from_slice(message);
+        self.tx_len = message.len();
+        self.tx_offset = 0;
+        self.sending = true;
+        true
+    }
+
+    pub fn is_sending(&self) -> bool {
+        self.sending || self.report_ready
+    }
+
+    // The next message from the host, cut short if buf is too small.
+    pub fn receive(&mut self, buf: &mut [u8]) -> Option<usize> {
+        let len = min(self.received.take()?, buf.len());
+        buf[..len].copy_from_slice(&self.deframer.message()[..len]);
+        Some(len)
+    }
+
+    // Incoming reports dropped since the last call, for being malformed or coming after a
+    // lost one. The message they were part of is lost with them.
+    pub fn take_errors(&mut self) -> u16 {
+        core::mem::take(&mut self.errors)
+    }
+
+    // Move reports between the endpoints and the framing. Call it after Usb::interrupt,
+    // and after send so the first report goes out.
+    pub fn poll<PINS>(&mut self, usb: &mut Usb<USB, PINS>) {
+        let mut report = [0u8; REPORT_SIZE];
+        while self.received.is_none() {
+            let len = match usb.read(self.endpoint_out, &mut report) {
+                Ok(len) => len,
+                Err(_) => match self.hid.read_output(&mut report) {
+                    Some(len) => len,
+                    None => break,
+                },
+            };
+            match self.deframer.push(&report[..len]) {
+                Ok(received) => self.received = received,
+                Err(_) => self.errors = self.errors.saturating_add(1),
+            }
+        }
+
+        loop {
+            if !self.report_ready && self.sending {
+                self.tx_offset =
+                    self.framer
+                        .frame(&self.tx[..self.tx_len], self.tx_offset, &mut self.report);
+                self.sending = self.tx_offset < self.tx_len;
+                self.report_ready = true;
+            }
+            if !self.report_ready || self.hid.send_report(usb, &self.report).is_err() {
+                break;
+            }
+            self.report_ready = false;
+        }
+    }
+}
+
+impl UsbClass for RawHid {
+    fn control(&mut self, setup: &SetupPacket, data: &mut [u8]) -> Option<ControlResponse> {
+        self.hid.control(setup, data)
+    }
+
+    // Both ends start their sequence numbers over.
+    fn reset(&mut self) {
+        self.hid.reset();
+        self.framer.reset();
+        self.deframer.reset();
+        self.received = None;
+        self.sending = false;
+        self.report_ready = false;
+    }
+}
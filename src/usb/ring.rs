@@ -0,0 +1,92 @@
+#![allow(dead_code)]
+
+// Fixed size byte FIFO, for classes that turn packets in to a byte stream.
+pub struct RingBuffer<const N: usize> {
+    buf: [u8; N],
+    head: usize, // Next byte to read.
+    len: usize,
+}
+
+impl<const N: usize> RingBuffer<N> {
+    pub const fn new() -> Self {
+        RingBuffer {
+            buf: [0; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    pub fn free(&self) -> usize {
+        N - self.len
+    }
+
+    pub fn clear(&mut self) {
+        self.head = 0;
+        self.len = 0;
+    }
+
+    pub fn push(&mut self, byte: u8) -> bool {
+        if self.is_full() {
+            return false;
+        }
+        self.buf[(self.head + self.len) % N] = byte;
+        self.len += 1;
+        true
+    }
+
+    pub fn pop(&mut self) -> Option<u8> {
+        if self.is_empty() {
+            return None;
+        }
+        let byte = self.buf[self.head];
+        self.consume(1);
+        Some(byte)
+    }
+
+    // As much of data as fits, returns how much that was.
+    pub fn write(&mut self, data: &[u8]) -> usize {
+        let count = data.len().min(self.free());
+        for &byte in &data[..count] {
+            self.push(byte);
+        }
+        count
+    }
+
+    // Copy out without consuming, see consume.
+    pub fn peek(&self, buf: &mut [u8]) -> usize {
+        let count = buf.len().min(self.len);
+        for (i, byte) in buf[..count].iter_mut().enumerate() {
+            *byte = self.buf[(self.head + i) % N];
+        }
+        count
+    }
+
+    // Oldest first, without consuming.
+    pub fn iter(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..self.len).map(move |i| self.buf[(self.head + i) % N])
+    }
+
+    pub fn consume(&mut self, count: usize) {
+        let count = count.min(self.len);
+        self.head = (self.head + count) % N;
+        self.len -= count;
+    }
+
+    pub fn read(&mut self, buf: &mut [u8]) -> usize {
+        let count = self.peek(buf);
+        self.consume(count);
+        count
+    }
+}
@@ -0,0 +1,260 @@
+#![allow(dead_code)]
+
+use core::cmp::min;
+
+// super rather than crate::usb, host/ builds these files on their own for its tests.
+use super::block::{BlockDevice, BlockError, BLOCK_SIZE};
+
+// SPC-3 / SBC-2 operation codes.
+const TEST_UNIT_READY: u8 = 0x00;
+const REQUEST_SENSE: u8 = 0x03;
+const INQUIRY: u8 = 0x12;
+const MODE_SENSE_6: u8 = 0x1A;
+const START_STOP_UNIT: u8 = 0x1B;
+const PREVENT_ALLOW_MEDIUM_REMOVAL: u8 = 0x1E;
+const READ_FORMAT_CAPACITIES: u8 = 0x23;
+const READ_CAPACITY_10: u8 = 0x25;
+const READ_10: u8 = 0x28;
+const WRITE_10: u8 = 0x2A;
+
+const INQUIRY_SIZE: usize = 36;
+const REQUEST_SENSE_SIZE: usize = 18;
+const MODE_SENSE_ALL_PAGES: u8 = 0x3F;
+
+// Sense key and additional sense code, SPC-3 4.5.6. What REQUEST SENSE returns after a
+// command failed.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Sense {
+    pub key: u8,
+    pub asc: u8,
+    pub ascq: u8,
+}
+
+impl Sense {
+    pub const NONE: Sense = Sense::new(0x00, 0x00, 0x00);
+    pub const INVALID_COMMAND: Sense = Sense::new(0x05, 0x20, 0x00);
+    pub const LBA_OUT_OF_RANGE: Sense = Sense::new(0x05, 0x21, 0x00);
+    pub const INVALID_FIELD_IN_CDB: Sense = Sense::new(0x05, 0x24, 0x00);
+    pub const READ_ERROR: Sense = Sense::new(0x03, 0x11, 0x00);
+    pub const WRITE_ERROR: Sense = Sense::new(0x03, 0x0C, 0x00);
+    pub const WRITE_PROTECTED: Sense = Sense::new(0x07, 0x27, 0x00);
+
+    pub const fn new(key: u8, asc: u8, ascq: u8) -> Self {
+        Sense { key, asc, ascq }
+    }
+}
+
+// The data phase a command wants, for the transport to check against what the host asked
+// for.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Transfer {
+    None,
+    // The response is in the buffer given to command.
+    In(usize),
+    // Blocks for Scsi::read.
+    Read { lba: u32, blocks: u32 },
+    // Blocks for Scsi::write.
+    Write { lba: u32, blocks: u32 },
+}
+
+// The SCSI block commands a USB drive needs, for a single LUN on a BlockDevice.
+pub struct Scsi<B> {
+    device: B,
+    vendor: &'static str,
+    product: &'static str,
+    revision: &'static str,
+    sense: Sense,
+    prevent_removal: bool,
+}
+
+impl<B: BlockDevice> Scsi<B> {
+    // INQUIRY identification, cut to 8, 16 and 4 characters.
+    pub fn new(
+        device: B,
+        vendor: &'static str,
+        product: &'static str,
+        revision: &'static str,
+    ) -> Self {
+        Scsi {
+            device,
+            vendor,
+            product,
+            revision,
+            sense: Sense::NONE,
+            prevent_removal: false,
+        }
+    }
+
+    pub fn device(&mut self) -> &mut B {
+        &mut self.device
+    }
+
+    // The host has the medium locked, e.g. a filesystem is mounted.
+    pub fn is_removal_prevented(&self) -> bool {
+        self.prevent_removal
+    }
+
+    pub fn sense(&self) -> Sense {
+        self.sense
+    }
+
+    // Run the command block cdb. Responses go in buf, which has to hold a block. On
+    // failure the sense is kept for REQUEST SENSE and the transport reports CHECK
+    // CONDITION.
+    pub fn command(&mut self, cdb: &[u8], buf: &mut [u8]) -> Result<Transfer, Sense> {
+        let result = self.execute(cdb, buf);
+        if let Err(sense) = result {
+            self.sense = sense;
+        }
+        result
+    }
+
+    // One block of a Read transfer.
+    pub fn read(&mut self, lba: u32, block: &mut [u8; BLOCK_SIZE]) -> Result<(), Sense> {
+        let result = self.device.read_block(lba, block).map_err(|e| match e {
+            BlockError::OutOfRange => Sense::LBA_OUT_OF_RANGE,
+            _ => Sense::READ_ERROR,
+        });
+        if let Err(sense) = result {
+            self.sense = sense;
+        }
+        result
+    }
+
+    // One block of a Write transfer.
+    pub fn write(&mut self, lba: u32, block: &[u8; BLOCK_SIZE]) -> Result<(), Sense> {
+        let result = self.device.write_block(lba, block).map_err(|e| match e {
+            BlockError::OutOfRange => Sense::LBA_OUT_OF_RANGE,
+            BlockError::ReadOnly => Sense::WRITE_PROTECTED,
+            BlockError::Io => Sense::WRITE_ERROR,
+        });
+        if let Err(sense) = result {
+            self.sense = sense;
+        }
+        result
+    }
+
+    // Bus or transport reset.
+    pub fn reset(&mut self) {
+        self.sense = Sense::NONE;
+        self.prevent_removal = false;
+    }
+
+    fn execute(&mut self, cdb: &[u8], buf: &mut [u8]) -> Result<Transfer, Sense> {
+        let opcode = *cdb.first().ok_or(Sense::INVALID_COMMAND)?;
+        let needed = match opcode {
+            READ_FORMAT_CAPACITIES | READ_CAPACITY_10 | READ_10 | WRITE_10 => 10,
+            _ => 6,
+        };
+        if cdb.len() < needed {
+            return Err(Sense::INVALID_FIELD_IN_CDB);
+        }
+
+        match opcode {
+            TEST_UNIT_READY => Ok(Transfer::None),
+
+            REQUEST_SENSE => {
+                let response = &mut buf[..REQUEST_SENSE_SIZE];
+                response.iter_mut().for_each(|b| *b = 0);
+                response[0] = 0x70; // Current error, fixed format.
+                response[2] = self.sense.key;
+                response[7] = (REQUEST_SENSE_SIZE - 8) as u8;
+                response[12] = self.sense.asc;
+                response[13] = self.sense.ascq;
+                self.sense = Sense::NONE;
+                Ok(Transfer::In(min(REQUEST_SENSE_SIZE, cdb[4] as usize)))
+            }
+
+            INQUIRY => {
+                // No vital product data pages.
+                if cdb[1] & 0x01 != 0 {
+                    return Err(Sense::INVALID_FIELD_IN_CDB);
+                }
+                let response = &mut buf[..INQUIRY_SIZE];
+                response[0] = 0x00; // Direct access block device.
+                response[1] = 0x80; // Removable.
+                response[2] = 0x04; // SPC-2.
+                response[3] = 0x02; // Response data format.
+                response[4] = (INQUIRY_SIZE - 5) as u8;
+                response[5..8].iter_mut().for_each(|b| *b = 0);
+                pad(&mut response[8..16], self.vendor);
+                pad(&mut response[16..32], self.product);
+                pad(&mut response[32..36], self.revision);
+                let allocation = u16::from_be_bytes([cdb[3], cdb[4]]) as usize;
+                Ok(Transfer::In(min(INQUIRY_SIZE, allocation)))
+            }
+
+            // Header only, no block descriptors and no pages to report.
+            MODE_SENSE_6 => {
+                if cdb[2] & 0x3F != MODE_SENSE_ALL_PAGES {
+                    return Err(Sense::INVALID_FIELD_IN_CDB);
+                }
+                buf[0] = 3; // Mode data length, less this byte.
+                buf[1] = 0; // Medium type.
+                buf[2] = if self.device.is_read_only() { 0x80 } else { 0 }; // WP.
+                buf[3] = 0; // Block descriptor length.
+                Ok(Transfer::In(min(4, cdb[4] as usize)))
+            }
+
+            // Eject, or spin up. Nothing to do for either.
+            START_STOP_UNIT => Ok(Transfer::None),
+
+            PREVENT_ALLOW_MEDIUM_REMOVAL => {
+                self.prevent_removal = cdb[4] & 0x01 != 0;
+                Ok(Transfer::None)
+            }
+
+            // UFI, some hosts ask it before READ CAPACITY. One formatted medium.
+            READ_FORMAT_CAPACITIES => {
+                let blocks = self.device.block_count().to_be_bytes();
+                let size = (BLOCK_SIZE as u32).to_be_bytes();
+                buf[..4].copy_from_slice(&[0, 0, 0, 8]); // Capacity list length.
+                buf[4..8].copy_from_slice(&blocks);
+                buf[8] = 0x02; // Formatted media.
+                buf[9..12].copy_from_slice(&size[1..]);
+                let allocation = u16::from_be_bytes([cdb[7], cdb[8]]) as usize;
+                Ok(Transfer::In(min(12, allocation)))
+            }
+
+            // Last block, not the number of blocks.
+            READ_CAPACITY_10 => {
+                let last = self.device.block_count().wrapping_sub(1);
+                buf[..4].copy_from_slice(&last.to_be_bytes());
+                buf[4..8].copy_from_slice(&(BLOCK_SIZE as u32).to_be_bytes());
+                Ok(Transfer::In(8))
+            }
+
+            READ_10 => {
+                let (lba, blocks) = self.range(cdb)?;
+                Ok(Transfer::Read { lba, blocks })
+            }
+
+            WRITE_10 => {
+                let (lba, blocks) = self.range(cdb)?;
+                if self.device.is_read_only() {
+                    return Err(Sense::WRITE_PROTECTED);
+                }
+                Ok(Transfer::Write { lba, blocks })
+            }
+
+            _ => Err(Sense::INVALID_COMMAND),
+        }
+    }
+
+    // READ(10)/WRITE(10) logical block address and transfer length.
+    fn range(&self, cdb: &[u8]) -> Result<(u32, u32), Sense> {
+        let lba = u32::from_be_bytes([cdb[2], cdb[3], cdb[4], cdb[5]]);
+        let blocks = u16::from_be_bytes([cdb[7], cdb[8]]) as u32;
+        match lba.checked_add(blocks) {
+            Some(end) if end <= self.device.block_count() => Ok((lba, blocks)),
+            _ => Err(Sense::LBA_OUT_OF_RANGE),
+        }
+    }
+}
+
+// ASCII field padded with spaces.
+fn pad(field: &mut [u8], s: &str) {
+    for (i, b) in field.iter_mut().enumerate() {
+        *b = *s.as_bytes().get(i).unwrap_or(&b' ');
+    }
+}
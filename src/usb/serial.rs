@@ -0,0 +1,103 @@
+use core::fmt;
+
+use embedded_hal::serial;
+
+use crate::usb::cdc::CdcAcm;
+use crate::usb::{Usb, USB};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum SerialError {
+    // DTR is clear, no terminal has the port open. Writing would only fill the TX ring
+    // up and block, so the byte is refused instead.
+    NotConnected,
+}
+
+// A CdcAcm and the driver it sends through, borrowed together for as long as the
+// critical section that owns both:
+//
+//   let mut serial = port.serial(usb);
+//   writeln!(serial, "count: {}", count).ok();
+//
+// Reads and writes poll the endpoints themselves, so blocking on them works without the
+// USB interrupt running. A host that has the port open but stops reading blocks a writer
+// for good though, keep blocking writes short. fmt::Write doesn't block, it is meant for
+// critical sections.
+pub struct Serial<'a, PINS> {
+    port: &'a mut CdcAcm,
+    usb: &'a mut Usb<USB, PINS>,
+}
+
+impl<'a, PINS> Serial<'a, PINS> {
+    pub fn new(port: &'a mut CdcAcm, usb: &'a mut Usb<USB, PINS>) -> Self {
+        Serial { port, usb }
+    }
+
+    // A terminal has the port open, output is being read.
+    pub fn dtr(&self) -> bool {
+        self.port.dtr()
+    }
+}
+
+impl<'a, PINS> serial::Read<u8> for Serial<'a, PINS> {
+    type Error = SerialError;
+
+    fn read(&mut self) -> nb::Result<u8, SerialError> {
+        let mut byte = [0u8];
+        if self.port.read(&mut byte) == 0 {
+            self.port.poll(self.usb);
+            if self.port.read(&mut byte) == 0 {
+                return Err(nb::Error::WouldBlock);
+            }
+        }
+        Ok(byte[0])
+    }
+}
+
+impl<'a, PINS> serial::Write<u8> for Serial<'a, PINS> {
+    type Error = SerialError;
+
+    // WouldBlock while the TX ring is full and the host hasn't taken the last packet.
+    fn write(&mut self, word: u8) -> nb::Result<(), SerialError> {
+        if !self.port.dtr() {
+            return Err(nb::Error::Other(SerialError::NotConnected));
+        }
+
+        if self.port.write(&[word]) == 0 {
+            self.port.poll(self.usb);
+            if self.port.write(&[word]) == 0 {
+                return Err(nb::Error::WouldBlock);
+            }
+        }
+        Ok(())
+    }
+
+    // Done once the host has been handed every byte, not when it has read them.
+    fn flush(&mut self) -> nb::Result<(), SerialError> {
+        self.port.poll(self.usb);
+        if self.port.flushed() {
+            Ok(())
+        } else if !self.port.dtr() {
+            Err(nb::Error::Other(SerialError::NotConnected))
+        } else {
+            Err(nb::Error::WouldBlock)
+        }
+    }
+}
+
+// Queues as much of the string as the TX ring has room for. If the host isn't keeping up
+// the rest is dropped and fmt::Error returned, rather than waiting on a host that may
+// never read with the USB interrupt masked. With no terminal open the output is dropped
+// too, so status messages can be written unconditionally.
+impl<'a, PINS> fmt::Write for Serial<'a, PINS> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            match serial::Write::write(self, byte) {
+                Ok(()) => {}
+                Err(nb::Error::Other(SerialError::NotConnected)) => return Ok(()),
+                Err(nb::Error::WouldBlock) => return Err(fmt::Error),
+            }
+        }
+        self.port.poll(self.usb);
+        Ok(())
+    }
+}
@@ -1,12 +1,21 @@
 #![allow(non_snake_case)]
 #![allow(dead_code)]
 
-use core::marker::Sized;
-use core::mem::{size_of, transmute};
-use core::slice::*;
+use core::mem::size_of;
 
-use crate::usb::constants;
 use crate::usb::descriptors;
+use crate::usb::descriptors::{DescriptorWriter, DeviceQualifier, StringTable};
+
+#[derive(Debug)]
+pub struct Descriptors<'a> {
+    // Device, its configurations, interfaces and endpoints.
+    pub Device: Device<'a>,
+    // None for full speed only devices.
+    pub DeviceQualifier: Option<DeviceQualifier>,
+    // One table per language, the first one is used if the host asks for another.
+    pub Strings: &'a [StringTable<'a>],
+    pub Bos: Option<&'a [u8]>,
+}
 
 #[derive(Debug)]
 pub struct Device<'a> {
@@ -30,4 +39,234 @@ pub struct Interface<'a> {
 #[derive(Debug, Copy, Clone)]
 pub struct Endpoint<'a> {
     descriptor: &'a descriptors::Endpoint,
+    double_buffered: bool, // Driver side only, not part of the descriptor.
+}
+
+impl<'a> Device<'a> {
+    pub const fn new(
+        descriptor: &'a descriptors::Device,
+        configurations: &'a [Configuration<'a>],
+    ) -> Self {
+        Device {
+            descriptor,
+            configurations,
+        }
+    }
+
+    pub const fn descriptor(&self) -> &'a descriptors::Device {
+        self.descriptor
+    }
+
+    pub const fn configurations(&self) -> &'a [Configuration<'a>] {
+        self.configurations
+    }
+
+    // Configuration by bConfigurationValue, as used by SET_CONFIGURATION.
+    pub fn configuration(&self, value: u8) -> Option<&'a Configuration<'a>> {
+        self.configurations
+            .iter()
+            .find(|c| c.descriptor.configuration_value() == value)
+    }
+
+    pub fn write(&self, writer: &mut DescriptorWriter) {
+        let header = self
+            .descriptor
+            .bNumConfigurations(self.configurations.len() as u8);
+        writer.write(&header.to_bytes());
+    }
+}
+
+impl<'a> Configuration<'a> {
+    pub const fn new(
+        descriptor: &'a descriptors::Configuration,
+        interfaces: &'a [Interface<'a>],
+    ) -> Self {
+        Configuration {
+            descriptor,
+            interfaces,
+        }
+    }
+
+    pub const fn descriptor(&self) -> &'a descriptors::Configuration {
+        self.descriptor
+    }
+
+    pub const fn interfaces(&self) -> &'a [Interface<'a>] {
+        self.interfaces
+    }
+
+    // Alternate settings share an interface number, only count them once.
+    pub const fn num_interfaces(&self) -> u8 {
+        let mut count = 0;
+        let mut i = 0;
+        while i < self.interfaces.len() {
+            if self.interfaces[i].descriptor.alternate_setting() == 0 {
+                count += 1;
+            }
+            i += 1;
+        }
+        count
+    }
+
+    pub const fn total_length(&self) -> u16 {
+        let mut length = size_of::<descriptors::Configuration>();
+        let mut i = 0;
+        while i < self.interfaces.len() {
+            length += self.interfaces[i].length();
+            i += 1;
+        }
+        length as u16
+    }
+
+    // The whole configuration as one blob, so it can live in flash as a const:
+    //
+    //   const CONF: [u8; confs[0].total_length() as usize] = confs[0].to_bytes();
+    pub const fn to_bytes<const N: usize>(&self) -> [u8; N] {
+        if N != self.total_length() as usize {
+            panic!("array length does not match wTotalLength");
+        }
+
+        let header = self
+            .descriptor
+            .wTotalLength(self.total_length())
+            .bNumInterfaces(self.num_interfaces());
+
+        let (mut out, mut pos) = put([0; N], 0, &header.to_bytes());
+        let mut i = 0;
+        while i < self.interfaces.len() {
+            let interface = &self.interfaces[i];
+            let (o, p) = put(out, pos, &interface.descriptor.to_bytes());
+            out = o;
+            pos = p;
+
+            let mut j = 0;
+            while j < interface.other_descriptors.len() {
+                let (o, p) = put(out, pos, interface.other_descriptors[j]);
+                out = o;
+                pos = p;
+                j += 1;
+            }
+
+            let mut j = 0;
+            while j < interface.endpoints.len() {
+                let (o, p) = put(out, pos, &interface.endpoints[j].descriptor.to_bytes());
+                out = o;
+                pos = p;
+                j += 1;
+            }
+            i += 1;
+        }
+        out
+    }
+
+    // USB 2.0 9.4.3, the configuration followed by each interface, its class specific
+    // descriptors and then its endpoints.
+    pub fn write(&self, writer: &mut DescriptorWriter) {
+        self.write_with(writer, *self.descriptor);
+    }
+
+    pub fn write_other_speed(&self, writer: &mut DescriptorWriter) {
+        self.write_with(writer, self.descriptor.other_speed());
+    }
+
+    fn write_with(&self, writer: &mut DescriptorWriter, header: descriptors::Configuration) {
+        let header = header
+            .wTotalLength(self.total_length())
+            .bNumInterfaces(self.num_interfaces());
+        writer.write(&header.to_bytes());
+
+        for interface in self.interfaces {
+            interface.write(writer);
+        }
+    }
+}
+
+impl<'a> Interface<'a> {
+    pub const fn new(
+        descriptor: &'a descriptors::Interface,
+        other_descriptors: &'a [&'a [u8]],
+        endpoints: &'a [Endpoint<'a>],
+    ) -> Self {
+        Interface {
+            descriptor,
+            other_descriptors,
+            endpoints,
+        }
+    }
+
+    pub const fn descriptor(&self) -> &'a descriptors::Interface {
+        self.descriptor
+    }
+
+    pub const fn other_descriptors(&self) -> &'a [&'a [u8]] {
+        self.other_descriptors
+    }
+
+    pub const fn endpoints(&self) -> &'a [Endpoint<'a>] {
+        self.endpoints
+    }
+
+    pub const fn length(&self) -> usize {
+        let mut length = size_of::<descriptors::Interface>()
+            + self.endpoints.len() * size_of::<descriptors::Endpoint>();
+        let mut i = 0;
+        while i < self.other_descriptors.len() {
+            length += self.other_descriptors[i].len();
+            i += 1;
+        }
+        length
+    }
+
+    pub fn write(&self, writer: &mut DescriptorWriter) {
+        writer.write(&self.descriptor.to_bytes());
+
+        for other in self.other_descriptors {
+            writer.write(other);
+        }
+
+        for endpoint in self.endpoints {
+            endpoint.write(writer);
+        }
+    }
+}
+
+impl<'a> Endpoint<'a> {
+    pub const fn new(descriptor: &'a descriptors::Endpoint) -> Self {
+        Endpoint {
+            descriptor,
+            double_buffered: false,
+        }
+    }
+
+    // Run a bulk endpoint with both PMA buffers, so the host isn't NAKed while the
+    // firmware copies the other one. Takes the whole EPnR, the other direction of the
+    // same endpoint number can't be used.
+    pub const fn double_buffered(&self) -> Self {
+        Endpoint {
+            double_buffered: true,
+            ..*self
+        }
+    }
+
+    pub const fn is_double_buffered(&self) -> bool {
+        self.double_buffered
+    }
+
+    pub const fn descriptor(&self) -> &'a descriptors::Endpoint {
+        self.descriptor
+    }
+
+    pub fn write(&self, writer: &mut DescriptorWriter) {
+        writer.write(&self.descriptor.to_bytes());
+    }
+}
+
+// Copy bytes into out at pos, const fns can't take &mut so the array goes through by value.
+const fn put<const N: usize>(mut out: [u8; N], pos: usize, bytes: &[u8]) -> ([u8; N], usize) {
+    let mut i = 0;
+    while i < bytes.len() {
+        out[pos + i] = bytes[i];
+        i += 1;
+    }
+    (out, pos + bytes.len())
 }
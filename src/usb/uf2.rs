@@ -0,0 +1,216 @@
+#![allow(dead_code)]
+
+use core::convert::TryInto;
+use core::fmt;
+
+// super rather than crate::usb, host/ builds these files on their own for its tests.
+use super::block::{BlockError, BLOCK_SIZE};
+use super::fat::File;
+use super::flash::{Flash, FlashError, Update};
+
+// Microsoft's UF2 (https://github.com/microsoft/uf2). A .uf2 file is a run of 512 byte
+// blocks, each carrying up to 476 bytes for one address:
+//
+//   0    magic start 0, magic start 1, flags, target address, payload size,
+//        block number, number of blocks, family id or file size
+//   32   payload
+//   508  magic end
+//
+// Copying one to the drive writes its blocks in to sectors in an order up to the host,
+// along with the directory and FAT updates. Any sector that is a UF2 block is taken
+// wherever it lands, the rest are dropped.
+const MAGIC_START0: u32 = 0x0A32_4655; // "UF2\n"
+const MAGIC_START1: u32 = 0x9E5D_5157;
+const MAGIC_END: u32 = 0x0AB1_6F30;
+const MAX_PAYLOAD: usize = 476;
+
+const FLAG_NOT_MAIN_FLASH: u32 = 0x0000_0001;
+const FLAG_FILE_CONTAINER: u32 = 0x0000_1000;
+const FLAG_FAMILY_ID: u32 = 0x0000_2000;
+
+// uf2families.json, "STM32F0".
+pub const FAMILY_STM32F0: u32 = 0x6478_24B6;
+
+// Blocks an image may have, the image half in 256 byte payloads with room to spare.
+pub const MAX_BLOCKS: u32 = 512;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Uf2Block<'a> {
+    pub flags: u32,
+    pub target: u32,
+    pub payload: &'a [u8],
+    pub block: u32,
+    pub blocks: u32,
+    pub family: u32,
+}
+
+impl<'a> Uf2Block<'a> {
+    // None if it isn't a UF2 block.
+    pub fn parse(data: &'a [u8; BLOCK_SIZE]) -> Option<Self> {
+        let word = |n: usize| u32::from_le_bytes(data[n * 4..n * 4 + 4].try_into().unwrap());
+        if word(0) != MAGIC_START0 || word(1) != MAGIC_START1 || word(127) != MAGIC_END {
+            return None;
+        }
+        let size = word(4) as usize;
+        if size > MAX_PAYLOAD {
+            return None;
+        }
+        Some(Uf2Block {
+            flags: word(2),
+            target: word(3),
+            payload: &data[32..32 + size],
+            block: word(5),
+            blocks: word(6),
+            family: word(7),
+        })
+    }
+}
+
+// The INFO_UF2.TXT and INDEX.HTM a UF2 drive has, for a VirtualFat with a Uf2 context.
+pub const fn files<F: Flash>() -> [File<Uf2<F>>; 2] {
+    [
+        File {
+            name: "INFO_UF2.TXT",
+            generate: info_uf2::<F>,
+        },
+        File {
+            name: "INDEX.HTM",
+            generate: index_htm::<F>,
+        },
+    ]
+}
+
+// VirtualFat::on_write for a Uf2 context.
+pub fn write<F: Flash>(
+    uf2: &mut Uf2<F>,
+    _lba: u32,
+    data: &[u8; BLOCK_SIZE],
+) -> Result<(), BlockError> {
+    uf2.write_block(data)
+}
+
+fn info_uf2<F: Flash>(uf2: &Uf2<F>, w: &mut dyn fmt::Write) -> fmt::Result {
+    write!(w, "UF2 Bootloader {}\r\n", uf2.version)?;
+    write!(w, "Model: {}\r\n", uf2.model)?;
+    write!(w, "Board-ID: {}\r\n", uf2.board)
+}
+
+fn index_htm<F: Flash>(uf2: &Uf2<F>, w: &mut dyn fmt::Write) -> fmt::Result {
+    write!(
+        w,
+        "<!doctype html>\n<html><body><script>\nlocation.replace(\"{}\");\n</script></body></html>\n",
+        uf2.url
+    )
+}
+
+// Takes the blocks of one UF2 file for this chip and stages them. Blocks not for main
+// flash count towards the file without being written. Blocks for another family, or
+// outside the image, are dropped and counted. Once every block of the file
+// is in, is_done and the update can be installed.
+pub struct Uf2<F> {
+    update: Update<F>,
+    version: &'static str,
+    model: &'static str,
+    board: &'static str,
+    url: &'static str,
+    // Number of blocks of the file being received, 0 before the first block.
+    blocks: u32,
+    received: [u32; MAX_BLOCKS as usize / 32],
+    count: u32,
+    rejected: u32,
+}
+
+impl<F: Flash> Uf2<F> {
+    // What INFO_UF2.TXT and INDEX.HTM show, url is where INDEX.HTM sends the browser.
+    pub const fn new(
+        flash: F,
+        version: &'static str,
+        model: &'static str,
+        board: &'static str,
+        url: &'static str,
+    ) -> Self {
+        Uf2 {
+            update: Update::new(flash),
+            version,
+            model,
+            board,
+            url,
+            blocks: 0,
+            received: [0; MAX_BLOCKS as usize / 32],
+            count: 0,
+            rejected: 0,
+        }
+    }
+
+    pub fn update(&mut self) -> &mut Update<F> {
+        &mut self.update
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.blocks != 0 && self.count == self.blocks
+    }
+
+    // Blocks of the file received so far, and of how many.
+    pub fn progress(&self) -> (u32, u32) {
+        (self.count, self.blocks)
+    }
+
+    // UF2 blocks that were dropped.
+    pub fn rejected(&self) -> u32 {
+        self.rejected
+    }
+
+    // A sector the host wrote. Only flash failing is an error, everything else that
+    // isn't a block of the image is ignored.
+    pub fn write_block(&mut self, data: &[u8; BLOCK_SIZE]) -> Result<(), BlockError> {
+        let block = match Uf2Block::parse(data) {
+            Some(block) => block,
+            None => return Ok(()),
+        };
+        if block.flags & FLAG_FILE_CONTAINER != 0 {
+            return Ok(());
+        }
+        if block.flags & FLAG_FAMILY_ID == 0
+            || block.family != FAMILY_STM32F0
+            || block.blocks == 0
+            || block.blocks > MAX_BLOCKS
+            || block.block >= block.blocks
+        {
+            self.rejected += 1;
+            return Ok(());
+        }
+
+        // Another file starts over.
+        if block.blocks != self.blocks {
+            self.blocks = block.blocks;
+            self.received = [0; MAX_BLOCKS as usize / 32];
+            self.count = 0;
+            self.update.begin();
+        }
+
+        let (word, bit) = ((block.block / 32) as usize, 1 << (block.block % 32));
+        if self.received[word] & bit != 0 {
+            return Ok(());
+        }
+
+        // Numbered along with the rest of the file, but not for flash.
+        if block.flags & FLAG_NOT_MAIN_FLASH != 0 {
+            self.received[word] |= bit;
+            self.count += 1;
+            return Ok(());
+        }
+
+        match self.update.write(block.target, block.payload) {
+            Ok(()) => {
+                self.received[word] |= bit;
+                self.count += 1;
+                Ok(())
+            }
+            Err(FlashError::Address) => {
+                self.rejected += 1;
+                Ok(())
+            }
+            Err(_) => Err(BlockError::Io),
+        }
+    }
+}
@@ -1,107 +1,205 @@
-use hal::stm32::usb::{EP0R, EP1R};
+use hal::stm32::usb::{RegisterBlock, EP0R, EP1R, EP2R, EP3R, EP4R, EP5R, EP6R, EP7R};
+
+use crate::usb::constants::EndpointType;
+
+// RM0091 30.6.2, STAT_TX/STAT_RX encoding.
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum EpStatus {
+    Disabled = 0b00,
+    Stall = 0b01,
+    Nak = 0b10,
+    Valid = 0b11,
+}
 
-pub trait UsbEpExt {
-    fn toggle_tx_out(&self);
-    fn toggle_tx_stall(&self);
-    fn toggle_out(&self);
-    fn toggle_0(&self);
-    fn toggle_rx(&self);
-    fn toggle_tx(&self);
-    fn toggle(&self, mask: u32, val: u32, flags: u32);
-    fn clear_ctr_rx(&self);
-    fn clear_ctr_tx(&self);
+impl EpStatus {
+    pub fn from_bits(bits: u32) -> Self {
+        match bits & 0b11 {
+            0b00 => EpStatus::Disabled,
+            0b01 => EpStatus::Stall,
+            0b10 => EpStatus::Nak,
+            _ => EpStatus::Valid,
+        }
+    }
 }
 
-const EP_MASK: u32 = 0x0F0F;
-const EP_TX_MASK: u32 = 0x0030;
-const EP_RX_MASK: u32 = 0x3000;
-const EP_TX_RX_MASK: u32 = (EP_TX_MASK | EP_RX_MASK);
+// RM0091 30.6.2, EP_TYPE encoding. Not the same order as the descriptor's bmAttributes.
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum EpType {
+    Bulk = 0b00,
+    Control = 0b01,
+    Isochronous = 0b10,
+    Interrupt = 0b11,
+}
 
-const EP_TX_VALID: u32 = 0x0030;
-const EP_RX_VALID: u32 = 0x3000;
-const EP_TX_RX_VALID: u32 = (EP_TX_VALID | EP_RX_VALID);
+impl From<EndpointType> for EpType {
+    fn from(t: EndpointType) -> Self {
+        match t {
+            EndpointType::Control => EpType::Control,
+            EndpointType::Isochronous => EpType::Isochronous,
+            EndpointType::Bulk => EpType::Bulk,
+            EndpointType::Interrupt => EpType::Interrupt,
+        }
+    }
+}
 
-const EP_TX_STALL: u32 = 0x0010;
-const EP_STATUS_OUT: u32 = 0x0100;
+// EPnR bits are a mix of kinds, see RM0091 30.6.2:
+//   CTR_RX, CTR_TX                    rc_w0, writing 0 clears, 1 leaves them alone
+//   DTOG_RX, STAT_RX, DTOG_TX, STAT_TX t,     writing 1 toggles, 0 leaves them alone
+//   SETUP                             r
+//   EP_TYPE, EP_KIND, EA              rw
+// so every write starts from the rw bits with the CTR bits set and toggle bits clear.
+const EP_MASK: u32 = 0x0F0F;
 
 const EP_CTR_RX: u32 = 0x8000;
-const EP_CTR_TX: u32 = 0x0080;
-
 const EP_DTOG_RX: u32 = 0x4000;
+const EP_STAT_RX: u32 = 0x3000;
+const EP_SETUP: u32 = 0x0800;
+const EP_TYPE: u32 = 0x0600;
+const EP_KIND: u32 = 0x0100;
+const EP_CTR_TX: u32 = 0x0080;
 const EP_DTOG_TX: u32 = 0x0040;
+const EP_STAT_TX: u32 = 0x0030;
+const EP_EA: u32 = 0x000F;
+
+const EP_STAT_RX_SHIFT: u32 = 12;
+const EP_STAT_TX_SHIFT: u32 = 4;
+const EP_TYPE_SHIFT: u32 = 9;
+
+// Write that changes nothing, to be or'ed with whatever should change.
+fn unchanged(r: u32) -> u32 {
+    (r & EP_MASK) | EP_CTR_RX | EP_CTR_TX
+}
+
+pub trait UsbEpExt {
+    // Raw access, everything else is built on these two.
+    fn read_bits(&self) -> u32;
+    fn write_bits(&self, bits: u32);
 
-impl UsbEpExt for EP0R {
-    fn toggle_tx_stall(&self) {
-        self.toggle(EP_TX_RX_MASK, EP_RX_VALID | EP_TX_STALL, 0)
+    fn ctr_rx(&self) -> bool {
+        self.read_bits() & EP_CTR_RX != 0
     }
 
-    fn toggle_tx_out(&self) {
-        self.toggle(EP_TX_MASK, EP_TX_VALID, EP_STATUS_OUT)
+    fn ctr_tx(&self) -> bool {
+        self.read_bits() & EP_CTR_TX != 0
     }
 
-    fn toggle_out(&self) {
-        self.toggle(EP_TX_RX_MASK, EP_TX_RX_VALID, EP_STATUS_OUT)
+    fn setup(&self) -> bool {
+        self.read_bits() & EP_SETUP != 0
     }
 
-    fn toggle_0(&self) {
-        self.toggle(EP_TX_RX_MASK, EP_TX_RX_VALID, 0)
+    fn dtog_rx(&self) -> bool {
+        self.read_bits() & EP_DTOG_RX != 0
     }
 
-    fn toggle_rx(&self) {
-        self.toggle(EP_DTOG_RX, EP_DTOG_RX, 0)
+    fn dtog_tx(&self) -> bool {
+        self.read_bits() & EP_DTOG_TX != 0
     }
 
-    fn toggle_tx(&self) {
-        self.toggle(EP_DTOG_TX, EP_DTOG_TX, 0)
+    fn stat_rx(&self) -> EpStatus {
+        EpStatus::from_bits(self.read_bits() >> EP_STAT_RX_SHIFT)
     }
 
-    fn toggle(&self, mask: u32, val: u32, flags: u32) {
-        self.modify(|r, w| unsafe { w.bits(((r.bits() & (EP_MASK | mask)) ^ val) | flags) })
+    fn stat_tx(&self) -> EpStatus {
+        EpStatus::from_bits(self.read_bits() >> EP_STAT_TX_SHIFT)
     }
 
-    fn clear_ctr_rx(&self) {
-        self.modify(|r, w| unsafe { w.bits((r.bits() & EP_MASK) | EP_CTR_TX) });
+    fn set_stat_tx(&self, status: EpStatus) {
+        let r = self.read_bits();
+        let stat = (r & EP_STAT_TX) ^ ((status as u32) << EP_STAT_TX_SHIFT);
+        self.write_bits(unchanged(r) | stat);
     }
 
-    fn clear_ctr_tx(&self) {
-        self.modify(|r, w| unsafe { w.bits((r.bits() & EP_MASK) | EP_CTR_RX) });
+    fn set_stat_rx(&self, status: EpStatus) {
+        let r = self.read_bits();
+        let stat = (r & EP_STAT_RX) ^ ((status as u32) << EP_STAT_RX_SHIFT);
+        self.write_bits(unchanged(r) | stat);
     }
-}
 
-impl UsbEpExt for EP1R {
-    fn toggle_tx_stall(&self) {
-        self.toggle(EP_TX_RX_MASK, EP_RX_VALID | EP_TX_STALL, 0)
+    // Both directions in a single write.
+    fn set_stat(&self, tx: EpStatus, rx: EpStatus) {
+        let r = self.read_bits();
+        let stat = (r & (EP_STAT_TX | EP_STAT_RX))
+            ^ (((tx as u32) << EP_STAT_TX_SHIFT) | ((rx as u32) << EP_STAT_RX_SHIFT));
+        self.write_bits(unchanged(r) | stat);
     }
 
-    fn toggle_tx_out(&self) {
-        self.toggle(EP_TX_MASK, EP_TX_VALID, 0)
+    fn set_type(&self, ep_type: EpType) {
+        let r = self.read_bits();
+        self.write_bits((unchanged(r) & !EP_TYPE) | ((ep_type as u32) << EP_TYPE_SHIFT));
     }
 
-    fn toggle_out(&self) {
-        self.toggle(EP_TX_RX_MASK, EP_TX_RX_VALID, EP_STATUS_OUT)
+    // STATUS_OUT on control endpoints, DBL_BUF on bulk endpoints.
+    fn set_kind(&self, kind: bool) {
+        let w = unchanged(self.read_bits()) & !EP_KIND;
+        self.write_bits(if kind { w | EP_KIND } else { w });
     }
 
-    fn toggle_0(&self) {
-        self.toggle(EP_TX_RX_MASK, EP_TX_RX_VALID, 0)
+    fn set_address(&self, address: u8) {
+        let r = self.read_bits();
+        self.write_bits((unchanged(r) & !EP_EA) | (address as u32 & EP_EA));
     }
 
-    fn toggle_rx(&self) {
-        self.toggle(EP_DTOG_RX, EP_DTOG_RX, 0)
+    fn clear_ctr_rx(&self) {
+        self.write_bits(unchanged(self.read_bits()) & !EP_CTR_RX);
     }
 
-    fn toggle_tx(&self) {
-        self.toggle(EP_DTOG_TX, EP_DTOG_TX, 0)
+    fn clear_ctr_tx(&self) {
+        self.write_bits(unchanged(self.read_bits()) & !EP_CTR_TX);
     }
 
-    fn toggle(&self, mask: u32, val: u32, flags: u32) {
-        self.modify(|r, w| unsafe { w.bits(((r.bits() & (EP_MASK | mask)) ^ val) | flags) })
+    // Back to DATA0, toggling only if the bit is currently set.
+    fn reset_dtog_rx(&self) {
+        let r = self.read_bits();
+        self.write_bits(unchanged(r) | (r & EP_DTOG_RX));
     }
 
-    fn clear_ctr_rx(&self) {
-        self.modify(|r, w| unsafe { w.bits((r.bits() & EP_MASK) | EP_CTR_TX) });
+    fn reset_dtog_tx(&self) {
+        let r = self.read_bits();
+        self.write_bits(unchanged(r) | (r & EP_DTOG_TX));
     }
 
-    fn clear_ctr_tx(&self) {
-        self.modify(|r, w| unsafe { w.bits((r.bits() & EP_MASK) | EP_CTR_RX) });
+    // On double buffered endpoints the other direction's DTOG is SW_BUF, the buffer the
+    // application owns. TX endpoints flip it with toggle_dtog_rx, RX ones with toggle_dtog_tx.
+    fn toggle_dtog_rx(&self) {
+        self.write_bits(unchanged(self.read_bits()) | EP_DTOG_RX);
+    }
+
+    fn toggle_dtog_tx(&self) {
+        self.write_bits(unchanged(self.read_bits()) | EP_DTOG_TX);
+    }
+}
+
+macro_rules! ep_ext {
+    ($($EPnR:ident),*) => {
+        $(
+            impl UsbEpExt for $EPnR {
+                fn read_bits(&self) -> u32 {
+                    self.read().bits()
+                }
+
+                fn write_bits(&self, bits: u32) {
+                    self.write(|w| unsafe { w.bits(bits) })
+                }
+            }
+        )*
+    };
+}
+
+ep_ext!(EP0R, EP1R, EP2R, EP3R, EP4R, EP5R, EP6R, EP7R);
+
+// Endpoint register by endpoint number, 0..=7.
+pub fn endpoint(usb: &RegisterBlock, number: u8) -> &dyn UsbEpExt {
+    match number {
+        0 => &usb.ep0r,
+        1 => &usb.ep1r,
+        2 => &usb.ep2r,
+        3 => &usb.ep3r,
+        4 => &usb.ep4r,
+        5 => &usb.ep5r,
+        6 => &usb.ep6r,
+        7 => &usb.ep7r,
+        _ => panic!("no endpoint register {}", number),
     }
 }
@@ -0,0 +1,314 @@
+#![allow(dead_code)]
+
+use crate::usb::constants::EndpointType;
+use crate::usb::descriptors::StringTable;
+use crate::usb::pma::{rx_size_bits, BTABLE_SIZE, PMA_SIZE};
+use crate::usb::types::{self, Descriptors};
+use crate::usb::MAX_PACKET_SIZE;
+
+// RM0091 30.6.2, 8 endpoint registers.
+pub const NUM_ENDPOINTS: u8 = 8;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum DescriptorError {
+    // bMaxPacketSize0 is not 8/16/32/64, or bigger than the driver's EP0 buffers.
+    MaxPacketSize0(u8),
+    NumConfigurations { declared: u8, actual: u8 },
+    // wTotalLength/bNumInterfaces are only checked when set, zero means fill in.
+    TotalLength { configuration: u8, declared: u16, actual: u16 },
+    NumInterfaces { configuration: u8, declared: u8, actual: u8 },
+    NumEndpoints { interface: u8, declared: u8, actual: u8 },
+    // Endpoint 0, a number without an EPnR register, or reserved address bits set.
+    ReservedEndpoint(u8),
+    DuplicateEndpoint(u8),
+    MaxPacketSize { address: u8, size: u16 },
+    // Double buffering is only for bulk and isochronous endpoints, and needs the EPnR to
+    // itself. Isochronous endpoints are always double buffered.
+    DoubleBuffered(u8),
+    SharedDoubleBuffered(u8),
+    // The IN and OUT endpoint of one number share an EPnR, and with it EP_TYPE.
+    MixedType(u8),
+    // A string index that is missing from at least one language table.
+    MissingString(u8),
+    // bLength disagrees with the bytes that are there. Class specific descriptors are
+    // written by hand, and a Device can be made from raw bytes.
+    Length { descriptor_type: u8, declared: u8, actual: usize },
+    PmaExhausted { required: usize, available: usize },
+}
+
+// const fns can't use ?, this does the same for Result<(), DescriptorError>.
+macro_rules! check {
+    ($e:expr) => {
+        match $e {
+            Ok(()) => {}
+            Err(e) => return Err(e),
+        }
+    };
+}
+
+// Usable from a const item, so bad descriptors fail the build:
+//
+//   const _: () = match validate(&DESCS) {
+//       Ok(()) => (),
+//       Err(_) => panic!("inconsistent USB descriptors"),
+//   };
+pub const fn validate(descriptors: &Descriptors) -> Result<(), DescriptorError> {
+    let device = descriptors.Device.descriptor();
+    let strings = descriptors.Strings;
+
+    check!(check_length(&device.to_bytes()));
+
+    let mps0 = device.max_packet_size0();
+    match mps0 {
+        8 | 16 | 32 | 64 if mps0 as u32 <= MAX_PACKET_SIZE => {}
+        _ => return Err(DescriptorError::MaxPacketSize0(mps0)),
+    }
+
+    let configurations = descriptors.Device.configurations();
+    if device.num_configurations() as usize != configurations.len() {
+        return Err(DescriptorError::NumConfigurations {
+            declared: device.num_configurations(),
+            actual: configurations.len() as u8,
+        });
+    }
+
+    check!(check_string(device.manufacturer_string(), strings));
+    check!(check_string(device.product_string(), strings));
+    check!(check_string(device.serial_number_string(), strings));
+
+    let mut i = 0;
+    while i < configurations.len() {
+        check!(validate_configuration(&configurations[i], strings));
+        i += 1;
+    }
+
+    Ok(())
+}
+
+pub const fn validate_configuration(
+    configuration: &types::Configuration,
+    strings: &[StringTable],
+) -> Result<(), DescriptorError> {
+    let desc = configuration.descriptor();
+    let value = desc.configuration_value();
+
+    if desc.total_length() != 0 && desc.total_length() != configuration.total_length() {
+        return Err(DescriptorError::TotalLength {
+            configuration: value,
+            declared: desc.total_length(),
+            actual: configuration.total_length(),
+        });
+    }
+
+    if desc.num_interfaces() != 0 && desc.num_interfaces() != configuration.num_interfaces() {
+        return Err(DescriptorError::NumInterfaces {
+            configuration: value,
+            declared: desc.num_interfaces(),
+            actual: configuration.num_interfaces(),
+        });
+    }
+
+    check!(check_string(desc.configuration_string(), strings));
+
+    let interfaces = configuration.interfaces();
+    let mut i = 0;
+    while i < interfaces.len() {
+        check!(validate_interface(&interfaces[i], strings));
+        check!(check_duplicates(interfaces, i));
+        i += 1;
+    }
+
+    let required = pma_demand(configuration);
+    if required > PMA_SIZE {
+        return Err(DescriptorError::PmaExhausted {
+            required,
+            available: PMA_SIZE,
+        });
+    }
+
+    Ok(())
+}
+
+const fn validate_interface(
+    interface: &types::Interface,
+    strings: &[StringTable],
+) -> Result<(), DescriptorError> {
+    let desc = interface.descriptor();
+    let endpoints = interface.endpoints();
+
+    if desc.num_endpoints() as usize != endpoints.len() {
+        return Err(DescriptorError::NumEndpoints {
+            interface: desc.interface_number(),
+            declared: desc.num_endpoints(),
+            actual: endpoints.len() as u8,
+        });
+    }
+
+    check!(check_string(desc.interface_string(), strings));
+
+    let others = interface.other_descriptors();
+    let mut i = 0;
+    while i < others.len() {
+        check!(check_length(others[i]));
+        i += 1;
+    }
+
+    let mut i = 0;
+    while i < endpoints.len() {
+        check!(validate_endpoint(&endpoints[i]));
+        i += 1;
+    }
+
+    Ok(())
+}
+
+const fn validate_endpoint(endpoint: &types::Endpoint) -> Result<(), DescriptorError> {
+    let desc = endpoint.descriptor();
+    let address = desc.address();
+    let number = address & 0x0f;
+
+    if number == 0 || number >= NUM_ENDPOINTS || address & 0x70 != 0 {
+        return Err(DescriptorError::ReservedEndpoint(address));
+    }
+
+    let size = desc.max_packet_size();
+    let valid = match desc.transfer_type() {
+        EndpointType::Bulk => matches!(size, 8 | 16 | 32 | 64),
+        EndpointType::Control | EndpointType::Interrupt => size > 0 && size <= 64,
+        EndpointType::Isochronous => size <= 1023,
+    };
+    if !valid {
+        return Err(DescriptorError::MaxPacketSize { address, size });
+    }
+
+    let double_capable = matches!(
+        desc.transfer_type(),
+        EndpointType::Bulk | EndpointType::Isochronous
+    );
+    if endpoint.is_double_buffered() && !double_capable {
+        return Err(DescriptorError::DoubleBuffered(address));
+    }
+
+    Ok(())
+}
+
+// Alternate settings of one interface may reuse addresses, anything else may not. Neither
+// may the IN and OUT endpoint of one number be used together if either is double buffered
+// or isochronous, or if their transfer types differ.
+const fn check_duplicates(
+    interfaces: &[types::Interface],
+    index: usize,
+) -> Result<(), DescriptorError> {
+    let a = &interfaces[index];
+    let mut j = index;
+    while j < interfaces.len() {
+        let b = &interfaces[j];
+        let same_number = a.descriptor().interface_number() == b.descriptor().interface_number();
+        if j == index || !same_number {
+            let ea = a.endpoints();
+            let eb = b.endpoints();
+            let mut k = 0;
+            while k < ea.len() {
+                // Within one interface only compare against the endpoints after k.
+                let mut l = if j == index { k + 1 } else { 0 };
+                while l < eb.len() {
+                    let address = ea[k].descriptor().address();
+                    let other = eb[l].descriptor().address();
+                    if address == other {
+                        return Err(DescriptorError::DuplicateEndpoint(address));
+                    }
+                    let double = both_buffers(&ea[k]) || both_buffers(&eb[l]);
+                    if double && address & 0x0f == other & 0x0f {
+                        return Err(DescriptorError::SharedDoubleBuffered(address));
+                    }
+                    let ta = ea[k].descriptor().transfer_type() as u8;
+                    let tb = eb[l].descriptor().transfer_type() as u8;
+                    if ta != tb && address & 0x0f == other & 0x0f {
+                        return Err(DescriptorError::MixedType(address));
+                    }
+                    l += 1;
+                }
+                k += 1;
+            }
+        }
+        j += 1;
+    }
+    Ok(())
+}
+
+const fn both_buffers(endpoint: &types::Endpoint) -> bool {
+    endpoint.is_double_buffered()
+        || matches!(endpoint.descriptor().transfer_type(), EndpointType::Isochronous)
+}
+
+// One descriptor, bLength first and bDescriptorType after it.
+const fn check_length(bytes: &[u8]) -> Result<(), DescriptorError> {
+    if bytes.len() >= 2 && bytes[0] as usize == bytes.len() {
+        return Ok(());
+    }
+    Err(DescriptorError::Length {
+        descriptor_type: if bytes.len() >= 2 { bytes[1] } else { 0 },
+        declared: if bytes.is_empty() { 0 } else { bytes[0] },
+        actual: bytes.len(),
+    })
+}
+
+const fn check_string(index: u8, strings: &[StringTable]) -> Result<(), DescriptorError> {
+    if index == 0 {
+        return Ok(());
+    }
+    if strings.is_empty() {
+        return Err(DescriptorError::MissingString(index));
+    }
+
+    let mut i = 0;
+    while i < strings.len() {
+        if index as usize > strings[i].len() {
+            return Err(DescriptorError::MissingString(index));
+        }
+        i += 1;
+    }
+    Ok(())
+}
+
+// TX buffers are half word aligned, RX buffers are rounded up to what COUNTn_RX can describe.
+const fn pma_tx_size(size: u16) -> usize {
+    (size as usize + 1) & !1
+}
+
+const fn pma_rx_size(size: u16) -> usize {
+    match rx_size_bits(size as usize) {
+        Ok((_, size)) => size,
+        Err(_) => size as usize,
+    }
+}
+
+// PMA needed by the BTABLE, EP0 and every endpoint of the configuration. Double buffered
+// and isochronous endpoints, which always run double buffered, need two buffers.
+pub const fn pma_demand(configuration: &types::Configuration) -> usize {
+    let mut total = BTABLE_SIZE + 2 * MAX_PACKET_SIZE as usize;
+
+    let interfaces = configuration.interfaces();
+    let mut i = 0;
+    while i < interfaces.len() {
+        let endpoints = interfaces[i].endpoints();
+        let mut j = 0;
+        while j < endpoints.len() {
+            let desc = endpoints[j].descriptor();
+            let size = if desc.address() & 0x80 != 0 {
+                pma_tx_size(desc.max_packet_size())
+            } else {
+                pma_rx_size(desc.max_packet_size())
+            };
+            total += match desc.transfer_type() {
+                EndpointType::Isochronous => 2 * size,
+                EndpointType::Bulk if endpoints[j].is_double_buffered() => 2 * size,
+                _ => size,
+            };
+            j += 1;
+        }
+        i += 1;
+    }
+
+    total
+}